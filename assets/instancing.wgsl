@@ -48,6 +48,14 @@ fn vertex(vertex: Vertex) -> VertexOutput {
     return out;
 }
 
+// see GpuMaterial
+struct Material {
+    emissive: f32,
+    roughness: f32,
+    transparency: u32,
+    flags: u32,
+}
+
 struct VoxelUniforms {
     brick_map_depth: u32,
     brick_size: u32, // brick size as a power of 2
@@ -64,9 +72,13 @@ var<storage, read_write> counters: array<u32>;
 var<storage, read> bricks: array<u32>;
 @group(2) @binding(4)
 var color_texture: texture_storage_3d<rgba8unorm, read>;
+@group(2) @binding(5)
+var material_texture: texture_3d<u32>;
+@group(2) @binding(6)
+var<storage, read> materials: array<Material>;
 
 // local_pos ranges from (0,0,0) to (1,1,1) inside the brick
-fn trace_brick(index: u32, local_pos: ptr<function, vec3<f32>>, dir: vec3<f32>, normal: ptr<function, vec3<f32>>) -> vec3<f32> {
+fn trace_brick(index: u32, local_pos: ptr<function, vec3<f32>>, dir: vec3<f32>, normal: ptr<function, vec3<f32>>, material: ptr<function, u32>) -> vec3<f32> {
     let r_sign = sign(dir);
     var initial_pos = *local_pos;
     var steps = 0u;
@@ -110,7 +122,7 @@ fn trace_brick(index: u32, local_pos: ptr<function, vec3<f32>>, dir: vec3<f32>,
         }
 
         if bit_0 != 0u {
-            // get color of the voxel
+            // get color and material of the voxel
             let brick_size = i32(1u << voxel_uniforms.brick_size);
             let dim = vec3<i32>(textureDimensions(color_texture)) / brick_size;
             let brick_pos_in_texture = vec3(
@@ -118,8 +130,9 @@ fn trace_brick(index: u32, local_pos: ptr<function, vec3<f32>>, dir: vec3<f32>,
                 (i32(index) / dim.z) % dim.y,
                 i32(index) % dim.z,
             ) * brick_size;
-            let color = textureLoad(color_texture, brick_pos_in_texture + vec3<i32>(lookup_pos * f32(brick_size))).rgb;
-            return color;
+            let texture_pos = brick_pos_in_texture + vec3<i32>(lookup_pos * f32(brick_size));
+            *material = textureLoad(material_texture, texture_pos, 0).r;
+            return textureLoad(color_texture, texture_pos).rgb;
             // return vec3(f32(steps) / 2.0);
         }
 
@@ -207,7 +220,8 @@ fn fragment(in: VertexOutput, @builtin(front_facing) facing: bool) -> FragmentOu
 
     // shoot ray
     var normal = in.normal;
-    let color = trace_brick(in.brick, &pos, dir, &normal);
+    var material = 0u;
+    let color = trace_brick(in.brick, &pos, dir, &normal, &material);
 
     // diffuse
     let diffuse = max(dot(normal, -normalize(light_dir)), 0.0);
@@ -226,7 +240,7 @@ fn fragment(in: VertexOutput, @builtin(front_facing) facing: bool) -> FragmentOu
     let interpolated_ao = mix(mix(ao.z, ao.w, uv.x), mix(ao.y, ao.x, uv.x), uv.y);
     let indirect = pow(interpolated_ao, 1.0 / 3.0) * 0.3;
 
-    output_color = color * (diffuse + indirect);
+    output_color = color * (diffuse + indirect + materials[material].emissive);
     // output_color = in.local_pos;
     
     var out: FragmentOutput;
@@ -9,6 +9,14 @@ struct VoxelUniforms {
     brick_ints: u32,
 }
 
+// see GpuMaterial
+struct Material {
+    emissive: f32,
+    roughness: f32,
+    transparency: u32,
+    flags: u32,
+}
+
 struct MainPassUniforms {
     camera: mat4x4<f32>,
     camera_inverse: mat4x4<f32>,
@@ -31,6 +39,10 @@ var<storage, read_write> counters: array<u32>;
 var<storage, read> bricks: array<u32>;
 @group(0) @binding(4)
 var color_texture: texture_storage_3d<rgba8unorm, read>;
+@group(0) @binding(5)
+var material_texture: texture_3d<u32>;
+@group(0) @binding(6)
+var<storage, read> materials: array<Material>;
 
 @group(1) @binding(0)
 var<uniform> uniforms: MainPassUniforms;
@@ -129,6 +141,7 @@ struct Voxel {
     col: vec4<f32>,
     pos: vec3<f32>,
     half_size: f32,
+    material: u32,
 };
 
 struct HitInfo {
@@ -152,7 +165,7 @@ fn shoot_ray(r: Ray, maximum_ratio: f32) -> HitInfo {
     if !in_bounds(pos) {
         let ray_box = ray_box_dist(Ray(pos, dir), vec3(0.0), vec3(f32(1u << voxel_uniforms.brick_map_depth)));
         if ray_box.min == 0.0 {
-            return HitInfo(false, Voxel(vec4(0.0), vec3(0.0), 0.0), vec3(0.0), vec3(0.0), 0u);
+            return HitInfo(false, Voxel(vec4(0.0), vec3(0.0), 0.0, 0u), vec3(0.0), vec3(0.0), 0u);
         }
 
         pos = pos + dir * ray_box.min;
@@ -218,8 +231,9 @@ fn shoot_ray(r: Ray, maximum_ratio: f32) -> HitInfo {
                     let half_size = annoying_factor / f32(brick_size);
                     let voxel_pos = (floor(pos_in_brick * f32(brick_size)) + 0.5) / f32(brick_size) * annoying_factor + vec3<f32>(brick.pos) - f32(1u << voxel_uniforms.brick_map_depth) / 2.0;
 
-                    // get color of the voxel
+                    // get color and material of the voxel
                     var col = vec4(1.0);
+                    var material = 0u;
                     if maximum_ratio == 0.0 {
                         let dim = vec3<i32>(textureDimensions(color_texture)) / brick_size;
                         let brick_pos_in_texture = vec3(
@@ -227,11 +241,13 @@ fn shoot_ray(r: Ray, maximum_ratio: f32) -> HitInfo {
                             (i32(brick.index) / dim.z) % dim.y,
                             i32(brick.index) % dim.z,
                         ) * brick_size;
-                        col = textureLoad(color_texture, brick_pos_in_texture + vec3<i32>(pos_in_brick * f32(brick_size)));
+                        let texture_pos = brick_pos_in_texture + vec3<i32>(pos_in_brick * f32(brick_size));
+                        col = textureLoad(color_texture, texture_pos);
+                        material = textureLoad(material_texture, texture_pos, 0).r;
                     }
 
                     // let counter_value = f32(counters[brick.node_index]) / 100.0;
-                    return HitInfo(true, Voxel(vec4(col), voxel_pos, half_size), world_pos, normal, steps);
+                    return HitInfo(true, Voxel(vec4(col), voxel_pos, half_size, material), world_pos, normal, steps);
                 }
 
                 let rounded_pos = floor(pos_in_brick * f32(size)) / f32(size);
@@ -263,13 +279,13 @@ fn shoot_ray(r: Ray, maximum_ratio: f32) -> HitInfo {
         tcpotr = pos + dir * t_current - normal * 0.00004;
 
         if !in_bounds(tcpotr) {
-            return HitInfo(false, Voxel(vec4(0.0), vec3(0.0), 0.0), vec3(0.0), vec3(0.0), steps);
+            return HitInfo(false, Voxel(vec4(0.0), vec3(0.0), 0.0, 0u), vec3(0.0), vec3(0.0), steps);
         }
 
         steps += 1u;
     }
 
-    return HitInfo(false, Voxel(vec4(0.0), vec3(0.0), 0.0), vec3(0.0), vec3(0.0), steps);
+    return HitInfo(false, Voxel(vec4(0.0), vec3(0.0), 0.0, 0u), vec3(0.0), vec3(0.0), steps);
 }
 
 const light_dir = vec3<f32>(0.8, -1.0, 0.8);
@@ -381,8 +397,16 @@ fn fragment(in: FullscreenVertexOutput) -> @location(0) vec4<f32> {
                 indirect_lighting = vec3(interpolated_ao * 0.3);
             }
 
+            // specular highlight, sharper the smoother the material
+            let material = materials[hit.voxel.material];
+            let lit = f32(any(direct_lighting > vec3(0.0)));
+            let specular = pow(
+                max(dot(reflect(ray.dir, hit.normal), -normalize(light_dir)), 0.0),
+                mix(64.0, 1.0, material.roughness)
+            ) * (1.0 - material.roughness) * lit * light_colour;
+
             // final blend
-            output_colour = (direct_lighting + indirect_lighting) * hit.voxel.col.rgb;
+            output_colour = (direct_lighting + indirect_lighting + material.emissive) * hit.voxel.col.rgb + specular;
         } else {
             output_colour = vec3(0.2);
         }
@@ -0,0 +1,40 @@
+use alex::render_pipeline::{procedural_bricks, CpuBrickmap, BRICK_SIZE};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+
+const WORLD_DEPTH: u32 = 9;
+
+/// the parallel `place_bricks` against placing the same procedural world's
+/// bricks one at a time
+fn place_bricks(c: &mut Criterion) {
+    let brickmap_depth = WORLD_DEPTH - BRICK_SIZE.trailing_zeros();
+    let bricks = procedural_bricks(0, WORLD_DEPTH).expect("Failed to build bricks");
+
+    let mut group = c.benchmark_group("place_bricks");
+    group.sample_size(10);
+    group.bench_function("serial", |b| {
+        b.iter_batched(
+            || bricks.clone(),
+            |bricks| {
+                let mut brickmap = CpuBrickmap::new(brickmap_depth);
+                brickmap.place_bricks_serial(bricks).unwrap();
+                brickmap
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("parallel", |b| {
+        b.iter_batched(
+            || bricks.clone(),
+            |bricks| {
+                let mut brickmap = CpuBrickmap::new(brickmap_depth);
+                brickmap.place_bricks(bricks).unwrap();
+                brickmap
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, place_bricks);
+criterion_main!(benches);
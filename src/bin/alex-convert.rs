@@ -0,0 +1,306 @@
+//! Converts anvil regions, heightmaps and procedural terrain into a saved
+//! brickmap the viewer can open without building anything at startup, or
+//! exports a box of a world to a `.vox` file.
+
+use alex::render_pipeline::{
+    load_anvil, load_world, save_vox, AnvilWindow, CpuBrickmap, HeightmapSettings, MipFilter,
+    RegionStatus, VoxelWorldConfig, WorldSource,
+};
+use bevy::math::IVec3;
+use clap::Parser;
+use std::{
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Instant,
+};
+
+/// Converts a world into a brickmap file for alex
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+    /// anvil region directory, heightmap image, .vox file or brickmap to
+    /// convert, leave out with --seed to generate terrain
+    input: Option<PathBuf>,
+
+    /// where to write the brickmap, or a .vox file to export a box of the
+    /// world to
+    #[arg(long, short)]
+    output: PathBuf,
+
+    /// lowest corner of the box to export to .vox, as x,y,z
+    #[arg(long, value_parser = parse_ivec3, allow_hyphen_values = true)]
+    min: Option<IVec3>,
+
+    /// highest corner of the box to export to .vox, inclusive
+    #[arg(long, value_parser = parse_ivec3, allow_hyphen_values = true)]
+    max: Option<IVec3>,
+
+    /// anvil block placed at the centre of the world, as x,y,z in multiples
+    /// of 16
+    #[arg(long, value_parser = parse_ivec3, allow_hyphen_values = true)]
+    origin: Option<IVec3>,
+
+    /// lowest anvil block y to import
+    #[arg(long, allow_hyphen_values = true)]
+    min_y: Option<i32>,
+
+    /// highest anvil block y to import
+    #[arg(long, allow_hyphen_values = true)]
+    max_y: Option<i32>,
+
+    /// block colour overrides on top of blockstates.json, a .toml or .json
+    /// file
+    #[arg(long)]
+    palette: Option<PathBuf>,
+
+    /// voxels per heightmap sample along x and z, the heightmap is stretched
+    /// over the whole world if left out
+    #[arg(long)]
+    horizontal_scale: Option<f32>,
+
+    /// voxels per unit of heightmap height above the lowest point, the highest
+    /// point touches the top of the world if left out
+    #[arg(long)]
+    vertical_scale: Option<f32>,
+
+    /// heightmap height that water fills up to
+    #[arg(long, allow_hyphen_values = true)]
+    sea_level: Option<f32>,
+
+    /// seed for generated terrain
+    #[arg(long, conflicts_with = "input")]
+    seed: Option<u32>,
+
+    /// log2 of the world side length in voxels, 5 to 16
+    #[arg(
+        long,
+        default_value_t = VoxelWorldConfig::default().world_depth,
+        value_parser = clap::value_parser!(u32).range(5..=16),
+    )]
+    world_depth: u32,
+
+    /// how mips are filtered: average, majority or max-opacity
+    #[arg(long, default_value = "average")]
+    mip_filter: MipFilter,
+
+    /// store identical bricks separately
+    #[arg(long)]
+    no_dedup: bool,
+
+    /// overwrite the output if it exists
+    #[arg(long)]
+    force: bool,
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    match convert(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn convert(args: &Args) -> Result<(), String> {
+    if args.output.exists() && !args.force {
+        return Err(format!(
+            "{} already exists, pass --force to overwrite it",
+            args.output.display()
+        ));
+    }
+
+    let source = match (&args.input, args.seed) {
+        (Some(input), _) => WorldSource::from_path(input.clone())
+            .ok_or_else(|| format!("can't tell what kind of world {} is", input.display()))?,
+        (None, Some(seed)) => WorldSource::Procedural { seed },
+        (None, None) => return Err("pass an input world or --seed".into()),
+    };
+    let config = VoxelWorldConfig {
+        source,
+        world_depth: args.world_depth,
+        mip_filter: args.mip_filter.clone(),
+        dedup: !args.no_dedup,
+        palette: args.palette.clone(),
+        heightmap: HeightmapSettings {
+            horizontal_scale: args.horizontal_scale,
+            vertical_scale: args.vertical_scale,
+            sea_level: args.sea_level,
+        },
+        ..Default::default()
+    };
+
+    let to_vox = args
+        .output
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("vox"));
+    if !to_vox && (args.min.is_some() || args.max.is_some()) {
+        return Err("--min and --max only apply when exporting to .vox".into());
+    }
+
+    let is_anvil = matches!(config.source, WorldSource::Anvil(_));
+    if !is_anvil && (args.origin.is_some() || args.min_y.is_some() || args.max_y.is_some()) {
+        return Err("--origin, --min-y and --max-y only apply to anvil worlds".into());
+    }
+    if args.palette.is_some()
+        && matches!(
+            config.source,
+            WorldSource::Vox(_) | WorldSource::Brickmap(_)
+        )
+    {
+        return Err("--palette doesn't apply to .vox files or brickmaps".into());
+    }
+    let is_heightmap = matches!(
+        config.source,
+        WorldSource::Heightmap(_) | WorldSource::Procedural { .. }
+    );
+    if !is_heightmap
+        && (args.horizontal_scale.is_some()
+            || args.vertical_scale.is_some()
+            || args.sea_level.is_some())
+    {
+        return Err(
+            "--horizontal-scale, --vertical-scale and --sea-level only apply to heightmaps and \
+             generated terrain"
+                .into(),
+        );
+    }
+    for (flag, scale) in [
+        ("--horizontal-scale", args.horizontal_scale),
+        ("--vertical-scale", args.vertical_scale),
+    ] {
+        if scale.is_some_and(|scale| !(scale.is_finite() && scale > 0.0)) {
+            return Err(format!("{} must be above 0", flag));
+        }
+    }
+    let default_window = AnvilWindow::default();
+    let window = AnvilWindow {
+        origin: args.origin.unwrap_or(default_window.origin),
+        min_y: args.min_y.unwrap_or(default_window.min_y),
+        max_y: args.max_y.unwrap_or(default_window.max_y),
+    };
+
+    let start = Instant::now();
+    let brickmap = match &config.source {
+        WorldSource::Anvil(path) => convert_anvil(path, &config, &window)?,
+        WorldSource::Brickmap(path) if !to_vox => {
+            return Err(format!("{} is already a brickmap", path.display()));
+        }
+        source => {
+            println!("building {:?}", source);
+            load_world(&config)?
+        }
+    };
+
+    if to_vox {
+        let half = brickmap.size() as i32 / 2;
+        let min = args.min.unwrap_or(IVec3::splat(-half));
+        let max = args.max.unwrap_or(IVec3::splat(half - 1));
+        println!("exporting {}..={} to {}", min, max, args.output.display());
+        save_vox(&brickmap, min, max, &args.output)?;
+        println!("done in {:.1?}", start.elapsed());
+        return Ok(());
+    }
+
+    brickmap.validate(true)?;
+
+    println!("writing {}", args.output.display());
+    brickmap
+        .save(&args.output)
+        .map_err(|e| format!("failed to write {}: {}", args.output.display(), e))?;
+    println!(
+        "wrote {} nodes and {} bricks in {:.1?}",
+        brickmap.brickmap.len(),
+        brickmap.bricks.len(),
+        start.elapsed()
+    );
+
+    Ok(())
+}
+
+fn convert_anvil(
+    path: &Path,
+    config: &VoxelWorldConfig,
+    window: &AnvilWindow,
+) -> Result<CpuBrickmap, String> {
+    println!(
+        "importing y {}..={} with {} at the centre",
+        window.min_y, window.max_y, window.origin
+    );
+    let done = AtomicUsize::new(0);
+    let (mut brickmap, report) = load_anvil(path, config, window, |region, status| {
+        let done = done.fetch_add(1, Ordering::Relaxed) + 1;
+        let region = region.display();
+        match status {
+            RegionStatus::Loaded(summary) => println!(
+                "[{}] {}: {} chunks, {} bricks, {} chunks and {} sections outside the world, \
+                 {} errors",
+                done,
+                region,
+                summary.chunks,
+                summary.bricks,
+                summary.clipped_chunks,
+                summary.clipped_sections,
+                summary.chunk_errors.len()
+            ),
+            RegionStatus::OutsideWorld => println!("[{}] {}: outside the world", done, region),
+            RegionStatus::Failed(e) => println!("[{}] {}: {}", done, region, e),
+        }
+    })?;
+
+    let loaded = report.loaded().count();
+    let clipped_sections = report
+        .loaded()
+        .map(|(_, summary)| summary.clipped_sections)
+        .sum::<usize>();
+    if clipped_sections > 0 {
+        println!(
+            "{} sections were above or below the world, raise --world-depth, move --origin or \
+             narrow --min-y and --max-y",
+            clipped_sections
+        );
+    }
+    println!(
+        "{} regions loaded, {} outside the world, {} failed, {} chunk errors",
+        loaded,
+        report.outside_world(),
+        report.failed().count(),
+        report.chunk_errors().count()
+    );
+    for (region, e) in report.failed() {
+        println!("  {}: {}", region.display(), e);
+    }
+    for (region, slot, e) in report.chunk_errors() {
+        println!("  {} chunk {} {}: {}", region.display(), slot.x, slot.y, e);
+    }
+    if !report.unknown_blocks.is_empty() {
+        println!(
+            "{} blocks missing from the palette, add them to a --palette file:",
+            report.unknown_blocks.len()
+        );
+        for block in report.unknown_blocks.iter() {
+            println!("  {}", block);
+        }
+    }
+    if loaded == 0 {
+        return Err(format!("no regions loaded from {}", path.display()));
+    }
+
+    println!("building mips");
+    brickmap.recreate_mipmaps()?;
+    Ok(brickmap)
+}
+
+fn parse_ivec3(s: &str) -> Result<IVec3, String> {
+    let parts = s
+        .split(',')
+        .map(|part| part.trim().parse::<i32>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("expected x,y,z: {}", e))?;
+    match parts[..] {
+        [x, y, z] => Ok(IVec3::new(x, y, z)),
+        _ => Err(format!("expected 3 numbers as x,y,z, got {}", parts.len())),
+    }
+}
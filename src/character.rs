@@ -1,3 +1,4 @@
+use crate::render_pipeline::{CpuVoxelWorld, CpuVoxelWorldResource, BRICK_SIZE};
 use bevy::{
     input::mouse::{MouseMotion, MouseWheel},
     prelude::*,
@@ -6,6 +7,16 @@ use bevy::{
 
 const SENSITIVITY: f32 = 0.004;
 
+// walking, in voxels. the transform is in bricks
+const HALF_WIDTH: f32 = 0.3;
+const HEIGHT: f32 = 1.8;
+const EYE_HEIGHT: f32 = 1.62;
+const STEP_HEIGHT: f32 = 1.0;
+const WALK_SPEED: f32 = 4.3;
+const JUMP_SPEED: f32 = 9.0;
+const GRAVITY: f32 = 32.0;
+const TOGGLE_FLY: KeyCode = KeyCode::KeyF;
+
 #[derive(Component)]
 pub struct CharacterEntity {
     pub speed: f32,
@@ -59,6 +70,7 @@ fn update_character(
     time: Res<Time>,
     mut primary_query: Query<&mut Window, With<PrimaryWindow>>,
     mut mouse_wheel_events: EventReader<MouseWheel>,
+    cpu_voxel_world: Option<Res<CpuVoxelWorldResource>>,
 ) {
     let mut window = primary_query.single_mut();
     if keys.just_pressed(KeyCode::Escape) {
@@ -66,6 +78,13 @@ fn update_character(
     }
 
     let (mut transform, mut character) = character.single_mut();
+    if keys.just_pressed(TOGGLE_FLY) {
+        character.in_spectator = !character.in_spectator;
+        character.grounded = false;
+    }
+    // without a world there's nothing to walk on
+    let walking = !character.in_spectator && cpu_voxel_world.is_some();
+
     let target_velocity;
     if window.cursor.grab_mode == CursorGrabMode::Locked {
         // speed
@@ -105,32 +124,27 @@ fn update_character(
         if input != Vec3::ZERO {
             input = input.normalize();
         }
-        input *= character.speed;
-
-        // if character.in_spectator {
-        target_velocity = input.z * transform.local_z()
-            + input.x * transform.local_x()
-            + input.y * transform.local_y();
-        // } else {
-        //     if voxel_physics.velocity.y == 0.0 {
-        //         character.grounded = true;
-        //     }
-        //     if input.y > 0.0 && character.grounded {
-        //         voxel_physics.velocity.y = 5.0;
-        //         character.grounded = false;
-        //     }
-        //     voxel_physics.velocity += Vec3::new(0.0, -9.81 * time.delta_seconds(), 0.0);
-
-        //     let plane_forward = transform.local_x().cross(Vec3::Y).normalize();
-        //     target_velocity = input.z * plane_forward
-        //         + input.x * transform.local_x()
-        //         + voxel_physics.velocity.y * Vec3::Y;
-        // }
+
+        if !walking {
+            input *= character.speed;
+            target_velocity = input.z * transform.local_z()
+                + input.x * transform.local_x()
+                + input.y * transform.local_y();
+        } else {
+            if input.y > 0.0 && character.grounded {
+                character.velocity.y = JUMP_SPEED / BRICK_SIZE as f32;
+                character.grounded = false;
+            }
+
+            let plane_forward = transform.local_x().cross(Vec3::Y).normalize();
+            let input = input * WALK_SPEED / BRICK_SIZE as f32;
+            target_velocity = input.z * plane_forward + input.x * transform.local_x();
+        }
     } else {
         target_velocity = Vec3::splat(0.0);
     }
 
-    let acceleration: f32 = if character.in_spectator {
+    let acceleration: f32 = if !walking {
         0.2
     } else if character.grounded {
         0.2
@@ -138,6 +152,8 @@ fn update_character(
         0.01
     };
 
+    // only horizontal movement is smoothed when walking
+    let vertical_velocity = character.velocity.y;
     character.velocity = lerp(
         character.velocity,
         target_velocity,
@@ -152,7 +168,132 @@ fn update_character(
         time.delta_seconds(),
     );
 
-    transform.translation += character.velocity * time.delta_seconds();
+    if let (true, Some(cpu_voxel_world)) = (walking, cpu_voxel_world) {
+        character.velocity.y =
+            vertical_velocity - GRAVITY / BRICK_SIZE as f32 * time.delta_seconds();
+        if let Err(e) = walk(
+            &mut transform,
+            &mut character,
+            &cpu_voxel_world.read().unwrap(),
+            time.delta_seconds(),
+        ) {
+            warn!("failed to move the character: {}", e);
+        }
+    } else {
+        transform.translation += character.velocity * time.delta_seconds();
+    }
+}
+
+/// moves the character by its velocity, sliding along and stepping up onto
+/// solid voxels
+fn walk(
+    transform: &mut Transform,
+    character: &mut CharacterEntity,
+    cpu_voxel_world: &CpuVoxelWorld,
+    dt: f32,
+) -> Result<(), String> {
+    let scale = BRICK_SIZE as f32;
+    let mut body = Body {
+        cpu_voxel_world,
+        feet: transform.translation * scale - Vec3::Y * EYE_HEIGHT,
+    };
+    let delta = character.velocity * scale * dt;
+
+    // stuck inside terrain, e.g. after landing from flying. pop up on top
+    if body.overlaps(body.feet)? {
+        body.feet.y = body.feet.y.floor() + 1.0;
+        character.velocity.y = 0.0;
+        transform.translation = (body.feet + Vec3::Y * EYE_HEIGHT) / scale;
+        return Ok(());
+    }
+
+    let start = body.feet;
+    let mut blocked_x = body.sweep(0, delta.x)?;
+    let mut blocked_z = body.sweep(2, delta.z)?;
+
+    // retry the horizontal move from one step higher and keep it if it got
+    // further
+    if (blocked_x || blocked_z) && character.grounded {
+        let mut stepped = Body { feet: start, ..body };
+        stepped.sweep(1, STEP_HEIGHT)?;
+        let raised = stepped.feet.y - start.y;
+        let stepped_x = stepped.sweep(0, delta.x)?;
+        let stepped_z = stepped.sweep(2, delta.z)?;
+        stepped.sweep(1, -raised)?;
+
+        let progress = |p: Vec3| (p - start).xz().length_squared();
+        if progress(stepped.feet) > progress(body.feet) + 1e-6 {
+            body = stepped;
+            (blocked_x, blocked_z) = (stepped_x, stepped_z);
+        }
+    }
+
+    if body.sweep(1, delta.y)? {
+        character.grounded = delta.y < 0.0;
+        character.velocity.y = 0.0;
+    } else {
+        character.grounded = false;
+    }
+    if blocked_x {
+        character.velocity.x = 0.0;
+    }
+    if blocked_z {
+        character.velocity.z = 0.0;
+    }
+
+    transform.translation = (body.feet + Vec3::Y * EYE_HEIGHT) / scale;
+    Ok(())
+}
+
+/// The character's collision box in the world, in voxels from its feet.
+#[derive(Clone, Copy)]
+struct Body<'a> {
+    cpu_voxel_world: &'a CpuVoxelWorld,
+    feet: Vec3,
+}
+
+impl Body<'_> {
+    fn bounds(feet: Vec3) -> (Vec3, Vec3) {
+        (
+            feet - Vec3::new(HALF_WIDTH, 0.0, HALF_WIDTH),
+            feet + Vec3::new(HALF_WIDTH, HEIGHT, HALF_WIDTH),
+        )
+    }
+
+    /// whether the box would overlap a solid voxel with its feet at `feet`
+    fn overlaps(&self, feet: Vec3) -> Result<bool, String> {
+        let (min, max) = Self::bounds(feet);
+        self.cpu_voxel_world.overlaps_solid(min, max)
+    }
+
+    /// moves along one axis in sub voxel steps, stopping flush against the
+    /// first solid voxel. returns whether it was blocked
+    fn sweep(&mut self, axis: usize, delta: f32) -> Result<bool, String> {
+        let steps = (delta.abs() / 0.45).ceil().max(1.0);
+        let step = delta / steps;
+        for _ in 0..steps as usize {
+            let mut next = self.feet;
+            next[axis] += step;
+            if !self.overlaps(next)? {
+                self.feet = next;
+                continue;
+            }
+
+            // snap to the face of the voxel we ran into
+            let (min, max) = Self::bounds(next);
+            let (lo, hi) = Self::bounds(Vec3::ZERO);
+            if step > 0.0 {
+                next[axis] = (max[axis].floor() - hi[axis]).max(self.feet[axis]);
+            } else {
+                next[axis] = (min[axis].floor() + 1.0 - lo[axis]).min(self.feet[axis]);
+            }
+            if !self.overlaps(next)? {
+                self.feet = next;
+            }
+            return Ok(true);
+        }
+        Ok(false)
+    }
 }
 
 fn lerp(i: Vec3, f: Vec3, s: f32, dt: f32) -> Vec3 {
@@ -169,3 +310,117 @@ fn slerp(i: Vec3, f: Vec3, s: f32, dt: f32) -> Vec3 {
     }
     ((s * theta).sin() / theta.sin()) * i + (((1.0 - s) * theta).sin() / theta.sin()) * f
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render_pipeline::CpuBrickmap;
+
+    const DT: f32 = 1.0 / 60.0;
+    const STONE: [u8; 4] = [120, 120, 120, 255];
+
+    /// a world with a floor whose top is at y = 0
+    fn world_with_floor() -> CpuVoxelWorld {
+        let mut world = CpuVoxelWorld::new(CpuBrickmap::new(3));
+        world
+            .fill_box(IVec3::new(-30, -1, -30), IVec3::new(30, -1, 30), STONE)
+            .unwrap();
+        world
+    }
+
+    /// a walking character standing with its feet at `feet`, in voxels
+    fn character_at(feet: Vec3) -> (Transform, CharacterEntity) {
+        let transform =
+            Transform::from_translation((feet + Vec3::Y * EYE_HEIGHT) / BRICK_SIZE as f32);
+        let character = CharacterEntity {
+            in_spectator: false,
+            ..default()
+        };
+        (transform, character)
+    }
+
+    fn feet(transform: &Transform) -> Vec3 {
+        transform.translation * BRICK_SIZE as f32 - Vec3::Y * EYE_HEIGHT
+    }
+
+    /// runs `frames` frames of gravity and walking, holding the horizontal
+    /// velocity at `run` voxels a second
+    fn simulate(
+        world: &CpuVoxelWorld,
+        transform: &mut Transform,
+        character: &mut CharacterEntity,
+        run: Vec3,
+        frames: usize,
+    ) {
+        for _ in 0..frames {
+            let scale = BRICK_SIZE as f32;
+            character.velocity.x = run.x / scale;
+            character.velocity.z = run.z / scale;
+            character.velocity.y -= GRAVITY / scale * DT;
+            walk(transform, character, world, DT).unwrap();
+        }
+    }
+
+    #[test]
+    fn lands_on_the_floor() {
+        let world = world_with_floor();
+        let (mut transform, mut character) = character_at(Vec3::new(0.5, 5.0, 0.5));
+        simulate(&world, &mut transform, &mut character, Vec3::ZERO, 120);
+
+        assert!(character.grounded);
+        assert_eq!(character.velocity.y, 0.0);
+        assert!(feet(&transform).y.abs() < 1e-3, "{}", feet(&transform));
+    }
+
+    #[test]
+    fn walls_two_voxels_high_block() {
+        let mut world = world_with_floor();
+        world
+            .fill_box(IVec3::new(3, 0, -30), IVec3::new(3, 1, 30), STONE)
+            .unwrap();
+        let (mut transform, mut character) = character_at(Vec3::new(0.5, 0.0, 0.5));
+        let run = Vec3::X * WALK_SPEED;
+        simulate(&world, &mut transform, &mut character, run, 120);
+
+        let feet = feet(&transform);
+        assert!((feet.x - (3.0 - HALF_WIDTH)).abs() < 1e-3, "{}", feet);
+        assert!(feet.y.abs() < 1e-3, "{}", feet);
+        assert!(character.grounded);
+    }
+
+    #[test]
+    fn steps_up_one_voxel_ledges() {
+        let mut world = world_with_floor();
+        world
+            .fill_box(IVec3::new(3, 0, -30), IVec3::new(30, 0, 30), STONE)
+            .unwrap();
+        let (mut transform, mut character) = character_at(Vec3::new(0.5, 0.0, 0.5));
+        let run = Vec3::X * WALK_SPEED;
+        simulate(&world, &mut transform, &mut character, run, 120);
+
+        let feet = feet(&transform);
+        assert!(feet.x > 3.0 + HALF_WIDTH, "{}", feet);
+        assert!((feet.y - 1.0).abs() < 1e-3, "{}", feet);
+        assert!(character.grounded);
+    }
+
+    #[test]
+    fn fast_moves_dont_tunnel_through_thin_walls() {
+        let mut world = world_with_floor();
+        world
+            .fill_box(IVec3::new(3, 0, -30), IVec3::new(3, 1, 30), STONE)
+            .unwrap();
+
+        // many voxels a frame, straight down onto the one voxel thick floor
+        let (mut transform, mut character) = character_at(Vec3::new(0.5, 40.0, 0.5));
+        character.velocity.y = -2000.0 / BRICK_SIZE as f32;
+        simulate(&world, &mut transform, &mut character, Vec3::ZERO, 2);
+        assert!(feet(&transform).y.abs() < 1e-3, "{}", feet(&transform));
+        assert!(character.grounded);
+
+        // and sideways into the one voxel thick wall
+        simulate(&world, &mut transform, &mut character, Vec3::X * 2000.0, 2);
+        let feet = feet(&transform);
+        assert!((feet.x - (3.0 - HALF_WIDTH)).abs() < 1e-3, "{}", feet);
+    }
+}
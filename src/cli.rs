@@ -0,0 +1,298 @@
+use crate::render_pipeline::{HeightmapSettings, VoxelWorldConfig, WorldSource, BRICK_SIZE};
+use bevy::prelude::*;
+use clap::{error::ErrorKind, CommandFactory, Parser, ValueEnum};
+use std::path::{Path, PathBuf};
+
+// the largest 3d texture wgpu guarantees on every backend
+const MAX_TEXTURE_SIZE: u32 = 2048;
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WorldType {
+    Heightmap,
+    Vox,
+    Procedural,
+    Brickmap,
+}
+
+/// A massive scale voxel engine
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Args {
+    /// world to load, a heightmap image, .vox file or saved brickmap. anvil
+    /// worlds have to be converted with alex-convert first
+    #[arg(long, short)]
+    pub world: Option<PathBuf>,
+
+    /// what kind of world `--world` is, guessed from the path if left out
+    #[arg(long, value_enum)]
+    pub world_type: Option<WorldType>,
+
+    /// seed for procedural worlds
+    #[arg(long)]
+    pub seed: Option<u32>,
+
+    /// block colour overrides for heightmap and procedural worlds, a .toml
+    /// or .json file
+    #[arg(long)]
+    pub palette: Option<PathBuf>,
+
+    /// voxels per heightmap sample along x and z, the heightmap is stretched
+    /// over the whole world if left out
+    #[arg(long)]
+    pub horizontal_scale: Option<f32>,
+
+    /// voxels per unit of heightmap height above the lowest point, the highest
+    /// point touches the top of the world if left out
+    #[arg(long)]
+    pub vertical_scale: Option<f32>,
+
+    /// heightmap height that water fills up to
+    #[arg(long, allow_hyphen_values = true)]
+    pub sea_level: Option<f32>,
+
+    /// log2 of the world side length in voxels
+    #[arg(long)]
+    pub world_depth: Option<u32>,
+
+    /// side length of the gpu brick texture in voxels, sets how many bricks
+    /// can be resident on the gpu
+    #[arg(long)]
+    pub gpu_texture_size: Option<u32>,
+
+    /// how many groups of 8 nodes the gpu brickmap can hold
+    #[arg(long)]
+    pub gpu_max_nodes: Option<usize>,
+
+    /// page a saved brickmap in from disk, keeping at most this many MiB of
+    /// bricks in memory
+    #[arg(long)]
+    pub cpu_budget_mib: Option<usize>,
+
+    /// check the checksum of a paged brickmap up front, this reads the whole
+    /// file
+    #[arg(long, requires = "cpu_budget_mib")]
+    pub verify_checksum: bool,
+
+    /// initial camera position in bricks, as x,y,z
+    #[arg(long, value_parser = parse_vec3, allow_hyphen_values = true)]
+    pub camera_pos: Option<Vec3>,
+
+    /// level of detail threshold, nodes are split while 100 * their size over
+    /// their distance from the camera is above it. lower loads more detail,
+    /// defaults to 0.4
+    #[arg(long)]
+    pub streaming_ratio: Option<f32>,
+
+    /// window size in pixels, as WIDTHxHEIGHT. sets the render texture size
+    /// when headless
+    #[arg(long, value_parser = parse_size, default_value = "1280x720")]
+    pub window_size: UVec2,
+
+    /// render without a window or ui
+    #[arg(long)]
+    pub headless: bool,
+
+    /// exit after rendering this many frames
+    #[arg(long)]
+    pub frames: Option<u32>,
+}
+
+impl Args {
+    /// Parses the command line, printing an error and exiting if the flags
+    /// don't make sense together.
+    pub fn parse_and_validate() -> Self {
+        let args = Self::parse();
+        if let Err(e) = args.validate() {
+            Self::command().error(ErrorKind::ArgumentConflict, e).exit();
+        }
+        args
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.world.as_ref().is_some_and(|world| world.is_dir()) {
+            return Err(
+                "anvil worlds have to be converted first, run alex-convert and pass the \
+                 brickmap it writes"
+                    .into(),
+            );
+        }
+        let world_type = self.world_type()?;
+
+        if world_type == WorldType::Procedural {
+            if self.world.is_some() {
+                return Err("procedural worlds don't take a --world path".into());
+            }
+        } else if self.seed.is_some() {
+            return Err("--seed only applies to procedural worlds".into());
+        }
+        if let Some(world) = &self.world {
+            if !world.exists() {
+                return Err(format!("{} doesn't exist", world.display()));
+            }
+        }
+        if let Some(palette) = &self.palette {
+            if !matches!(world_type, WorldType::Heightmap | WorldType::Procedural) {
+                return Err("--palette only applies to heightmap and procedural worlds".into());
+            }
+            if !palette.is_file() {
+                return Err(format!("{} doesn't exist", palette.display()));
+            }
+        }
+        if self.horizontal_scale.is_some()
+            || self.vertical_scale.is_some()
+            || self.sea_level.is_some()
+        {
+            if !matches!(world_type, WorldType::Heightmap | WorldType::Procedural) {
+                return Err(
+                    "--horizontal-scale, --vertical-scale and --sea-level only apply to \
+                     heightmap and procedural worlds"
+                        .into(),
+                );
+            }
+            config_heightmap(self)?;
+        }
+        if self.cpu_budget_mib.is_some() && world_type != WorldType::Brickmap {
+            return Err("--cpu-budget-mib only applies to saved brickmaps".into());
+        }
+
+        let min_depth = BRICK_SIZE.trailing_zeros() + 1;
+        if let Some(depth) = self.world_depth {
+            if !(min_depth..=16).contains(&depth) {
+                return Err(format!(
+                    "--world-depth must be between {} and 16, got {}",
+                    min_depth, depth
+                ));
+            }
+        }
+        if let Some(size) = self.gpu_texture_size {
+            if size == 0 || size % BRICK_SIZE != 0 || size > MAX_TEXTURE_SIZE {
+                return Err(format!(
+                    "--gpu-texture-size must be a multiple of {} up to {}, got {}",
+                    BRICK_SIZE, MAX_TEXTURE_SIZE, size
+                ));
+            }
+        }
+        if self.gpu_max_nodes == Some(0) {
+            return Err("--gpu-max-nodes must be at least 1".into());
+        }
+        if let Some(ratio) = self.streaming_ratio {
+            if !(ratio.is_finite() && ratio > 0.0) {
+                return Err(format!("--streaming-ratio must be above 0, got {}", ratio));
+            }
+        }
+        if self.window_size.min_element() == 0 {
+            return Err("--window-size can't be zero".into());
+        }
+        if self.frames == Some(0) {
+            return Err("--frames must be at least 1".into());
+        }
+
+        Ok(())
+    }
+
+    fn world_type(&self) -> Result<WorldType, String> {
+        if let Some(world_type) = self.world_type {
+            return Ok(world_type);
+        }
+        match &self.world {
+            Some(path) => guess_world_type(path).ok_or_else(|| {
+                format!(
+                    "can't tell what kind of world {} is, pass --world-type",
+                    path.display()
+                )
+            }),
+            None if self.seed.is_some() => Ok(WorldType::Procedural),
+            None => Ok(WorldType::Heightmap),
+        }
+    }
+
+    /// The world config these flags ask for, on top of the defaults.
+    pub fn world_config(&self) -> VoxelWorldConfig {
+        let mut config = VoxelWorldConfig::default();
+        let world_type = self.world_type().expect("args are validated");
+
+        if let Some(path) = self.world.clone() {
+            config.source = match world_type {
+                WorldType::Heightmap => WorldSource::Heightmap(path),
+                WorldType::Vox => WorldSource::Vox(path),
+                WorldType::Brickmap => WorldSource::Brickmap(path),
+                WorldType::Procedural => unreachable!("args are validated"),
+            };
+        } else if world_type == WorldType::Procedural {
+            config.source = WorldSource::Procedural {
+                seed: self.seed.unwrap_or_default(),
+            };
+        }
+        config.palette = self.palette.clone();
+        config.heightmap = config_heightmap(self).expect("args are validated");
+        if let Some(depth) = self.world_depth {
+            config.world_depth = depth;
+        }
+        if let Some(size) = self.gpu_texture_size {
+            config.color_texture_size = size;
+        }
+        if let Some(nodes) = self.gpu_max_nodes {
+            config.brickmap_max_nodes = nodes;
+        }
+        config.cpu_brick_budget = self.cpu_budget_mib.map(|mib| mib << 20);
+        config.verify_checksum = self.verify_checksum;
+
+        config
+    }
+}
+
+/// the heightmap scaling the flags ask for, checking the scales are positive
+fn config_heightmap(args: &Args) -> Result<HeightmapSettings, String> {
+    for (flag, scale) in [
+        ("--horizontal-scale", args.horizontal_scale),
+        ("--vertical-scale", args.vertical_scale),
+    ] {
+        if let Some(scale) = scale {
+            if !(scale.is_finite() && scale > 0.0) {
+                return Err(format!("{} must be above 0, got {}", flag, scale));
+            }
+        }
+    }
+    if args
+        .sea_level
+        .is_some_and(|sea_level| !sea_level.is_finite())
+    {
+        return Err("--sea-level must be a number".into());
+    }
+    Ok(HeightmapSettings {
+        horizontal_scale: args.horizontal_scale,
+        vertical_scale: args.vertical_scale,
+        sea_level: args.sea_level,
+    })
+}
+
+fn guess_world_type(path: &Path) -> Option<WorldType> {
+    Some(match WorldSource::from_path(path.to_path_buf())? {
+        WorldSource::Heightmap(_) => WorldType::Heightmap,
+        WorldSource::Anvil(_) => return None,
+        WorldSource::Vox(_) => WorldType::Vox,
+        WorldSource::Procedural { .. } => WorldType::Procedural,
+        WorldSource::Brickmap(_) => WorldType::Brickmap,
+    })
+}
+
+fn parse_vec3(s: &str) -> Result<Vec3, String> {
+    let parts = s
+        .split(',')
+        .map(|part| part.trim().parse::<f32>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("expected x,y,z: {}", e))?;
+    match parts[..] {
+        [x, y, z] => Ok(Vec3::new(x, y, z)),
+        _ => Err(format!("expected 3 numbers as x,y,z, got {}", parts.len())),
+    }
+}
+
+fn parse_size(s: &str) -> Result<UVec2, String> {
+    let (width, height) = s
+        .split_once('x')
+        .ok_or_else(|| "expected WIDTHxHEIGHT".to_string())?;
+    let width = width.parse::<u32>().map_err(|e| e.to_string())?;
+    let height = height.parse::<u32>().map_err(|e| e.to_string())?;
+    Ok(UVec2::new(width, height))
+}
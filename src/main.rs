@@ -1,4 +1,5 @@
 use bevy::{
+    app::{AppExit, PluginsState, ScheduleRunnerPlugin},
     core_pipeline::{bloom::BloomSettings, fxaa::Fxaa, tonemapping::Tonemapping},
     prelude::*,
     render::{
@@ -9,29 +10,55 @@ use bevy::{
         texture::{ImageSampler, ImageSamplerDescriptor},
         RenderPlugin,
     },
-    window::{PrimaryWindow, WindowResized, WindowScaleFactorChanged},
+    tasks::tick_global_task_pools_on_main_thread,
+    window::{ExitCondition, PrimaryWindow, WindowResized, WindowScaleFactorChanged},
+    winit::WinitPlugin,
 };
 // use bevy_atmosphere::prelude::*;
+use alex::render_pipeline::{self, StreamingSettings, VoxelVolume, VoxelVolumeBundle};
 use character::CharacterEntity;
-use render_pipeline::{VoxelVolume, VoxelVolumeBundle};
+use cli::Args;
+use std::time::Duration;
 use wgpu::Backends;
 
 mod character;
-mod render_pipeline;
+mod cli;
 mod ui;
 mod ultilities;
 
-fn main() {
-    App::new()
-        .add_plugins((
-            DefaultPlugins.set(RenderPlugin {
-                render_creation: RenderCreation::Automatic(WgpuSettings {
-                    backends: Some(
-                        Backends::BROWSER_WEBGPU
-                            | Backends::GL
-                            | Backends::VULKAN
-                            | Backends::METAL,
-                    ),
+fn main() -> AppExit {
+    let args = Args::parse_and_validate();
+
+    let render_plugin = RenderPlugin {
+        render_creation: RenderCreation::Automatic(WgpuSettings {
+            backends: Some(
+                Backends::BROWSER_WEBGPU | Backends::GL | Backends::VULKAN | Backends::METAL,
+            ),
+            ..default()
+        }),
+        ..default()
+    };
+
+    let mut app = App::new();
+    app.insert_resource(args.world_config());
+    if args.headless {
+        app.add_plugins((
+            DefaultPlugins
+                .set(render_plugin)
+                .set(WindowPlugin {
+                    primary_window: None,
+                    exit_condition: ExitCondition::DontExit,
+                    close_when_requested: false,
+                })
+                .disable::<WinitPlugin>(),
+            ScheduleRunnerPlugin::run_loop(Duration::from_secs_f64(1.0 / 60.0)),
+            render_pipeline::VoxelPlugin,
+        ));
+    } else {
+        app.add_plugins((
+            DefaultPlugins.set(render_plugin).set(WindowPlugin {
+                primary_window: Some(Window {
+                    resolution: args.window_size.as_vec2().into(),
                     ..default()
                 }),
                 ..default()
@@ -41,10 +68,55 @@ fn main() {
             character::CharacterPlugin,
             ui::UiPlugin,
         ))
-        .insert_resource(Msaa::Off)
+        .add_systems(Update, update_render_texture);
+    }
+    if let Some(streaming_ratio) = args.streaming_ratio {
+        app.insert_resource(StreamingSettings {
+            streaming_ratio,
+            ..default()
+        });
+    }
+    if let Some(frames) = args.frames {
+        app.add_systems(Last, exit_after(frames));
+    }
+
+    app.insert_resource(Msaa::Off)
+        .insert_resource(LaunchSettings {
+            camera_pos: args.camera_pos,
+            render_size: args.window_size,
+        })
         .add_systems(Startup, setup)
-        .add_systems(Update, (update_streaming_pos, update_render_texture))
-        .run();
+        .add_systems(Update, update_streaming_pos);
+
+    // finish the plugins here rather than in the runner, so a world that fails
+    // to load exits with an error before anything renders
+    while app.plugins_state() == PluginsState::Adding {
+        tick_global_task_pools_on_main_thread();
+    }
+    app.finish();
+    app.cleanup();
+    if let Some(exit) = app.should_exit() {
+        return exit;
+    }
+
+    app.run()
+}
+
+/// Settings from the command line that `setup` needs.
+#[derive(Resource)]
+struct LaunchSettings {
+    camera_pos: Option<Vec3>,
+    render_size: UVec2,
+}
+
+fn exit_after(frames: u32) -> impl FnMut(EventWriter<AppExit>) {
+    let mut frame = 0;
+    move |mut exit: EventWriter<AppExit>| {
+        frame += 1;
+        if frame >= frames {
+            exit.send(AppExit::Success);
+        }
+    }
 }
 
 #[allow(dead_code)]
@@ -54,12 +126,16 @@ struct CameraData {
     sprite: Entity,
 }
 
-fn setup(mut commands: Commands, mut images: ResMut<Assets<Image>>) {
+fn setup(
+    mut commands: Commands,
+    mut images: ResMut<Assets<Image>>,
+    launch_settings: Res<LaunchSettings>,
+) {
     // we use a render texture to downscale the main pass
     let mut render_texture = Image::new_fill(
         Extent3d {
-            width: 100,
-            height: 100,
+            width: launch_settings.render_size.x,
+            height: launch_settings.render_size.y,
             depth_or_array_layers: 1,
         },
         TextureDimension::D2,
@@ -76,8 +152,11 @@ fn setup(mut commands: Commands, mut images: ResMut<Assets<Image>>) {
     commands.spawn(VoxelVolumeBundle::default());
 
     // add camera with character controller
+    let camera_pos = launch_settings
+        .camera_pos
+        .unwrap_or(Vec3::new(21.035963, 19.771912, -31.12883));
     let character_transform =
-        Transform::from_xyz(21.035963, 19.771912, -31.12883).looking_at(Vec3::ZERO, Vec3::Y);
+        Transform::from_translation(camera_pos).looking_at(Vec3::ZERO, Vec3::Y);
     commands.spawn((
         Camera3dBundle {
             transform: character_transform,
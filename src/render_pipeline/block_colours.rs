@@ -0,0 +1,327 @@
+use super::{material, palette::Palette};
+use bevy::utils::HashMap;
+use fastanvil::biome::Biome;
+use image::RgbaImage;
+use serde::Deserialize;
+use std::path::Path;
+
+const GRASS_COLOURMAP_PATH: &str = "assets/palette/grass-colourmap.png";
+const FOLIAGE_COLOURMAP_PATH: &str = "assets/palette/foliage-colourmap.png";
+
+/// base colour of tinted blocks missing from the palette, so they take the
+/// tint as is
+const UNTEXTURED_COLOUR: [u8; 4] = [255, 255, 255, 255];
+const WATER_ALPHA: u8 = 30;
+
+/// A block from a section's palette with its properties.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct AnvilBlock {
+    pub name: String,
+    #[serde(default)]
+    pub properties: HashMap<String, String>,
+}
+
+impl AnvilBlock {
+    /// a block in its default state
+    pub fn named(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            properties: HashMap::new(),
+        }
+    }
+
+    pub fn property(&self, key: &str) -> Option<&str> {
+        self.properties.get(key).map(String::as_str)
+    }
+
+    /// the key blockstates.json uses, `name|key=value,...` with the properties
+    /// sorted. waterlogged and powered aren't part of it
+    fn encoded(&self) -> String {
+        let mut properties = self
+            .properties
+            .iter()
+            .filter(|(key, _)| *key != "waterlogged" && *key != "powered")
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect::<Vec<_>>();
+        if properties.is_empty() {
+            return self.name.clone();
+        }
+        properties.sort_unstable();
+        format!("{}|{}", self.name, properties.join(","))
+    }
+}
+
+impl From<&fastanvil::Block> for AnvilBlock {
+    /// for chunks from before 1.18, which fastanvil reads into its own blocks.
+    /// waterlogged and powered are lost
+    fn from(block: &fastanvil::Block) -> Self {
+        let properties = block
+            .encoded_description()
+            .split_once('|')
+            .map_or("", |(_, properties)| properties)
+            .split(',')
+            .filter_map(|property| property.split_once('='))
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        Self {
+            name: block.name().to_string(),
+            properties,
+        }
+    }
+}
+
+/// How the biome a block is in changes its colour.
+#[derive(Clone, Copy, Debug)]
+enum Tint {
+    None,
+    Grass,
+    Foliage,
+    Water,
+    Fixed([u8; 3]),
+}
+
+/// The colour of a block before the biome it's in is known.
+#[derive(Clone, Copy, Debug)]
+pub struct BlockColour {
+    colour: [u8; 4],
+    tint: Tint,
+    waterlogged: bool,
+    material: u8,
+}
+
+impl BlockColour {
+    /// the block's index in `MATERIALS`
+    pub fn material(&self) -> u8 {
+        self.material
+    }
+}
+
+/// Colours blocks like Minecraft does, from the block's state and the biome
+/// it's in.
+pub struct BlockColours {
+    palette: Palette,
+    grass: RgbaImage,
+    foliage: RgbaImage,
+}
+
+impl BlockColours {
+    /// loads the palette with the overrides in `overrides`, see
+    /// `Palette::load`, and the colourmaps
+    pub fn load(overrides: Option<&Path>) -> Result<Self, String> {
+        Ok(Self {
+            palette: Palette::load(overrides)?,
+            grass: load_colourmap(Path::new(GRASS_COLOURMAP_PATH))?,
+            foliage: load_colourmap(Path::new(FOLIAGE_COLOURMAP_PATH))?,
+        })
+    }
+
+    pub fn palette(&self) -> &Palette {
+        &self.palette
+    }
+
+    /// the palette colour of `block`'s state, or of another state of the block
+    fn find(&self, block: &AnvilBlock) -> Option<[u8; 4]> {
+        self.palette.base_colour(&block.name, &block.encoded())
+    }
+
+    /// the colour of `block` without its biome, or `None` if it isn't drawn.
+    /// resolve each entry of a section's palette once, then finish it per
+    /// voxel with `colour`
+    pub fn resolve(&self, block: &AnvilBlock) -> Option<BlockColour> {
+        let id = block.name.strip_prefix("minecraft:").unwrap_or(&block.name);
+        if matches!(id, "air" | "cave_air" | "void_air") {
+            return None;
+        }
+        let waterlogged = block.property("waterlogged") == Some("true");
+        let material = block_material(id);
+        // the user's colours win over everything else, including tints
+        if let Some(colour) = self.palette.override_colour(&block.name, &block.encoded()) {
+            return (colour[3] != 0).then_some(BlockColour {
+                colour,
+                tint: Tint::None,
+                waterlogged,
+                material,
+            });
+        }
+
+        let tinted = |tint| (self.find(block).unwrap_or(UNTEXTURED_COLOUR), tint);
+
+        let (colour, tint) = match id {
+            "structure_void" | "barrier" | "light" => return None,
+            // a voxel of grass on every block reads as a raised floor
+            "grass" | "short_grass" | "tall_grass" => return None,
+            "grass_block" if block.property("snowy") == Some("true") => (
+                self.find(&AnvilBlock::named("minecraft:snow_block"))
+                    .unwrap_or_else(|| self.palette.unknown("minecraft:snow_block")),
+                Tint::None,
+            ),
+            "grass_block" | "fern" | "large_fern" => tinted(Tint::Grass),
+            "oak_leaves" | "jungle_leaves" | "acacia_leaves" | "dark_oak_leaves"
+            | "mangrove_leaves" | "vine" => tinted(Tint::Foliage),
+            // the game hardcodes these
+            "birch_leaves" => tinted(Tint::Fixed([0x80, 0xa7, 0x55])),
+            "spruce_leaves" => tinted(Tint::Fixed([0x61, 0x99, 0x61])),
+            "lily_pad" => tinted(Tint::Fixed([0x20, 0x80, 0x30])),
+            "water" | "bubble_column" => ([255, 255, 255, WATER_ALPHA], Tint::Water),
+            "seagrass" | "tall_seagrass" | "kelp" | "kelp_plant" => {
+                (self.find(block).unwrap_or([62, 204, 18, 255]), Tint::None)
+            }
+            // stems ripen from green to yellow as they age
+            "pumpkin_stem" | "melon_stem" => {
+                let age = block
+                    .property("age")
+                    .and_then(|age| age.parse::<u8>().ok())
+                    .unwrap_or(0)
+                    .min(7);
+                tinted(Tint::Fixed([age * 32, 255 - age * 8, age * 4]))
+            }
+            "attached_pumpkin_stem" | "attached_melon_stem" => {
+                tinted(Tint::Fixed([0xe0, 0xc7, 0x1c]))
+            }
+            _ => (
+                self.find(block)
+                    .unwrap_or_else(|| self.palette.unknown(&block.name)),
+                Tint::None,
+            ),
+        };
+
+        Some(BlockColour {
+            colour,
+            tint,
+            waterlogged,
+            material,
+        })
+    }
+
+    /// the colour of `block` in `biome`
+    pub fn colour(&self, block: &BlockColour, biome: Option<Biome>) -> [u8; 4] {
+        let biome = biome
+            .filter(|biome| *biome != Biome::Unknown)
+            .unwrap_or(Biome::Plains);
+        let tint = match block.tint {
+            Tint::None => None,
+            Tint::Grass => Some(sample_colourmap(&self.grass, biome)),
+            Tint::Foliage => Some(sample_colourmap(&self.foliage, biome)),
+            Tint::Water => Some(water_colour(biome)),
+            Tint::Fixed(tint) => Some(tint),
+        };
+
+        let mut colour = block.colour;
+        if let Some(tint) = tint {
+            for channel in 0..3 {
+                colour[channel] = (colour[channel] as u16 * tint[channel] as u16 / 255) as u8;
+            }
+        }
+        if block.waterlogged {
+            // whatever of the block is see through shows the water behind it
+            let water = water_colour(biome);
+            let alpha = colour[3] as u16;
+            for channel in 0..3 {
+                colour[channel] = ((colour[channel] as u16 * alpha
+                    + water[channel] as u16 * (255 - alpha))
+                    / 255) as u8;
+            }
+            colour[3] = colour[3].max(WATER_ALPHA);
+        }
+        colour
+    }
+}
+
+/// the material of a block, by its id without the namespace. waterlogged
+/// blocks keep their own
+fn block_material(id: &str) -> u8 {
+    match id {
+        "water" | "bubble_column" => material::WATER,
+        "lava" => material::LAVA,
+        "ice" | "frosted_ice" => material::ICE,
+        "glowstone"
+        | "sea_lantern"
+        | "shroomlight"
+        | "jack_o_lantern"
+        | "lantern"
+        | "soul_lantern"
+        | "torch"
+        | "wall_torch"
+        | "soul_torch"
+        | "soul_wall_torch"
+        | "end_rod"
+        | "beacon"
+        | "magma_block"
+        | "fire"
+        | "soul_fire"
+        | "campfire"
+        | "soul_campfire"
+        | "ochre_froglight"
+        | "verdant_froglight"
+        | "pearlescent_froglight" => material::LIGHT,
+        "fern"
+        | "large_fern"
+        | "vine"
+        | "lily_pad"
+        | "seagrass"
+        | "tall_seagrass"
+        | "kelp"
+        | "kelp_plant"
+        | "sugar_cane"
+        | "dead_bush"
+        | "pumpkin_stem"
+        | "melon_stem"
+        | "attached_pumpkin_stem"
+        | "attached_melon_stem"
+        | "wheat"
+        | "carrots"
+        | "potatoes"
+        | "beetroots"
+        | "dandelion"
+        | "poppy"
+        | "blue_orchid"
+        | "allium"
+        | "azure_bluet"
+        | "oxeye_daisy"
+        | "cornflower"
+        | "lily_of_the_valley"
+        | "sunflower"
+        | "lilac"
+        | "rose_bush"
+        | "peony" => material::PLANT,
+        _ if id.ends_with("_tulip") || id.ends_with("_sapling") || id.ends_with("_mushroom") => {
+            material::PLANT
+        }
+        _ if id.ends_with("_leaves") => material::FOLIAGE,
+        _ if id.contains("glass") => material::GLASS,
+        _ => material::DEFAULT_MATERIAL,
+    }
+}
+
+fn load_colourmap(path: &Path) -> Result<RgbaImage, String> {
+    Ok(image::open(path)
+        .map_err(|e| format!("failed to load {}: {}", path.display(), e))?
+        .into_rgba8())
+}
+
+/// Minecraft's colourmap lookup, hotter biomes to the left and wetter ones to
+/// the top. Only the lower left triangle is used.
+fn sample_colourmap(colourmap: &RgbaImage, biome: Biome) -> [u8; 3] {
+    let climate = biome.climate();
+    let temperature = climate.temperature.clamp(0.0, 1.0);
+    let rainfall = climate.rainfall.clamp(0.0, 1.0) * temperature;
+
+    let x = ((1.0 - temperature) * (colourmap.width() - 1) as f64).round() as u32;
+    let y = ((1.0 - rainfall) * (colourmap.height() - 1) as f64).round() as u32;
+    let [r, g, b, _] = colourmap.get_pixel(x, y).0;
+    [r, g, b]
+}
+
+fn water_colour(biome: Biome) -> [u8; 3] {
+    use Biome::*;
+    match biome {
+        Swamp | SwampHills => [0x61, 0x7b, 0x64],
+        MangroveSwamp => [0x3a, 0x7a, 0x6a],
+        LukewarmOcean | DeepLukewarmOcean => [0x45, 0xad, 0xf2],
+        WarmOcean | DeepWarmOcean => [0x43, 0xd5, 0xee],
+        ColdOcean | DeepColdOcean => [0x3d, 0x57, 0xd6],
+        FrozenRiver | FrozenOcean | DeepFrozenOcean => [0x39, 0x38, 0xc9],
+        _ => [0x3f, 0x76, 0xe4],
+    }
+}
@@ -0,0 +1,471 @@
+use super::{brickmap_file::MappedBrickmap, cpu_brickmap::Brick, BRICK_SIZE};
+use bevy::prelude::*;
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+/// Storage for the bricks of a `CpuBrickmap`. In memory worlds keep every
+/// brick resident. Worlds opened with `BrickStore::paged` treat memory as a
+/// cache over a brickmap file, evicting the least recently used bricks once
+/// they take up more than `budget` bytes.
+///
+/// The backing file is never written to, so edited and newly pushed bricks
+/// are written back to a temporary spill file when they're evicted and paged
+/// in from there afterwards.
+pub struct BrickStore {
+    slots: Vec<Option<Brick>>,
+    backing: Option<MappedBrickmap>,
+    spill: Option<SpillFile>,
+    /// resident bricks that differ from where they'd be paged in from
+    dirty: Vec<bool>,
+    budget: usize,
+    /// resident bricks and the bytes they use, kept up to date as bricks are
+    /// added, edited and evicted
+    cached: usize,
+    cached_bytes: usize,
+    /// the memory each resident brick was last counted with
+    sizes: Vec<usize>,
+    clock: u64,
+    last_used: Vec<u64>,
+    lru: BTreeMap<u64, usize>,
+}
+
+// never evict below this, a single edit touches a brick and its 8 children
+const MIN_BUDGET: usize = 64 * Brick::RAW_SIZE;
+
+#[allow(dead_code)]
+impl BrickStore {
+    pub fn new() -> Self {
+        Self {
+            slots: Vec::new(),
+            backing: None,
+            spill: None,
+            dirty: Vec::new(),
+            budget: usize::MAX,
+            cached: 0,
+            cached_bytes: 0,
+            sizes: Vec::new(),
+            clock: 0,
+            last_used: Vec::new(),
+            lru: BTreeMap::new(),
+        }
+    }
+
+    /// pages bricks in from `mapped` on demand, keeping at most
+    /// `budget_bytes` worth of bricks in memory
+    pub fn paged(mapped: MappedBrickmap, budget_bytes: usize) -> Self {
+        let brick_count = mapped.brick_count();
+        Self {
+            slots: (0..brick_count).map(|_| None).collect(),
+            backing: Some(mapped),
+            spill: None,
+            dirty: vec![false; brick_count],
+            budget: budget_bytes.max(MIN_BUDGET),
+            cached: 0,
+            cached_bytes: 0,
+            sizes: vec![0; brick_count],
+            clock: 0,
+            last_used: vec![0; brick_count],
+            lru: BTreeMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    /// number of bricks currently resident
+    pub fn cached(&self) -> usize {
+        self.cached
+    }
+
+    pub fn cached_bytes(&self) -> usize {
+        self.cached_bytes
+    }
+
+    /// number of bricks written out to the spill file
+    pub fn spilled(&self) -> usize {
+        self.spill.as_ref().map_or(0, |spill| spill.slots.len())
+    }
+
+    /// number of resident bricks and the bytes they use
+    pub fn memory_usage(&self) -> (usize, usize) {
+        (self.cached, self.cached_bytes)
+    }
+
+    pub fn is_paged(&self) -> bool {
+        self.backing.is_some()
+    }
+
+    pub fn push(&mut self, brick: Brick) {
+        self.slots.push(None);
+        self.dirty.push(false);
+        self.sizes.push(0);
+        self.last_used.push(0);
+        self.set(self.slots.len() - 1, brick);
+    }
+
+    /// takes every brick out of an in memory store
+    pub fn into_resident(self) -> Vec<Brick> {
+        self.slots
+            .into_iter()
+            .map(|brick| brick.expect("brick is paged out"))
+            .collect()
+    }
+
+    pub fn is_resident(&self, index: usize) -> bool {
+        self.slots[index].is_some()
+    }
+
+    /// reads a brick without caching it, copying it out of the spill or
+    /// backing file if it isn't resident. reads never change which bricks are
+    /// resident, so they don't count against the budget
+    pub fn read(&self, index: usize) -> io::Result<Cow<'_, Brick>> {
+        match &self.slots[index] {
+            Some(brick) => Ok(Cow::Borrowed(brick)),
+            None => self.page(index).map(Cow::Owned),
+        }
+    }
+
+    /// pages a brick in if needed and marks it as recently used
+    pub fn load(&mut self, index: usize) -> io::Result<&Brick> {
+        if self.slots[index].is_none() {
+            let brick = self.page(index)?;
+            self.evict(self.budget.saturating_sub(brick.memory_size()));
+            self.insert(index, brick);
+        }
+        self.touch(index);
+        Ok(self.slots[index].as_ref().unwrap())
+    }
+
+    /// pages a brick in and edits it, recounting its memory afterwards. it's
+    /// written back to the spill file if it's evicted later
+    pub fn edit<R>(&mut self, index: usize, f: impl FnOnce(&mut Brick) -> R) -> io::Result<R> {
+        self.load(index)?;
+        self.dirty[index] = true;
+        let result = f(self.slots[index].as_mut().unwrap());
+        let size = self.slots[index].as_ref().unwrap().memory_size();
+        self.cached_bytes = self.cached_bytes - self.sizes[index] + size;
+        self.sizes[index] = size;
+        Ok(result)
+    }
+
+    /// replaces a brick
+    pub fn set(&mut self, index: usize, brick: Brick) {
+        if self.slots[index].take().is_some() {
+            self.cached -= 1;
+            self.cached_bytes -= self.sizes[index];
+        }
+        self.evict(self.budget.saturating_sub(brick.memory_size()));
+        self.insert(index, brick);
+        self.dirty[index] = true;
+        self.touch(index);
+    }
+
+    /// drops bricks, least recently used first, until they take up at most
+    /// `target_bytes`. edited bricks are written to the spill file first
+    pub fn evict(&mut self, target_bytes: usize) {
+        while self.cached_bytes > target_bytes {
+            let Some((_, index)) = self.lru.pop_first() else {
+                break;
+            };
+            self.last_used[index] = 0;
+            let Some(brick) = self.slots[index].take() else {
+                continue;
+            };
+
+            if self.dirty[index] {
+                if let Err(e) = self.write_back(index, &brick) {
+                    // keep it in memory rather than lose the edit
+                    warn!("failed to write brick {} to the spill file: {}", index, e);
+                    self.slots[index] = Some(brick);
+                    self.touch(index);
+                    break;
+                }
+                self.dirty[index] = false;
+            }
+            self.cached -= 1;
+            self.cached_bytes -= self.sizes[index];
+        }
+    }
+
+    /// copies a non resident brick out of the spill file, or the backing file
+    /// if it hasn't been edited
+    fn page(&self, index: usize) -> io::Result<Brick> {
+        if let Some(brick) = self.spill.as_ref().and_then(|spill| spill.read(index)) {
+            return brick;
+        }
+        Ok(self.backing().brick(index))
+    }
+
+    fn write_back(&mut self, index: usize, brick: &Brick) -> io::Result<()> {
+        if self.spill.is_none() {
+            self.spill = Some(SpillFile::create()?);
+        }
+        self.spill.as_mut().unwrap().write(index, brick)
+    }
+
+    fn insert(&mut self, index: usize, brick: Brick) {
+        self.sizes[index] = brick.memory_size();
+        self.cached += 1;
+        self.cached_bytes += self.sizes[index];
+        self.slots[index] = Some(brick);
+    }
+
+    /// marks a brick as recently used
+    fn touch(&mut self, index: usize) {
+        if !self.is_paged() {
+            return;
+        }
+        self.lru.remove(&self.last_used[index]);
+        self.clock += 1;
+        self.last_used[index] = self.clock;
+        self.lru.insert(self.clock, index);
+    }
+
+    fn backing(&self) -> &MappedBrickmap {
+        self.backing
+            .as_ref()
+            .expect("non resident brick in an in memory brick store")
+    }
+}
+
+impl Default for BrickStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Debug for BrickStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BrickStore")
+            .field("len", &self.len())
+            .field("cached", &self.cached)
+            .field("cached_bytes", &self.cached_bytes)
+            .field("spilled", &self.spilled())
+            .field("budget", &self.budget)
+            .field("paged", &self.is_paged())
+            .finish()
+    }
+}
+
+const BRICK_VOXELS: usize = (BRICK_SIZE * BRICK_SIZE * BRICK_SIZE) as usize;
+/// rgba bytes then a material byte for each voxel, as in brickmap files
+const SPILLED_BRICK_SIZE: usize = BRICK_VOXELS * 5;
+
+/// Edited bricks evicted from a paged store, one fixed size slot each in a
+/// temporary file that's deleted along with the store.
+struct SpillFile {
+    path: PathBuf,
+    file: Mutex<File>,
+    /// the slot of each spilled brick
+    slots: HashMap<usize, u64>,
+}
+
+impl SpillFile {
+    fn create() -> io::Result<Self> {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "alex-{}-{}.bricks",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        Ok(Self {
+            path,
+            file: Mutex::new(file),
+            slots: HashMap::new(),
+        })
+    }
+
+    fn write(&mut self, index: usize, brick: &Brick) -> io::Result<()> {
+        let next_slot = self.slots.len() as u64;
+        let slot = *self.slots.entry(index).or_insert(next_slot);
+        let gpu_brick = brick.to_gpu();
+        let file = self.file.get_mut().unwrap();
+        file.seek(SeekFrom::Start(slot * SPILLED_BRICK_SIZE as u64))?;
+        file.write_all(&gpu_brick.colours)?;
+        file.write_all(&gpu_brick.materials)
+    }
+
+    /// the brick at `index` if it's been spilled
+    fn read(&self, index: usize) -> Option<io::Result<Brick>> {
+        let slot = *self.slots.get(&index)?;
+        let mut bytes = vec![0; SPILLED_BRICK_SIZE];
+        let mut file = self.file.lock().unwrap();
+        let result = file
+            .seek(SeekFrom::Start(slot * SPILLED_BRICK_SIZE as u64))
+            .and_then(|_| file.read_exact(&mut bytes))
+            .map(|_| {
+                let (colours, materials) = bytes.split_at(BRICK_VOXELS * 4);
+                Brick::from_bytes(colours, materials)
+            });
+        Some(result)
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render_pipeline::CpuBrickmap;
+
+    /// a raw brick that doesn't compress, different for every seed
+    fn noisy_brick(seed: u32) -> Brick {
+        let mut brick = Brick::empty();
+        for i in 0..BRICK_VOXELS as u32 {
+            let hash = (i ^ seed.wrapping_mul(0x9e37_79b9)).wrapping_mul(0x85eb_ca6b);
+            let pos = UVec3::new(i % BRICK_SIZE, i / BRICK_SIZE % BRICK_SIZE, i / BRICK_SIZE.pow(2));
+            brick.write(pos, [hash as u8, (hash >> 8) as u8, (hash >> 16) as u8, 255]);
+        }
+        brick
+    }
+
+    #[test]
+    fn edits_survive_eviction() {
+        let mut brickmap = CpuBrickmap::new(3);
+        let positions = (0..128u32)
+            .map(|i| UVec3::new(i % 8, i / 8 % 8, i / 64))
+            .collect::<Vec<_>>();
+        for (seed, pos) in positions.iter().enumerate() {
+            brickmap.place_brick(noisy_brick(seed as u32), *pos).unwrap();
+        }
+        brickmap.recreate_mipmaps().unwrap();
+        let path = std::env::temp_dir().join(format!("alex-paged-{}.brickmap", std::process::id()));
+        brickmap.save(&path).unwrap();
+
+        let mut paged = CpuBrickmap::open_paged(&path, 0, true).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // edit a voxel in every brick, far more bricks than the budget holds
+        let half = brickmap.size() as i32 / 2;
+        let edits = positions
+            .iter()
+            .enumerate()
+            .map(|(i, pos)| {
+                let voxel = (*pos * BRICK_SIZE).as_ivec3() - half + (i as i32 % 16);
+                (voxel, [i as u8, 1, 2, 255])
+            })
+            .collect::<Vec<_>>();
+        for (voxel, colour) in edits.iter() {
+            brickmap.set_voxel(*voxel, *colour).unwrap();
+            paged.set_voxel(*voxel, *colour).unwrap();
+        }
+
+        assert!(paged.bricks.cached_bytes() <= MIN_BUDGET);
+        assert!(paged.bricks.spilled() > 0);
+        for (voxel, colour) in edits.iter() {
+            assert_eq!(paged.get_voxel(*voxel).unwrap(), *colour);
+        }
+        for i in 0..brickmap.bricks.len() {
+            assert!(
+                *paged.bricks.read(i).unwrap() == *brickmap.bricks.read(i).unwrap(),
+                "brick {}",
+                i
+            );
+        }
+        paged.validate(true).unwrap();
+    }
+
+    #[test]
+    fn in_memory_store_never_evicts() {
+        let mut store = BrickStore::new();
+        for seed in 0..100 {
+            store.push(noisy_brick(seed));
+        }
+        store.evict(0);
+        assert!((0..100).all(|i| store.is_resident(i)));
+        assert_eq!(store.spilled(), 0);
+    }
+
+    /// the resident bricks and their memory, counted the slow way
+    fn walk(store: &BrickStore) -> (usize, usize) {
+        store
+            .slots
+            .iter()
+            .flatten()
+            .fold((0, 0), |(count, bytes), brick| {
+                (count + 1, bytes + brick.memory_size())
+            })
+    }
+
+    #[test]
+    fn memory_usage_follows_edits() {
+        let mut store = BrickStore::new();
+        store.push(Brick::empty());
+        for seed in 0..10 {
+            store.push(noisy_brick(seed));
+        }
+        assert_eq!(store.memory_usage(), walk(&store));
+
+        // edits that shrink and grow bricks
+        store.edit(1, |brick| *brick = Brick::empty()).unwrap();
+        store.edit(0, |brick| *brick = noisy_brick(10)).unwrap();
+        store.set(2, Brick::empty());
+        assert_eq!(store.memory_usage(), walk(&store));
+
+        let mut brickmap = CpuBrickmap::new(2);
+        brickmap
+            .fill_box(IVec3::splat(-20), IVec3::splat(20), [1, 2, 3, 255])
+            .unwrap();
+        assert_eq!(brickmap.bricks.memory_usage(), walk(&brickmap.bricks));
+        brickmap
+            .fill_box(IVec3::splat(-5), IVec3::splat(5), [0; 4])
+            .unwrap();
+        assert_eq!(brickmap.bricks.memory_usage(), walk(&brickmap.bricks));
+    }
+
+    #[test]
+    fn spill_read_errors_are_returned() {
+        // more bricks than the smallest budget holds
+        let mut brickmap = CpuBrickmap::new(3);
+        for seed in 0..128 {
+            let pos = UVec3::new(seed % 8, seed / 8 % 8, seed / 64);
+            brickmap.place_brick(noisy_brick(seed), pos).unwrap();
+        }
+        let path = std::env::temp_dir().join(format!("alex-spill-{}.brickmap", std::process::id()));
+        brickmap.save(&path).unwrap();
+        let mut paged = CpuBrickmap::open_paged(&path, 0, false).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut edited = Vec::new();
+        for i in 1..paged.bricks.len() {
+            paged
+                .bricks
+                .edit(i, |brick| brick.write(UVec3::ZERO, [1; 4]))
+                .unwrap();
+            edited.push(i);
+        }
+        let spilled = edited
+            .into_iter()
+            .find(|i| !paged.bricks.is_resident(*i))
+            .unwrap();
+
+        // lose the spilled bricks out from under the store
+        let spill = paged.bricks.spill.as_ref().unwrap();
+        spill.file.lock().unwrap().set_len(0).unwrap();
+        assert!(paged.bricks.read(spilled).is_err());
+        assert!(paged.bricks.load(spilled).is_err());
+    }
+}
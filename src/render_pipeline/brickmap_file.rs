@@ -0,0 +1,507 @@
+use super::{
+    brick_store::BrickStore,
+    cpu_brickmap::{Brick, CpuBrickmap, Node},
+    mip_filter::MipFilter,
+    BRICK_SIZE,
+};
+use memmap2::Mmap;
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+const MAGIC: [u8; 8] = *b"ALEXBMAP";
+const VERSION: u32 = 1;
+/// worlds of `BRICK_SIZE << 12` voxels, 65536, are as big as the viewer goes
+const MAX_BRICKMAP_DEPTH: u32 = 12;
+const HEADER_SIZE: usize = 40;
+const CHECKSUM_OFFSET: usize = 32;
+const NODE_SIZE: usize = 8;
+const BRICK_VOXELS: usize = (BRICK_SIZE * BRICK_SIZE * BRICK_SIZE) as usize;
+const COLOUR_BYTES: usize = BRICK_VOXELS * 4;
+
+#[derive(Debug, thiserror::Error)]
+pub enum BrickmapFileError {
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("not a brickmap file")]
+    BadMagic,
+    #[error("unsupported brickmap file version {0}")]
+    UnsupportedVersion(u32),
+    #[error("brickmap depth {0} is out of range, expected 1 to {MAX_BRICKMAP_DEPTH}")]
+    InvalidDepth(u32),
+    #[error("brickmap file claims {nodes} nodes and {bricks} bricks, more than can be addressed")]
+    TooLarge { nodes: u64, bricks: u64 },
+    #[error("brickmap file truncated: expected {expected} bytes, found {found}")]
+    Truncated { expected: u64, found: u64 },
+    #[error("brickmap file checksum mismatch: expected {expected:#010x}, found {found:#010x}")]
+    ChecksumMismatch { expected: u32, found: u32 },
+    #[error("node {0} references data outside of the file")]
+    InvalidNode(usize),
+    #[error("invalid brickmap: {0}")]
+    InvalidStructure(String),
+}
+
+/// The fixed size header at the start of every brickmap file. All values are
+/// little endian.
+///
+/// | offset | size | field          |
+/// |--------|------|----------------|
+/// | 0      | 8    | magic          |
+/// | 8      | 4    | version        |
+/// | 12     | 4    | brickmap depth |
+/// | 16     | 8    | node count     |
+/// | 24     | 8    | brick count    |
+/// | 32     | 4    | crc32          |
+/// | 36     | 4    | reserved       |
+///
+/// The body follows with every node as `children, brick` u32 pairs and then
+/// every brick as raw rgba bytes followed by a material byte for each voxel.
+/// The crc32 covers the header, with the crc32 itself zeroed, and the body.
+#[derive(Clone, Copy, Debug)]
+struct Header {
+    version: u32,
+    brickmap_depth: u32,
+    node_count: u64,
+    brick_count: u64,
+    checksum: u32,
+}
+
+impl Header {
+    fn to_bytes(self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0; HEADER_SIZE];
+        bytes[0..8].copy_from_slice(&MAGIC);
+        bytes[8..12].copy_from_slice(&self.version.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.brickmap_depth.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.node_count.to_le_bytes());
+        bytes[24..32].copy_from_slice(&self.brick_count.to_le_bytes());
+        bytes[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 4].copy_from_slice(&self.checksum.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, BrickmapFileError> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(BrickmapFileError::Truncated {
+                expected: HEADER_SIZE as u64,
+                found: bytes.len() as u64,
+            });
+        }
+        if bytes[0..8] != MAGIC {
+            return Err(BrickmapFileError::BadMagic);
+        }
+
+        let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        let u64_at = |i: usize| u64::from_le_bytes(bytes[i..i + 8].try_into().unwrap());
+        let header = Self {
+            version: u32_at(8),
+            brickmap_depth: u32_at(12),
+            node_count: u64_at(16),
+            brick_count: u64_at(24),
+            checksum: u32_at(CHECKSUM_OFFSET),
+        };
+        if header.version != VERSION {
+            return Err(BrickmapFileError::UnsupportedVersion(header.version));
+        }
+        if !(1..=MAX_BRICKMAP_DEPTH).contains(&header.brickmap_depth) {
+            return Err(BrickmapFileError::InvalidDepth(header.brickmap_depth));
+        }
+        header.file_size()?;
+
+        Ok(header)
+    }
+
+    /// size of the whole file, erroring if the counts overflow it
+    fn file_size(&self) -> Result<u64, BrickmapFileError> {
+        let too_large = || BrickmapFileError::TooLarge {
+            nodes: self.node_count,
+            bricks: self.brick_count,
+        };
+        let size = self
+            .node_count
+            .checked_mul(NODE_SIZE as u64)
+            .and_then(|nodes| {
+                let bricks = self.brick_count.checked_mul(self.brick_bytes() as u64)?;
+                nodes.checked_add(bricks)?.checked_add(HEADER_SIZE as u64)
+            })
+            .ok_or_else(too_large)?;
+        // the file is mapped and indexed with usize, and nodes index bricks
+        // with u32
+        if usize::try_from(size).is_err() || self.brick_count > u32::MAX as u64 {
+            return Err(too_large());
+        }
+        Ok(size)
+    }
+
+    /// bytes a brick takes in the body
+    fn brick_bytes(&self) -> usize {
+        COLOUR_BYTES + BRICK_VOXELS
+    }
+}
+
+/// A brickmap file mapped into memory. Opening only reads the header, nodes
+/// and bricks are read from the mapping on demand.
+pub struct MappedBrickmap {
+    mmap: Mmap,
+    header: Header,
+}
+
+#[allow(dead_code)]
+impl MappedBrickmap {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, BrickmapFileError> {
+        let file = File::open(path)?;
+        // safety: the file is only read and we validate its size up front
+        let mmap = unsafe { Mmap::map(&file)? };
+
+        let header = Header::from_bytes(&mmap)?;
+        let file_size = header.file_size()?;
+        if (mmap.len() as u64) < file_size {
+            return Err(BrickmapFileError::Truncated {
+                expected: file_size,
+                found: mmap.len() as u64,
+            });
+        }
+
+        Ok(Self { mmap, header })
+    }
+
+    pub fn brickmap_depth(&self) -> u32 {
+        self.header.brickmap_depth
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.header.node_count as usize
+    }
+
+    pub fn brick_count(&self) -> usize {
+        self.header.brick_count as usize
+    }
+
+    pub fn node(&self, index: usize) -> Node {
+        let offset = HEADER_SIZE + index * NODE_SIZE;
+        let bytes = &self.mmap[offset..offset + NODE_SIZE];
+        Node {
+            children: u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+            brick: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+        }
+    }
+
+    pub fn brick(&self, index: usize) -> Brick {
+        let offset = self.bricks_offset() + index * self.header.brick_bytes();
+        let colours = &self.mmap[offset..offset + COLOUR_BYTES];
+        let materials = &self.mmap[offset + COLOUR_BYTES..offset + COLOUR_BYTES + BRICK_VOXELS];
+        Brick::from_bytes(colours, materials)
+    }
+
+    fn bricks_offset(&self) -> usize {
+        HEADER_SIZE + self.node_count() * NODE_SIZE
+    }
+
+    /// checks the checksum over the whole file
+    pub fn verify_checksum(&self) -> Result<(), BrickmapFileError> {
+        let mut hasher = crc32fast::Hasher::new();
+        let mut header: [u8; HEADER_SIZE] = self.mmap[..HEADER_SIZE].try_into().unwrap();
+        header[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 4].fill(0);
+        hasher.update(&header);
+        // open already checked the size fits
+        hasher.update(&self.mmap[HEADER_SIZE..self.header.file_size()? as usize]);
+        let found = hasher.finalize();
+        if found != self.header.checksum {
+            return Err(BrickmapFileError::ChecksumMismatch {
+                expected: self.header.checksum,
+                found,
+            });
+        }
+        Ok(())
+    }
+
+    /// checks that every node points inside the file
+    pub fn verify_nodes(&self) -> Result<(), BrickmapFileError> {
+        let node_count = self.node_count();
+        if node_count < 8 || !node_count.is_multiple_of(8) {
+            return Err(BrickmapFileError::InvalidNode(0));
+        }
+        for index in 0..node_count {
+            let node = self.node(index);
+            if 8 * node.children as usize + 8 > node_count
+                || node.brick as usize >= self.brick_count()
+            {
+                return Err(BrickmapFileError::InvalidNode(index));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// copies the whole file into a `CpuBrickmap`
+    pub fn to_brickmap(&self) -> Result<CpuBrickmap, BrickmapFileError> {
+        let mut bricks = BrickStore::new();
+        for i in 0..self.brick_count() {
+            bricks.push(self.brick(i));
+        }
+        let mut brickmap = CpuBrickmap {
+            brickmap: (0..self.node_count()).map(|i| self.node(i)).collect(),
+            brickmap_depth: self.brickmap_depth(),
+            bricks,
+            dedup: None,
+            mip_filter: MipFilter::default(),
+        };
+        brickmap.track_shared_bricks()?;
+        Ok(brickmap)
+    }
+
+    /// reads the nodes into memory and leaves the bricks in the file, paging
+    /// them in as they're needed
+    pub fn into_paged_brickmap(
+        self,
+        budget_bytes: usize,
+    ) -> Result<CpuBrickmap, BrickmapFileError> {
+        let mut brickmap = CpuBrickmap {
+            brickmap: (0..self.node_count()).map(|i| self.node(i)).collect(),
+            brickmap_depth: self.brickmap_depth(),
+            bricks: BrickStore::paged(self, budget_bytes),
+            dedup: None,
+            mip_filter: MipFilter::default(),
+        };
+        brickmap.track_shared_bricks()?;
+        Ok(brickmap)
+    }
+}
+
+#[allow(dead_code)]
+impl CpuBrickmap {
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), BrickmapFileError> {
+        let mut header = Header {
+            version: VERSION,
+            brickmap_depth: self.brickmap_depth,
+            node_count: self.brickmap.len() as u64,
+            brick_count: self.bricks.len() as u64,
+            checksum: 0,
+        };
+
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&header.to_bytes());
+        for node in self.brickmap.iter() {
+            hasher.update(&node.children.to_le_bytes());
+            hasher.update(&node.brick.to_le_bytes());
+        }
+        for i in 0..self.bricks.len() {
+            let brick = self.bricks.read(i)?;
+            let gpu_brick = brick.to_gpu();
+            hasher.update(&gpu_brick.colours);
+            hasher.update(&gpu_brick.materials);
+        }
+
+        header.checksum = hasher.finalize();
+
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(&header.to_bytes())?;
+        for node in self.brickmap.iter() {
+            writer.write_all(&node.children.to_le_bytes())?;
+            writer.write_all(&node.brick.to_le_bytes())?;
+        }
+        for i in 0..self.bricks.len() {
+            let brick = self.bricks.read(i)?;
+            let gpu_brick = brick.to_gpu();
+            writer.write_all(&gpu_brick.colours)?;
+            writer.write_all(&gpu_brick.materials)?;
+        }
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, BrickmapFileError> {
+        let mapped = MappedBrickmap::open(path)?;
+        mapped.verify_checksum()?;
+        mapped.verify_nodes()?;
+        let brickmap = mapped.to_brickmap()?;
+        brickmap
+            .validate(false)
+            .map_err(BrickmapFileError::InvalidStructure)?;
+        Ok(brickmap)
+    }
+
+    /// opens a brickmap file without reading its bricks, keeping at most
+    /// `budget_bytes` of them in memory at once. only the nodes are checked
+    /// unless `verify_checksum` is set, which reads the whole file up front
+    pub fn open_paged(
+        path: impl AsRef<Path>,
+        budget_bytes: usize,
+        verify_checksum: bool,
+    ) -> Result<Self, BrickmapFileError> {
+        let mapped = MappedBrickmap::open(path)?;
+        if verify_checksum {
+            mapped.verify_checksum()?;
+        }
+        mapped.verify_nodes()?;
+        let brickmap = mapped.into_paged_brickmap(budget_bytes)?;
+        brickmap
+            .validate(false)
+            .map_err(BrickmapFileError::InvalidStructure)?;
+        Ok(brickmap)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::math::IVec3;
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("alex-{}-{}.brickmap", name, std::process::id()))
+    }
+
+    fn header_bytes(brickmap_depth: u32, node_count: u64, brick_count: u64) -> [u8; HEADER_SIZE] {
+        Header {
+            version: VERSION,
+            brickmap_depth,
+            node_count,
+            brick_count,
+            checksum: 0,
+        }
+        .to_bytes()
+    }
+
+    fn small_brickmap() -> CpuBrickmap {
+        let mut brickmap = CpuBrickmap::new(3);
+        brickmap.set_voxel(IVec3::ZERO, [255, 0, 0, 255]).unwrap();
+        brickmap.set_voxel(IVec3::new(-40, 3, 20), [0, 255, 0, 128]).unwrap();
+        brickmap.recreate_mipmaps().unwrap();
+        brickmap
+    }
+
+    #[test]
+    fn round_trip() {
+        let brickmap = small_brickmap();
+        let path = temp_path("round-trip");
+        brickmap.save(&path).unwrap();
+        let loaded = CpuBrickmap::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.brickmap_depth, brickmap.brickmap_depth);
+        assert_eq!(loaded.brickmap, brickmap.brickmap);
+        assert_eq!(loaded.get_voxel(IVec3::ZERO).unwrap(), [255, 0, 0, 255]);
+        assert_eq!(loaded.get_voxel(IVec3::new(-40, 3, 20)).unwrap(), [0, 255, 0, 128]);
+        loaded.validate(true).unwrap();
+    }
+
+    #[test]
+    fn overflowing_counts_are_an_error() {
+        for (nodes, bricks) in [(u64::MAX, 8), (8, u64::MAX), (u64::MAX / 8, u64::MAX / 16400)] {
+            assert!(matches!(
+                Header::from_bytes(&header_bytes(3, nodes, bricks)),
+                Err(BrickmapFileError::TooLarge { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn unreasonable_depth_is_rejected() {
+        for depth in [0, MAX_BRICKMAP_DEPTH + 1, u32::MAX] {
+            assert!(matches!(
+                Header::from_bytes(&header_bytes(depth, 8, 1)),
+                Err(BrickmapFileError::InvalidDepth(d)) if d == depth
+            ));
+        }
+    }
+
+    #[test]
+    fn other_versions_are_rejected() {
+        for version in [0, VERSION + 1, 3] {
+            let mut bytes = header_bytes(3, 8, 1);
+            bytes[8..12].copy_from_slice(&version.to_le_bytes());
+            assert!(matches!(
+                Header::from_bytes(&bytes),
+                Err(BrickmapFileError::UnsupportedVersion(v)) if v == version
+            ));
+        }
+    }
+
+    #[test]
+    fn header_is_checksummed() {
+        let path = temp_path("header-checksum");
+        small_brickmap().save(&path).unwrap();
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[HEADER_SIZE - 1] ^= 1;
+        std::fs::write(&path, bytes).unwrap();
+        let result = CpuBrickmap::load(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(
+            result,
+            Err(BrickmapFileError::ChecksumMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn truncated_file_is_an_error() {
+        let path = temp_path("truncated");
+        small_brickmap().save(&path).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        let result = CpuBrickmap::load(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(result, Err(BrickmapFileError::Truncated { .. })));
+    }
+
+    #[test]
+    fn malformed_trees_are_rejected() {
+        let brickmap = small_brickmap();
+        let parent = (0..8)
+            .find(|&i| brickmap.brickmap[i].children != 0)
+            .unwrap();
+        let children = brickmap.brickmap[parent].children as usize;
+        let child = (0..8)
+            .map(|i| 8 * children + i)
+            .find(|&i| brickmap.brickmap[i].children != 0)
+            .unwrap();
+
+        // a child pointing back at its own group, and a mip brick cut off
+        // from its children so it becomes a leaf above the bottom level
+        let mut cyclic = small_brickmap();
+        cyclic.brickmap[child].children = children as u32;
+        let mut shallow_leaf = small_brickmap();
+        shallow_leaf.brickmap[child].children = 0;
+
+        for (name, brickmap) in [("cyclic", cyclic), ("shallow-leaf", shallow_leaf)] {
+            let path = temp_path(name);
+            brickmap.save(&path).unwrap();
+            let loaded = CpuBrickmap::load(&path);
+            let paged = CpuBrickmap::open_paged(&path, 0, false);
+            std::fs::remove_file(&path).unwrap();
+
+            assert!(
+                matches!(loaded, Err(BrickmapFileError::InvalidStructure(_))),
+                "{}",
+                name
+            );
+            assert!(
+                matches!(paged, Err(BrickmapFileError::InvalidStructure(_))),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn paged_checksum_is_opt_in() {
+        let path = temp_path("paged-checksum");
+        small_brickmap().save(&path).unwrap();
+        let mut bytes = std::fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        std::fs::write(&path, bytes).unwrap();
+        let unchecked = CpuBrickmap::open_paged(&path, 0, false);
+        let checked = CpuBrickmap::open_paged(&path, 0, true);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(unchecked.is_ok());
+        assert!(matches!(
+            checked,
+            Err(BrickmapFileError::ChecksumMismatch { .. })
+        ));
+    }
+}
@@ -1,17 +1,84 @@
-use super::BRICK_SIZE;
+use super::{
+    brick_store::BrickStore, material::DEFAULT_MATERIAL, mip_filter::MipFilter, BRICK_SIZE,
+};
 use bevy::prelude::*;
 use rayon::prelude::*;
 use std::{
-    ops::DerefMut,
-    sync::{Arc, Mutex},
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    hash::{DefaultHasher, Hash, Hasher},
+    io,
 };
 
-#[derive(Copy, Clone, Debug)]
+/// interleaves the bits of `pos` so nearby bricks sort next to each other
+fn morton_code(pos: UVec3) -> u64 {
+    fn spread(v: u32) -> u64 {
+        let mut v = v as u64 & 0x1f_ffff;
+        v = (v | v << 32) & 0x1f_0000_0000_ffff;
+        v = (v | v << 16) & 0x1f_0000_ff00_00ff;
+        v = (v | v << 8) & 0x100f_00f0_0f00_f00f;
+        v = (v | v << 4) & 0x10c3_0c30_c30c_30c3;
+        v = (v | v << 2) & 0x1249_2492_4924_9249;
+        v
+    }
+    spread(pos.x) << 2 | spread(pos.y) << 1 | spread(pos.z)
+}
+
+const BRICK_VOXELS: usize = (BRICK_SIZE * BRICK_SIZE * BRICK_SIZE) as usize;
+
+fn brick_error(brick_index: usize, e: io::Error) -> String {
+    format!("failed to read brick {}: {}", brick_index, e)
+}
+
+/// the first of `candidates` holding the same voxels as `brick`
+fn find_brick(bricks: &BrickStore, candidates: &[u32], brick: &Brick) -> Result<Option<u32>, String> {
+    for &i in candidates {
+        let candidate = bricks.read(i as usize).map_err(|e| brick_error(i as usize, e))?;
+        if *candidate == *brick {
+            return Ok(Some(i));
+        }
+    }
+    Ok(None)
+}
+
+/// A cube of `BRICK_SIZE` voxels, each with a colour and a material from
+/// `MATERIALS`. Bricks only store materials once a voxel has one other than
+/// the default.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Brick {
-    data: [[u8; 4]; (BRICK_SIZE * BRICK_SIZE * BRICK_SIZE) as usize],
+    colours: BrickColours,
+    /// the material of every voxel, `None` while they're all the default
+    materials: Option<Box<[u8; BRICK_VOXELS]>>,
+}
+
+/// The colours of a brick. Bricks start out empty and are promoted to a
+/// bigger representation as colours are written into them. `compact` goes
+/// the other way and picks the smallest representation for the contents.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum BrickColours {
+    Empty,
+    Uniform([u8; 4]),
+    /// up to 256 colours. indices are packed two to a byte while the palette
+    /// fits in 16 entries, one per byte otherwise
+    Palette {
+        palette: Vec<[u8; 4]>,
+        indices: Vec<u8>,
+    },
+    Raw(Box<[[u8; 4]; BRICK_VOXELS]>),
 }
 
-#[derive(Copy, Clone, Debug)]
+/// A brick laid out for the gpu textures.
+#[derive(PartialEq, Eq)]
+pub struct GpuBrick<'a> {
+    /// rgba bytes for the colour texture
+    pub colours: Cow<'a, [u8]>,
+    /// a byte a voxel for the material texture
+    pub materials: Cow<'a, [u8]>,
+}
+
+static DEFAULT_MATERIALS: [u8; BRICK_VOXELS] = [DEFAULT_MATERIAL; BRICK_VOXELS];
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct Node {
     pub children: u32,
     pub brick: u32,
@@ -28,31 +95,330 @@ impl Node {
 pub struct CpuBrickmap {
     pub brickmap: Vec<Node>,
     pub brickmap_depth: u32,
-    pub bricks: Vec<Brick>,
+    pub bricks: BrickStore,
+    pub dedup: Option<BrickDedup>,
+    pub mip_filter: MipFilter,
+}
+
+/// Lets identical leaf bricks share one slot in `CpuBrickmap::bricks`. Shared
+/// bricks are copied before they're written to.
+#[derive(Debug, Default)]
+pub struct BrickDedup {
+    by_hash: HashMap<u64, Vec<u32>>,
+    refs: HashMap<u32, u32>,
+}
+
+impl BrickDedup {
+    fn hash(brick: &Brick) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        brick.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn forget(&mut self, brick_index: u32, brick: &Brick) {
+        self.refs.remove(&brick_index);
+        let hash = Self::hash(brick);
+        if let Some(candidates) = self.by_hash.get_mut(&hash) {
+            candidates.retain(|i| *i != brick_index);
+            if candidates.is_empty() {
+                self.by_hash.remove(&hash);
+            }
+        }
+    }
 }
 
 #[allow(dead_code)]
 impl CpuBrickmap {
     pub fn new(brickmap_depth: u32) -> Self {
+        let mut bricks = BrickStore::new();
+        bricks.push(Brick::empty());
         Self {
             brickmap: vec![Node::ZERO; 8],
             brickmap_depth,
-            bricks: vec![Brick::empty()],
+            bricks,
+            dedup: None,
+            mip_filter: MipFilter::default(),
         }
     }
-    pub fn place_bricks(&mut self, bricks_to_insert: Vec<(Brick, UVec3)>) -> Result<(), String> {
-        let brickmap = Arc::new(Mutex::new(self));
 
-        bricks_to_insert.par_iter().try_for_each(|(brick, pos)| {
-            let mut brickmap = brickmap.lock().unwrap();
-            brickmap.place_brick(brick.clone(), *pos)
-        })?;
+    /// sets the filter used to build mips from now on
+    pub fn with_mip_filter(mut self, mip_filter: MipFilter) -> Self {
+        self.mip_filter = mip_filter;
+        self
+    }
 
-        // No need to reassign self, as the changes have been made through the mutex
+    /// share storage between identical bricks placed from now on
+    pub fn enable_dedup(&mut self) {
+        if self.dedup.is_none() {
+            self.dedup = Some(BrickDedup::default());
+        }
+    }
+
+    /// rebuilds the dedup reference counts from bricks used by more than one
+    /// node, needed before editing a brickmap loaded from disk
+    pub fn track_shared_bricks(&mut self) -> io::Result<()> {
+        let mut counts = HashMap::<u32, u32>::new();
+        for node in self.brickmap.iter().filter(|node| node.brick != 0) {
+            *counts.entry(node.brick).or_default() += 1;
+        }
+        counts.retain(|_, count| *count > 1);
+        if counts.is_empty() {
+            return Ok(());
+        }
+
+        let dedup = self.dedup.get_or_insert_with(BrickDedup::default);
+        for (brick_index, count) in counts {
+            let hash = BrickDedup::hash(&*self.bricks.read(brick_index as usize)?);
+            dedup.by_hash.entry(hash).or_default().push(brick_index);
+            dedup.refs.insert(brick_index, count);
+        }
         Ok(())
     }
+    /// places many bricks at once, building each top level octant on its own
+    /// rayon worker. bricks are placed in morton order, so the layout only
+    /// depends on the input. octants that already hold bricks fall back to
+    /// `place_brick`
+    pub fn place_bricks(
+        &mut self,
+        mut bricks_to_insert: Vec<(Brick, UVec3)>,
+    ) -> Result<(), String> {
+        let side_length = 1 << self.brickmap_depth;
+        if let Some((_, pos)) = bricks_to_insert
+            .iter()
+            .find(|(_, pos)| pos.cmpge(UVec3::splat(side_length)).any())
+        {
+            return Err(format!("brick position {} outside of brickmap", pos));
+        }
+        if self.brickmap_depth < 2 {
+            return self.place_bricks_serial(bricks_to_insert);
+        }
+
+        // stable so later bricks at the same position still win
+        bricks_to_insert.par_sort_by_cached_key(|(_, pos)| morton_code(*pos));
+
+        let half = side_length / 2;
+        let mut octants: [Vec<(Brick, UVec3)>; 8] = Default::default();
+        for (brick, pos) in bricks_to_insert {
+            let mask = pos.cmpge(UVec3::splat(half));
+            let octant = mask.x as usize * 4 + mask.y as usize * 2 + mask.z as usize;
+            octants[octant].push((brick, pos % half));
+        }
 
-    pub fn place_brick(&mut self, brick: Brick, pos: UVec3) -> Result<(), String> {
+        // octants with existing content have to be merged brick by brick
+        let mut serial = Vec::new();
+        for (octant, bricks) in octants.iter_mut().enumerate() {
+            let node = self.brickmap[octant];
+            if node.children != 0 || node.brick != 0 {
+                let offset = UVec3::new(
+                    octant as u32 >> 2 & 1,
+                    octant as u32 >> 1 & 1,
+                    octant as u32 & 1,
+                ) * half;
+                serial.extend(bricks.drain(..).map(|(brick, pos)| (brick, pos + offset)));
+            }
+        }
+
+        let subtree_depth = self.brickmap_depth - 1;
+        let dedup = self.dedup.is_some();
+        let subtrees = octants
+            .into_par_iter()
+            .map(|bricks| {
+                let mut subtree = CpuBrickmap::new(subtree_depth);
+                if dedup {
+                    subtree.enable_dedup();
+                }
+                subtree.place_bricks_serial(bricks).map(|_| subtree)
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        for (octant, subtree) in subtrees.into_iter().enumerate() {
+            self.graft(octant, subtree)?;
+        }
+
+        self.place_bricks_serial(serial)
+    }
+
+    pub fn place_bricks_serial(
+        &mut self,
+        bricks_to_insert: Vec<(Brick, UVec3)>,
+    ) -> Result<(), String> {
+        for (brick, pos) in bricks_to_insert {
+            self.place_brick(brick, pos)?;
+        }
+        Ok(())
+    }
+
+    /// appends the nodes and bricks of `subtree` below the empty top level
+    /// node `octant`
+    fn graft(&mut self, octant: usize, subtree: CpuBrickmap) -> Result<(), String> {
+        if subtree.brickmap[..8]
+            .iter()
+            .all(|node| node.children == 0 && node.brick == 0)
+        {
+            return Ok(());
+        }
+
+        // move the bricks over, merging deduplicated ones with ours
+        let shared = subtree.dedup.map(|dedup| dedup.refs).unwrap_or_default();
+        let mut remap = Vec::with_capacity(subtree.bricks.len());
+        for (index, brick) in subtree.bricks.into_resident().into_iter().enumerate() {
+            let brick_index = match (shared.get(&(index as u32)), &mut self.dedup) {
+                (Some(&count), Some(dedup)) => {
+                    let candidates = dedup.by_hash.entry(BrickDedup::hash(&brick)).or_default();
+                    let existing = find_brick(&self.bricks, candidates, &brick)?;
+                    let brick_index = existing.unwrap_or_else(|| {
+                        let brick_index = self.bricks.len() as u32;
+                        candidates.push(brick_index);
+                        self.bricks.push(brick);
+                        brick_index
+                    });
+                    *dedup.refs.entry(brick_index).or_default() += count;
+                    brick_index
+                }
+                _ if index == 0 => 0,
+                _ => {
+                    let brick_index = self.bricks.len() as u32;
+                    self.bricks.push(brick);
+                    brick_index
+                }
+            };
+            remap.push(brick_index);
+        }
+
+        let node_offset = self.brickmap.len() as u32 / 8;
+        self.brickmap
+            .extend(subtree.brickmap.iter().map(|node| Node {
+                children: if node.children == 0 {
+                    0
+                } else {
+                    node.children + node_offset
+                },
+                brick: remap[node.brick as usize],
+            }));
+
+        // the octant node itself needs a brick for its mip
+        self.brickmap[octant] = Node {
+            children: node_offset,
+            brick: self.bricks.len() as u32,
+        };
+        self.bricks.push(Brick::empty());
+        Ok(())
+    }
+
+    /// places a brick at a leaf node. empty bricks don't subdivide anything
+    /// and clear whatever brick was there before
+    pub fn place_brick(&mut self, mut brick: Brick, pos: UVec3) -> Result<(), String> {
+        if brick.is_empty() {
+            if pos.cmpge(UVec3::splat(1 << self.brickmap_depth)).any() {
+                return Err(format!("brick position {} outside of brickmap", pos));
+            }
+            let path = self.path_to(pos);
+            if path.len() == self.brickmap_depth as usize {
+                let leaf = *path.last().unwrap();
+                self.release_brick(leaf)?;
+                self.brickmap[leaf].brick = 0;
+            }
+            return Ok(());
+        }
+
+        brick.compact();
+        let path = self.leaf_path(pos)?;
+        let leaf = *path.last().unwrap();
+        self.release_brick(leaf)?;
+
+        // reuse an identical brick if there is one
+        if let Some(dedup) = &mut self.dedup {
+            let hash = BrickDedup::hash(&brick);
+            let candidates = dedup.by_hash.entry(hash).or_default();
+            let existing = find_brick(&self.bricks, candidates, &brick)?;
+            let brick_index = match existing {
+                Some(brick_index) => brick_index,
+                None => {
+                    let brick_index = self.bricks.len() as u32;
+                    candidates.push(brick_index);
+                    self.bricks.push(brick);
+                    brick_index
+                }
+            };
+            *dedup.refs.entry(brick_index).or_default() += 1;
+            self.brickmap[leaf].brick = brick_index;
+            return Ok(());
+        }
+
+        // place in data
+        let brick_index = self.bricks.len() as u32;
+        self.brickmap[leaf].brick = brick_index;
+        self.bricks.push(brick);
+
+        Ok(())
+    }
+
+    /// drops the reference a leaf holds on a deduplicated brick
+    fn release_brick(&mut self, leaf: usize) -> Result<(), String> {
+        let brick_index = self.brickmap[leaf].brick;
+        let Some(dedup) = &mut self.dedup else {
+            return Ok(());
+        };
+        match dedup.refs.get_mut(&brick_index) {
+            Some(count) if *count > 1 => *count -= 1,
+            Some(_) => {
+                let brick = self
+                    .bricks
+                    .read(brick_index as usize)
+                    .map_err(|e| brick_error(brick_index as usize, e))?;
+                dedup.forget(brick_index, &brick);
+            }
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// drops the unshared brick of a leaf that's been cleared to fully
+    /// transparent, leaving an empty leaf as placing an empty brick does
+    fn free_brick(&mut self, leaf: usize, brick_index: usize) -> Result<(), String> {
+        self.release_brick(leaf)?;
+        self.brickmap[leaf].brick = 0;
+        self.bricks.set(brick_index, Brick::empty());
+        Ok(())
+    }
+
+    /// makes sure the brick of a leaf isn't shared before it's written to,
+    /// copying it if needed. returns the brick index to write to
+    fn unshare_brick(&mut self, leaf: usize) -> Result<usize, String> {
+        let brick_index = self.brickmap[leaf].brick;
+        let Some(dedup) = &mut self.dedup else {
+            return Ok(brick_index as usize);
+        };
+        if !dedup.refs.contains_key(&brick_index) {
+            return Ok(brick_index as usize);
+        }
+        let brick = self
+            .bricks
+            .read(brick_index as usize)
+            .map_err(|e| brick_error(brick_index as usize, e))?;
+        let count = dedup.refs.get_mut(&brick_index).unwrap();
+        if *count > 1 {
+            *count -= 1;
+            let copy = brick.into_owned();
+            let new_index = self.bricks.len() as u32;
+            self.bricks.push(copy);
+            self.brickmap[leaf].brick = new_index;
+            Ok(new_index as usize)
+        } else {
+            // the only user is about to change it, so it can't be matched anymore
+            dedup.forget(brick_index, &brick);
+            Ok(brick_index as usize)
+        }
+    }
+
+    /// walks down to the leaf node containing the brick at `pos`, subdividing
+    /// along the way. returns the node indices from the top level to the leaf
+    fn leaf_path(&mut self, pos: UVec3) -> Result<Vec<usize>, String> {
+        if pos.cmpge(UVec3::splat(1 << self.brickmap_depth)).any() {
+            return Err(format!("brick position {} outside of brickmap", pos));
+        }
+
+        let mut path = Vec::with_capacity(self.brickmap_depth as usize);
         let mut node_index = 0;
         let mut node_pos = UVec3::new(0, 0, 0);
         let mut node_depth = 1;
@@ -63,16 +429,12 @@ impl CpuBrickmap {
 
             let child_index = mask.x as usize * 4 + mask.y as usize * 2 + mask.z as usize;
             let index = node_index + child_index;
+            path.push(index);
 
             let mut new_node = 8 * self.brickmap[index].children as usize;
             if new_node == 0 {
                 if node_depth == self.brickmap_depth {
-                    // place in data
-                    let brick_index = self.bricks.len() as u32;
-                    self.brickmap[index].brick = brick_index;
-                    self.bricks.push(brick);
-
-                    return Ok(());
+                    return Ok(path);
                 } else {
                     // subdivide node and continue
                     let new_children_index = self.brickmap.len() as u32;
@@ -93,6 +455,233 @@ impl CpuBrickmap {
             node_index = new_node;
         }
     }
+
+    /// side length of the world in voxels
+    pub fn size(&self) -> u32 {
+        BRICK_SIZE << self.brickmap_depth
+    }
+
+    /// converts a centred world voxel position into a position inside the
+    /// brickmap, or `None` if it lies outside of it
+    pub fn voxel_pos(&self, pos: IVec3) -> Option<UVec3> {
+        let pos = pos + IVec3::splat(self.size() as i32 / 2);
+        if pos.cmplt(IVec3::ZERO).any() || pos.cmpge(IVec3::splat(self.size() as i32)).any() {
+            return None;
+        }
+        Some(pos.as_uvec3())
+    }
+
+    /// sets a single voxel, creating the leaf brick if needed, and updates the
+    /// mips above it. returns the nodes whose bricks changed
+    pub fn set_voxel(&mut self, pos: IVec3, colour: [u8; 4]) -> Result<Vec<usize>, String> {
+        self.fill_box(pos, pos, colour)
+    }
+
+    pub fn clear_voxel(&mut self, pos: IVec3) -> Result<Vec<usize>, String> {
+        self.fill_box(pos, pos, [0; 4])
+    }
+
+    /// fills the inclusive box `min..=max` with `colour`. clearing never
+    /// creates new bricks. returns the nodes whose bricks changed
+    pub fn fill_box(
+        &mut self,
+        min: IVec3,
+        max: IVec3,
+        colour: [u8; 4],
+    ) -> Result<Vec<usize>, String> {
+        let (Some(min), Some(max)) = (self.voxel_pos(min.min(max)), self.voxel_pos(min.max(max)))
+        else {
+            return Err(format!("box {}..={} outside of brickmap", min, max));
+        };
+
+        let mut changed = Vec::new();
+        let min_brick = min / BRICK_SIZE;
+        let max_brick = max / BRICK_SIZE;
+        for x in min_brick.x..=max_brick.x {
+            for y in min_brick.y..=max_brick.y {
+                for z in min_brick.z..=max_brick.z {
+                    let brick_pos = UVec3::new(x, y, z);
+                    let path = if colour[3] == 0 {
+                        let path = self.path_to(brick_pos);
+                        let leaf = *path.last().unwrap();
+                        if path.len() != self.brickmap_depth as usize
+                            || self.brickmap[leaf].brick == 0
+                        {
+                            continue;
+                        }
+                        path
+                    } else {
+                        self.leaf_path(brick_pos)?
+                    };
+
+                    let leaf = *path.last().unwrap();
+                    if self.brickmap[leaf].brick == 0 {
+                        self.brickmap[leaf].brick = self.bricks.len() as u32;
+                        self.bricks.push(Brick::empty());
+                    }
+
+                    // write the voxels inside this brick
+                    let brick_min = brick_pos * BRICK_SIZE;
+                    let local_min = min.max(brick_min) - brick_min;
+                    let local_max = max.min(brick_min + BRICK_SIZE - 1) - brick_min;
+                    let brick_index = self.unshare_brick(leaf)?;
+                    let cleared = self
+                        .bricks
+                        .edit(brick_index, |brick| {
+                            for x in local_min.x..=local_max.x {
+                                for y in local_min.y..=local_max.y {
+                                    for z in local_min.z..=local_max.z {
+                                        brick.write(UVec3::new(x, y, z), colour);
+                                    }
+                                }
+                            }
+                            colour[3] == 0 && brick.is_empty()
+                        })
+                        .map_err(|e| brick_error(brick_index, e))?;
+                    if cleared {
+                        self.free_brick(leaf, brick_index)?;
+                    }
+
+                    self.propagate_mips(&path, local_min, local_max)?;
+                    changed.extend(path);
+                }
+            }
+        }
+
+        changed.sort_unstable();
+        changed.dedup();
+        Ok(changed)
+    }
+
+    /// sets many voxels at once, touching each brick and its mips only once.
+    /// later voxels at the same position win. returns the nodes whose bricks
+    /// changed
+    pub fn set_voxels(
+        &mut self,
+        voxels: impl IntoIterator<Item = (IVec3, [u8; 4])>,
+    ) -> Result<Vec<usize>, String> {
+        let mut by_brick: HashMap<UVec3, Vec<(UVec3, [u8; 4])>> = HashMap::new();
+        for (pos, colour) in voxels {
+            let Some(pos) = self.voxel_pos(pos) else {
+                return Err(format!("voxel {} outside of brickmap", pos));
+            };
+            by_brick
+                .entry(pos / BRICK_SIZE)
+                .or_default()
+                .push((pos % BRICK_SIZE, colour));
+        }
+
+        let mut changed = Vec::new();
+        for (brick_pos, voxels) in by_brick {
+            let path = if voxels.iter().all(|(_, colour)| colour[3] == 0) {
+                let path = self.path_to(brick_pos);
+                let leaf = *path.last().unwrap();
+                if path.len() != self.brickmap_depth as usize || self.brickmap[leaf].brick == 0 {
+                    continue;
+                }
+                path
+            } else {
+                self.leaf_path(brick_pos)?
+            };
+
+            let leaf = *path.last().unwrap();
+            if self.brickmap[leaf].brick == 0 {
+                self.brickmap[leaf].brick = self.bricks.len() as u32;
+                self.bricks.push(Brick::empty());
+            }
+
+            let brick_index = self.unshare_brick(leaf)?;
+            let (mut local_min, mut local_max) = (UVec3::splat(BRICK_SIZE), UVec3::ZERO);
+            let clearing = voxels.iter().any(|(_, colour)| colour[3] == 0);
+            let cleared = self
+                .bricks
+                .edit(brick_index, |brick| {
+                    for (pos, colour) in voxels {
+                        brick.write(pos, colour);
+                        local_min = local_min.min(pos);
+                        local_max = local_max.max(pos);
+                    }
+                    clearing && brick.is_empty()
+                })
+                .map_err(|e| brick_error(brick_index, e))?;
+            if cleared {
+                self.free_brick(leaf, brick_index)?;
+            }
+
+            self.propagate_mips(&path, local_min, local_max)?;
+            changed.extend(path);
+        }
+
+        changed.sort_unstable();
+        changed.dedup();
+        Ok(changed)
+    }
+
+    /// node indices from the top level down to the deepest existing node
+    /// containing the brick at `pos`
+    fn path_to(&self, pos: UVec3) -> Vec<usize> {
+        let mut path = Vec::with_capacity(self.brickmap_depth as usize);
+        let mut node_index = 0;
+        let mut node_pos = UVec3::new(0, 0, 0);
+        let mut node_depth = 1;
+        loop {
+            let offset = UVec3::splat(1 << (self.brickmap_depth - node_depth));
+            let mask = pos.cmpge(node_pos + offset);
+            node_pos += UVec3::select(mask, offset, UVec3::ZERO);
+
+            let child_index = mask.x as usize * 4 + mask.y as usize * 2 + mask.z as usize;
+            let index = node_index + child_index;
+            path.push(index);
+
+            let new_node = 8 * self.brickmap[index].children as usize;
+            if new_node == 0 {
+                return path;
+            }
+
+            node_depth += 1;
+            node_index = new_node;
+        }
+    }
+
+    /// re-averages the parent voxels covering `local_min..=local_max` of the
+    /// last brick in `path`, walking up to the top level
+    fn propagate_mips(
+        &mut self,
+        path: &[usize],
+        mut local_min: UVec3,
+        mut local_max: UVec3,
+    ) -> Result<(), String> {
+        for i in (1..path.len()).rev() {
+            let parent = path[i - 1];
+            let children_index = 8 * self.brickmap[parent].children as usize;
+            let child_index = (path[i] - children_index) as u32;
+            let child_offset =
+                UVec3::new(child_index >> 2 & 1, child_index >> 1 & 1, child_index & 1)
+                    * (BRICK_SIZE / 2);
+            local_min = child_offset + local_min / 2;
+            local_max = child_offset + local_max / 2;
+
+            let mut mips = Vec::new();
+            for x in local_min.x..=local_max.x {
+                for y in local_min.y..=local_max.y {
+                    for z in local_min.z..=local_max.z {
+                        let pos = UVec3::new(x, y, z);
+                        mips.push((pos, self.mip_voxel(children_index, pos)?));
+                    }
+                }
+            }
+            let brick_index = self.brickmap[parent].brick as usize;
+            self.bricks
+                .edit(brick_index, |brick| {
+                    for (pos, (colour, material)) in mips {
+                        brick.write_material(pos, colour, material);
+                    }
+                })
+                .map_err(|e| brick_error(brick_index, e))?;
+        }
+        Ok(())
+    }
+
     pub fn get_node(&self, pos: UVec3, max_depth: Option<u32>) -> (usize, UVec3, u32) {
         let mut node_index = 0;
         let mut node_pos = UVec3::new(0, 0, 0);
@@ -115,8 +704,261 @@ impl CpuBrickmap {
         }
     }
 
+    /// reads a brick, paging it in without caching it if it isn't resident
+    pub fn read_brick(&self, brick_index: usize) -> Result<Cow<'_, Brick>, String> {
+        self.bricks
+            .read(brick_index)
+            .map_err(|e| brick_error(brick_index, e))
+    }
+
+    /// colour of the voxel at a centred world position, transparent outside
+    /// of the world
+    pub fn get_voxel(&self, pos: IVec3) -> Result<[u8; 4], String> {
+        self.get_voxel_at_lod(pos, self.brickmap_depth)
+    }
+
+    /// colour of the voxel at a centred world position, read from the mip at
+    /// `depth`. depth 1 is the coarsest level, `brickmap_depth` the leaves
+    pub fn get_voxel_at_lod(&self, pos: IVec3, depth: u32) -> Result<[u8; 4], String> {
+        let Some(voxel_pos) = self.voxel_pos(pos) else {
+            return Ok([0; 4]);
+        };
+
+        let (index, node_pos, node_depth) =
+            self.get_node(voxel_pos / BRICK_SIZE, Some(depth.max(1)));
+        let brick_index = self.brickmap[index].brick as usize;
+        if brick_index == 0 {
+            return Ok([0; 4]);
+        }
+
+        let scale = 1 << (self.brickmap_depth - node_depth);
+        let local = (voxel_pos - node_pos * BRICK_SIZE) / scale;
+        Ok(self.read_brick(brick_index)?.get(local))
+    }
+
+    /// material of the leaf voxel at a centred world position, the default
+    /// outside of the world
+    pub fn get_material(&self, pos: IVec3) -> Result<u8, String> {
+        let Some(voxel_pos) = self.voxel_pos(pos) else {
+            return Ok(DEFAULT_MATERIAL);
+        };
+
+        let (index, node_pos, node_depth) = self.get_node(voxel_pos / BRICK_SIZE, None);
+        let brick_index = self.brickmap[index].brick as usize;
+        if brick_index == 0 || node_depth != self.brickmap_depth {
+            return Ok(DEFAULT_MATERIAL);
+        }
+
+        Ok(self
+            .read_brick(brick_index)?
+            .material(voxel_pos - node_pos * BRICK_SIZE))
+    }
+
+    /// copies the inclusive box `min..=max` into a dense array indexed by
+    /// `x + y * size.x + z * size.x * size.y`, where `size = max - min + 1`.
+    /// voxels outside of the world are transparent
+    pub fn read_box(&self, min: IVec3, max: IVec3) -> Result<Vec<[u8; 4]>, String> {
+        let (min, max) = (min.min(max), min.max(max));
+        let size = (max - min + 1).as_uvec3();
+        let mut voxels = vec![[0; 4]; (size.x * size.y * size.z) as usize];
+
+        // clamp to the world and walk the leaf bricks it overlaps
+        let half = IVec3::splat(self.size() as i32 / 2);
+        let world_min = (min + half).max(IVec3::ZERO);
+        let world_max = (max + half).min(IVec3::splat(self.size() as i32 - 1));
+        if world_min.cmpgt(world_max).any() {
+            return Ok(voxels);
+        }
+        let (world_min, world_max) = (world_min.as_uvec3(), world_max.as_uvec3());
+
+        let offset = min + half;
+        for brick_x in world_min.x / BRICK_SIZE..=world_max.x / BRICK_SIZE {
+            for brick_y in world_min.y / BRICK_SIZE..=world_max.y / BRICK_SIZE {
+                for brick_z in world_min.z / BRICK_SIZE..=world_max.z / BRICK_SIZE {
+                    let brick_pos = UVec3::new(brick_x, brick_y, brick_z);
+                    let (index, _, depth) = self.get_node(brick_pos, None);
+                    let brick_index = self.brickmap[index].brick as usize;
+                    if depth != self.brickmap_depth || brick_index == 0 {
+                        continue;
+                    }
+
+                    let brick = self.read_brick(brick_index)?;
+                    let brick_min = brick_pos * BRICK_SIZE;
+                    let local_min = world_min.max(brick_min);
+                    let local_max = world_max.min(brick_min + BRICK_SIZE - 1);
+                    for x in local_min.x..=local_max.x {
+                        for y in local_min.y..=local_max.y {
+                            for z in local_min.z..=local_max.z {
+                                let p = UVec3::new(x, y, z);
+                                let out = (p.as_ivec3() - offset).as_uvec3();
+                                let out = out.x + out.y * size.x + out.z * size.x * size.y;
+                                voxels[out as usize] = brick.get(p - brick_min);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(voxels)
+    }
+
+    /// every leaf node with a brick, along with its position in bricks
+    pub fn leaves(&self) -> impl Iterator<Item = (usize, UVec3)> + '_ {
+        let mut stack = (0..8u32)
+            .map(|i| {
+                let offset = 1 << (self.brickmap_depth - 1);
+                (
+                    i as usize,
+                    UVec3::new(i >> 2 & 1, i >> 1 & 1, i & 1) * offset,
+                    1,
+                )
+            })
+            .collect::<Vec<(usize, UVec3, u32)>>();
+        std::iter::from_fn(move || {
+            while let Some((node_index, pos, depth)) = stack.pop() {
+                let node = self.brickmap[node_index];
+                if node.children == 0 {
+                    if node.brick != 0 {
+                        return Some((node_index, pos));
+                    }
+                    continue;
+                }
+
+                let half_size = 1 << (self.brickmap_depth - depth - 1);
+                let children_index = 8 * node.children as usize;
+                stack.extend((0..8u32).map(|i| {
+                    let child_pos = pos + UVec3::new(i >> 2 & 1, i >> 1 & 1, i & 1) * half_size;
+                    (children_index + i as usize, child_pos, depth + 1)
+                }));
+            }
+            None
+        })
+    }
+
+    /// every non transparent leaf voxel with its centred world position
+    pub fn voxels(&self) -> Result<Vec<(IVec3, [u8; 4])>, String> {
+        let half = IVec3::splat(self.size() as i32 / 2);
+        let mut voxels = Vec::new();
+        for (node_index, brick_pos) in self.leaves() {
+            let brick = self.read_brick(self.brickmap[node_index].brick as usize)?;
+            let brick_min = (brick_pos * BRICK_SIZE).as_ivec3() - half;
+            voxels.extend((0..BRICK_VOXELS as u32).filter_map(|i| {
+                let local = UVec3::new(
+                    i % BRICK_SIZE,
+                    i / BRICK_SIZE % BRICK_SIZE,
+                    i / (BRICK_SIZE * BRICK_SIZE),
+                );
+                let colour = brick.get(local);
+                (colour[3] != 0).then(|| (brick_min + local.as_ivec3(), colour))
+            }));
+        }
+        Ok(voxels)
+    }
+
+    /// checks the structure of the tree: every child group has exactly one
+    /// parent, references stay in bounds, leaves sit at `brickmap_depth` and
+    /// dedup reference counts match the nodes. with `check_mips` it also
+    /// checks every mip brick matches its children
+    pub fn validate(&self, check_mips: bool) -> Result<(), String> {
+        if self.brickmap.len() < 8 || !self.brickmap.len().is_multiple_of(8) {
+            return Err(format!("invalid node count {}", self.brickmap.len()));
+        }
+
+        let mut parents = vec![usize::MAX; self.brickmap.len() / 8];
+        let mut stack = (0..8).map(|i| (i, 1)).collect::<Vec<(usize, u32)>>();
+        let mut brick_refs = HashMap::<u32, u32>::new();
+        while let Some((node_index, depth)) = stack.pop() {
+            let node = self.brickmap[node_index];
+            if node.brick as usize >= self.bricks.len() {
+                return Err(format!(
+                    "node {} has brick {} out of bounds",
+                    node_index, node.brick
+                ));
+            }
+            if node.children == 0 {
+                if node.brick != 0 && depth != self.brickmap_depth {
+                    return Err(format!("leaf node {} at depth {}", node_index, depth));
+                }
+                if node.brick != 0 {
+                    *brick_refs.entry(node.brick).or_default() += 1;
+                }
+                continue;
+            }
+
+            let children = node.children as usize;
+            if depth >= self.brickmap_depth || 8 * children + 8 > self.brickmap.len() {
+                return Err(format!(
+                    "node {} has invalid children {}",
+                    node_index, children
+                ));
+            }
+            if parents[children] != usize::MAX {
+                return Err(format!(
+                    "children {} shared by nodes {} and {}",
+                    children, parents[children], node_index
+                ));
+            }
+            parents[children] = node_index;
+            if node.brick == 0 {
+                return Err(format!("node {} has children but no mip brick", node_index));
+            }
+            stack.extend((0..8).map(|i| (8 * children + i, depth + 1)));
+        }
+
+        if let Some(dedup) = &self.dedup {
+            for (brick_index, count) in dedup.refs.iter() {
+                let used = brick_refs.get(brick_index).copied().unwrap_or(0);
+                if used != *count {
+                    return Err(format!(
+                        "brick {} used by {} nodes but has {} references",
+                        brick_index, used, count
+                    ));
+                }
+            }
+        }
+        if let Some((brick_index, _)) = brick_refs.iter().find(|(brick_index, count)| {
+            **count > 1
+                && self
+                    .dedup
+                    .as_ref()
+                    .is_none_or(|d| !d.refs.contains_key(brick_index))
+        }) {
+            return Err(format!(
+                "brick {} shared without being tracked",
+                brick_index
+            ));
+        }
+
+        if check_mips {
+            for (children, &node_index) in parents.iter().enumerate() {
+                if node_index == usize::MAX {
+                    continue;
+                }
+                let brick = self.read_brick(self.brickmap[node_index].brick as usize)?;
+                for x in 0..BRICK_SIZE {
+                    for y in 0..BRICK_SIZE {
+                        for z in 0..BRICK_SIZE {
+                            let pos = UVec3::new(x, y, z);
+                            if (brick.get(pos), brick.material(pos))
+                                != self.mip_voxel(8 * children, pos)?
+                            {
+                                return Err(format!(
+                                    "mip of node {} out of date at {}",
+                                    node_index, pos
+                                ));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     // returns the brickmap and gpu bricks texture
-    pub fn to_gpu(&self, brick_texture_size: UVec3) -> (Vec<u32>, Vec<u8>) {
+    pub fn to_gpu(&self, brick_texture_size: UVec3) -> Result<(Vec<u32>, Vec<u8>), String> {
         let mut brickmap = vec![0; self.brickmap.len()];
         for (i, node) in self.brickmap.iter().enumerate() {
             brickmap[i] = node.children | node.brick << 16;
@@ -134,7 +976,7 @@ impl CpuBrickmap {
                 break;
             }
 
-            let brick = &self.bricks[brick_index];
+            let brick = self.read_brick(brick_index)?;
             let dim = brick_texture_size / BRICK_SIZE;
             let brick_pos = UVec3::new(
                 brick_index as u32 / (dim.x * dim.y),
@@ -157,131 +999,278 @@ impl CpuBrickmap {
             }
         }
 
-        (brickmap, bricks)
+        Ok((brickmap, bricks))
     }
 
-    pub fn recreate_mipmaps(&mut self) {
+    pub fn recreate_mipmaps(&mut self) -> Result<(), String> {
         info!("recreating mipmaps for {} bricks", self.bricks.len());
 
-        // mip-mapping
-        fn recursive_mip(brickmap: &mut CpuBrickmap, node_index: usize, depth: u32) {
-            let children_index = 8 * brickmap.brickmap[node_index].children as usize;
+        // collect the nodes with children, one list per depth
+        let mut levels = vec![Vec::new(); self.brickmap_depth as usize];
+        let mut stack = (0..8).map(|i| (i, 1)).collect::<Vec<(usize, u32)>>();
+        while let Some((node_index, depth)) = stack.pop() {
+            let children_index = 8 * self.brickmap[node_index].children as usize;
             if children_index == 0 {
-                return;
+                continue;
             }
-            if depth < brickmap.brickmap_depth - 1 {
-                for i in 0..8 {
-                    recursive_mip(brickmap, children_index + i, depth + 1);
-                }
+            levels[depth as usize].push(node_index);
+            if depth < self.brickmap_depth - 1 {
+                stack.extend((0..8).map(|i| (children_index + i, depth + 1)));
             }
+        }
 
-            // mip the brick
-            let brick_index = brickmap.brickmap[node_index].brick;
+        // mip-mapping, deepest level first so children are always up to date
+        for nodes in levels.into_iter().rev() {
+            self.mip_nodes(nodes)?;
+        }
+        Ok(())
+    }
 
-            #[cfg(debug_assertions)]
-            if brick_index as usize >= brickmap.bricks.len() {
-                error!("brick index out of bounds");
-            }
-            #[cfg(debug_assertions)]
-            if brick_index as usize == 0 {
-                error!("tried to mip empty brick");
+    /// re-averages the bricks above the leaves at `dirty_bricks`, in bricks,
+    /// walking up each leaf's path and leaving the rest of the tree alone.
+    /// gives the same result as `recreate_mipmaps` when only those leaves
+    /// changed since the last full pass
+    pub fn update_mips(&mut self, dirty_bricks: &[UVec3]) -> Result<(), String> {
+        let mut levels = vec![Vec::new(); self.brickmap_depth as usize];
+        let mut seen = HashSet::new();
+        for &pos in dirty_bricks {
+            let path = self.path_to(pos);
+            // stop at the first ancestor another path already reached
+            for (depth, &node_index) in path.iter().enumerate().rev().skip(1) {
+                if !seen.insert(node_index) {
+                    break;
+                }
+                levels[depth].push(node_index);
             }
+        }
 
-            for x in 0..BRICK_SIZE {
-                for y in 0..BRICK_SIZE {
-                    for z in 0..BRICK_SIZE {
-                        let pos = UVec3::new(x, y, z);
+        for nodes in levels.into_iter().rev() {
+            self.mip_nodes(nodes)?;
+        }
+        Ok(())
+    }
 
-                        // get the average of the 8 children
-                        let mut colour = Vec3::ZERO;
-                        let mut total_alpha = 0.0;
-                        let mask = pos.cmpge(UVec3::splat(BRICK_SIZE / 2));
-                        let child_node_index = children_index
-                            + mask.x as usize * 4
-                            + mask.y as usize * 2
-                            + mask.z as usize;
-                        let child_brick_index = brickmap.brickmap[child_node_index].brick;
-                        if child_brick_index as usize == 0 {
-                            continue;
-                        }
-                        #[cfg(debug_assertions)]
-                        if child_brick_index as usize >= brickmap.bricks.len() {
-                            error!("child brick index out of bounds");
-                        }
-                        for j in 0..8 {
-                            let child_pos_in_brick = 2 * (pos % (BRICK_SIZE / 2))
-                                + UVec3::new(j & 1, j >> 1 & 1, j >> 2 & 1);
-                            let child_colour =
-                                brickmap.bricks[child_brick_index as usize].get(child_pos_in_brick);
-
-                            let alpha = child_colour[3] as f32;
-                            let child_colour = Vec3::new(
-                                child_colour[0] as f32,
-                                child_colour[1] as f32,
-                                child_colour[2] as f32,
-                            );
-
-                            colour += child_colour * alpha;
-                            total_alpha += alpha;
+    /// rebuilds the bricks of `nodes` from their children in parallel. the
+    /// nodes must not be ancestors of each other
+    fn mip_nodes(&mut self, nodes: Vec<usize>) -> Result<(), String> {
+        let bricks = nodes
+            .into_par_iter()
+            .map(|node_index| {
+                let node = self.brickmap[node_index];
+
+                #[cfg(debug_assertions)]
+                if node.brick as usize >= self.bricks.len() {
+                    error!("brick index out of bounds");
+                }
+                #[cfg(debug_assertions)]
+                if node.brick as usize == 0 {
+                    error!("tried to mip empty brick");
+                }
+
+                // mip the brick
+                let children_index = 8 * node.children as usize;
+                let mut brick = Brick::empty();
+                for x in 0..BRICK_SIZE {
+                    for y in 0..BRICK_SIZE {
+                        for z in 0..BRICK_SIZE {
+                            let pos = UVec3::new(x, y, z);
+                            let (colour, material) = self.mip_voxel(children_index, pos)?;
+                            brick.write_material(pos, colour, material);
                         }
-                        colour /= total_alpha;
-                        total_alpha /= 8.0;
-
-                        // write the average to the brick
-                        let new_colour = [
-                            colour.x as u8,
-                            colour.y as u8,
-                            colour.z as u8,
-                            total_alpha as u8,
-                        ];
-                        brickmap.bricks[brick_index as usize].write(pos, new_colour);
                     }
                 }
-            }
+                brick.compact();
+                Ok((node.brick as usize, brick))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        for (brick_index, brick) in bricks {
+            self.bricks.set(brick_index, brick);
         }
+        Ok(())
+    }
 
-        for i in 0..8 {
-            recursive_mip(self, i, 1);
+    /// averages the 8 child voxels below `pos` in the parent brick whose
+    /// children start at `children_index`. the mip takes the material most
+    /// of the non empty children have
+    fn mip_voxel(&self, children_index: usize, pos: UVec3) -> Result<([u8; 4], u8), String> {
+        let mask = pos.cmpge(UVec3::splat(BRICK_SIZE / 2));
+        let child_node_index =
+            children_index + mask.x as usize * 4 + mask.y as usize * 2 + mask.z as usize;
+        let child_brick_index = self.brickmap[child_node_index].brick;
+        if child_brick_index as usize == 0 {
+            return Ok(([0; 4], DEFAULT_MATERIAL));
+        }
+        #[cfg(debug_assertions)]
+        if child_brick_index as usize >= self.bricks.len() {
+            error!("child brick index out of bounds");
         }
+
+        let child_brick = self.read_brick(child_brick_index as usize)?;
+        let mut children = [[0; 4]; 8];
+        let mut materials = [DEFAULT_MATERIAL; 8];
+        for (j, (child_colour, child_material)) in
+            children.iter_mut().zip(materials.iter_mut()).enumerate()
+        {
+            let j = j as u32;
+            let child_pos_in_brick =
+                2 * (pos % (BRICK_SIZE / 2)) + UVec3::new(j & 1, j >> 1 & 1, j >> 2 & 1);
+            *child_colour = child_brick.get(child_pos_in_brick);
+            *child_material = child_brick.material(child_pos_in_brick);
+        }
+
+        let colour = self.mip_filter.apply(&children);
+        if colour[3] == 0 {
+            return Ok((colour, DEFAULT_MATERIAL));
+        }
+        // the most common material of the visible children, ties go to the
+        // first one seen
+        let mut seen = [DEFAULT_MATERIAL; 8];
+        let mut counts = [0u8; 8];
+        let mut seen_count = 0;
+        for (material, _) in materials
+            .iter()
+            .zip(children.iter())
+            .filter(|(_, colour)| colour[3] != 0)
+        {
+            match seen[..seen_count].iter().position(|m| m == material) {
+                Some(i) => counts[i] += 1,
+                None => {
+                    seen[seen_count] = *material;
+                    counts[seen_count] = 1;
+                    seen_count += 1;
+                }
+            }
+        }
+        let mut material = DEFAULT_MATERIAL;
+        let mut best_count = 0;
+        for (m, count) in seen.into_iter().zip(counts).take(seen_count) {
+            if count > best_count {
+                material = m;
+                best_count = count;
+            }
+        }
+        Ok((colour, material))
     }
 }
 
 #[allow(dead_code)]
 impl Brick {
+    /// memory used by a brick stored without compression
+    pub const RAW_SIZE: usize = std::mem::size_of::<Self>() + BRICK_VOXELS * 4;
+
     pub fn empty() -> Self {
         Self {
-            data: [[0; 4]; (BRICK_SIZE * BRICK_SIZE * BRICK_SIZE) as usize],
+            colours: BrickColours::Empty,
+            materials: None,
         }
     }
 
-    pub fn get(&self, pos: UVec3) -> [u8; 4] {
+    /// builds a brick from the raw rgba bytes and material bytes written by
+    /// `to_gpu`
+    pub fn from_bytes(colours: &[u8], materials: &[u8]) -> Self {
+        let mut data = Box::new([[0; 4]; BRICK_VOXELS]);
+        for (voxel, colour) in data.iter_mut().zip(colours.chunks_exact(4)) {
+            voxel.copy_from_slice(colour);
+        }
+        let mut material_data = Box::new([DEFAULT_MATERIAL; BRICK_VOXELS]);
+        material_data.copy_from_slice(&materials[..BRICK_VOXELS]);
+        let mut brick = Self {
+            colours: BrickColours::Raw(data),
+            materials: Some(material_data),
+        };
+        brick.compact();
+        brick
+    }
+
+    fn index(pos: UVec3) -> usize {
         #[cfg(debug_assertions)]
         if pos.cmplt(UVec3::ZERO).any() || pos.cmpge(UVec3::splat(BRICK_SIZE)).any() {
             error!("pos out of bounds");
         }
 
-        let index = (pos.z * BRICK_SIZE * BRICK_SIZE + pos.y * BRICK_SIZE + pos.x) as usize;
-        self.data[index]
+        (pos.z * BRICK_SIZE * BRICK_SIZE + pos.y * BRICK_SIZE + pos.x) as usize
+    }
+
+    pub fn get(&self, pos: UVec3) -> [u8; 4] {
+        self.get_index(Self::index(pos))
+    }
+
+    pub fn get_index(&self, index: usize) -> [u8; 4] {
+        self.colours.get_index(index)
+    }
+
+    pub fn material(&self, pos: UVec3) -> u8 {
+        self.material_index(Self::index(pos))
+    }
+
+    pub fn material_index(&self, index: usize) -> u8 {
+        self.materials
+            .as_ref()
+            .map_or(DEFAULT_MATERIAL, |materials| materials[index])
     }
 
+    /// writes a voxel of the default material
     pub fn write(&mut self, pos: UVec3, colour: [u8; 4]) {
-        #[cfg(debug_assertions)]
-        if pos.cmplt(UVec3::ZERO).any() || pos.cmpge(UVec3::splat(BRICK_SIZE)).any() {
-            error!("pos out of bounds");
-        }
+        self.write_material(pos, colour, DEFAULT_MATERIAL);
+    }
+
+    /// writes a voxel with the material `material`. empty voxels always get
+    /// the default
+    pub fn write_material(&mut self, pos: UVec3, colour: [u8; 4], material: u8) {
+        let index = Self::index(pos);
+        self.colours.write(index, colour);
 
-        let index = (pos.z * BRICK_SIZE * BRICK_SIZE + pos.y * BRICK_SIZE + pos.x) as usize;
-        self.data[index] = colour;
+        let material = if colour[3] == 0 {
+            DEFAULT_MATERIAL
+        } else {
+            material
+        };
+        match &mut self.materials {
+            Some(materials) => materials[index] = material,
+            None if material != DEFAULT_MATERIAL => {
+                let mut materials = Box::new([DEFAULT_MATERIAL; BRICK_VOXELS]);
+                materials[index] = material;
+                self.materials = Some(materials);
+            }
+            None => {}
+        }
     }
 
-    pub unsafe fn to_gpu(&self) -> &[u8] {
-        let (_head, data, _tail) = unsafe { self.data.align_to::<u8>() };
-        #[cfg(debug_assertions)]
+    /// switches to the smallest representation that holds the current voxels
+    pub fn compact(&mut self) {
+        self.colours.compact();
+        if self
+            .materials
+            .as_ref()
+            .is_some_and(|materials| materials.iter().all(|m| *m == DEFAULT_MATERIAL))
         {
-            assert!(_head.is_empty());
-            assert!(_tail.is_empty());
+            self.materials = None;
         }
-        data
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.colours.is_empty()
+    }
+
+    /// bytes of memory used by this brick, including its heap allocations
+    pub fn memory_size(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.colours.heap_size()
+            + self.materials.as_ref().map_or(0, |_| BRICK_VOXELS)
+    }
+
+    /// the voxels in the layout of the colour and material textures
+    pub fn to_gpu(&self) -> GpuBrick<'_> {
+        let colours = match &self.colours {
+            BrickColours::Raw(data) => Cow::Borrowed(bytemuck::cast_slice(data.as_slice())),
+            colours => Cow::Owned(bytemuck::cast_slice(colours.to_raw().as_slice()).to_vec()),
+        };
+        let materials = match &self.materials {
+            Some(materials) => Cow::Borrowed(materials.as_slice()),
+            None => Cow::Borrowed(DEFAULT_MATERIALS.as_slice()),
+        };
+        GpuBrick { colours, materials }
     }
 
     pub fn brick_ints() -> usize {
@@ -306,11 +1295,13 @@ impl Brick {
 
     pub fn get_bitmask(&self) -> Vec<u8> {
         let mut bitmask = vec![0; 4 * Self::brick_ints()];
+        if self.is_empty() {
+            return bitmask;
+        }
         for x in 0..BRICK_SIZE {
             for y in 0..BRICK_SIZE {
                 for z in 0..BRICK_SIZE {
-                    let index = (z * BRICK_SIZE * BRICK_SIZE + y * BRICK_SIZE + x) as usize;
-                    let colour = self.data[index];
+                    let colour = self.get(UVec3::new(x, y, z));
                     if colour[3] != 0 {
                         for (size, offset) in Self::size_offset() {
                             let pos = UVec3::new(x, y, z) * size / BRICK_SIZE;
@@ -325,3 +1316,389 @@ impl Brick {
         bitmask
     }
 }
+
+impl BrickColours {
+    fn get_index(&self, index: usize) -> [u8; 4] {
+        match self {
+            Self::Empty => [0; 4],
+            Self::Uniform(colour) => *colour,
+            Self::Palette { palette, indices } => {
+                palette[Self::palette_index(palette.len(), indices, index)]
+            }
+            Self::Raw(data) => data[index],
+        }
+    }
+
+    fn palette_index(palette_len: usize, indices: &[u8], index: usize) -> usize {
+        if palette_len <= 16 {
+            (indices[index / 2] >> (4 * (index % 2)) & 0xf) as usize
+        } else {
+            indices[index] as usize
+        }
+    }
+
+    fn set_palette_index(palette_len: usize, indices: &mut [u8], index: usize, value: usize) {
+        if palette_len <= 16 {
+            let shift = 4 * (index % 2);
+            indices[index / 2] = indices[index / 2] & !(0xf << shift) | (value as u8) << shift;
+        } else {
+            indices[index] = value as u8;
+        }
+    }
+
+    fn write(&mut self, index: usize, colour: [u8; 4]) {
+        match self {
+            Self::Empty | Self::Uniform(_) => {
+                let current = self.get_index(0);
+                if current == colour {
+                    return;
+                }
+                *self = Self::Palette {
+                    palette: vec![current],
+                    indices: vec![0; BRICK_VOXELS / 2],
+                };
+                self.write(index, colour);
+            }
+            Self::Palette { palette, indices } => {
+                if let Some(value) = palette.iter().position(|c| *c == colour) {
+                    Self::set_palette_index(palette.len(), indices, index, value);
+                } else if palette.len() == 256 {
+                    *self = Self::Raw(self.to_raw());
+                    self.write(index, colour);
+                } else {
+                    if palette.len() == 16 {
+                        // widen to one index per byte
+                        *indices = (0..BRICK_VOXELS)
+                            .map(|i| Self::palette_index(16, indices, i) as u8)
+                            .collect();
+                    }
+                    palette.push(colour);
+                    Self::set_palette_index(palette.len(), indices, index, palette.len() - 1);
+                }
+            }
+            Self::Raw(data) => data[index] = colour,
+        }
+    }
+
+    fn to_raw(&self) -> Box<[[u8; 4]; BRICK_VOXELS]> {
+        match self {
+            Self::Raw(data) => data.clone(),
+            _ => {
+                let mut data = Box::new([[0; 4]; BRICK_VOXELS]);
+                for (index, voxel) in data.iter_mut().enumerate() {
+                    *voxel = self.get_index(index);
+                }
+                data
+            }
+        }
+    }
+
+    fn compact(&mut self) {
+        let mut palette: Vec<[u8; 4]> = Vec::new();
+        let mut lookup = HashMap::new();
+        let mut values = vec![0u8; BRICK_VOXELS];
+        for (index, value) in values.iter_mut().enumerate() {
+            let colour = self.get_index(index);
+            let next = palette.len();
+            let entry = *lookup.entry(colour).or_insert(next);
+            if entry == next {
+                if next == 256 {
+                    if !matches!(self, Self::Raw(_)) {
+                        *self = Self::Raw(self.to_raw());
+                    }
+                    return;
+                }
+                palette.push(colour);
+            }
+            *value = entry as u8;
+        }
+
+        *self = match palette.len() {
+            1 if palette[0] == [0; 4] => Self::Empty,
+            1 => Self::Uniform(palette[0]),
+            len => {
+                let mut indices = vec![
+                    0;
+                    if len <= 16 {
+                        BRICK_VOXELS / 2
+                    } else {
+                        BRICK_VOXELS
+                    }
+                ];
+                for (index, value) in values.into_iter().enumerate() {
+                    Self::set_palette_index(len, &mut indices, index, value as usize);
+                }
+                Self::Palette { palette, indices }
+            }
+        };
+    }
+
+    fn is_empty(&self) -> bool {
+        match self {
+            Self::Empty => true,
+            Self::Uniform(colour) => colour[3] == 0,
+            _ => (0..BRICK_VOXELS).all(|index| self.get_index(index)[3] == 0),
+        }
+    }
+
+    /// bytes allocated on the heap for the colours
+    fn heap_size(&self) -> usize {
+        match self {
+            Self::Empty | Self::Uniform(_) => 0,
+            Self::Palette { palette, indices } => palette.len() * 4 + indices.len(),
+            Self::Raw(_) => BRICK_VOXELS * 4,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::{collection::vec, prelude::*};
+
+    const DEPTH: u32 = 3;
+    const HALF: i32 = (BRICK_SIZE << DEPTH) as i32 / 2;
+
+    #[derive(Clone, Debug)]
+    enum Op {
+        PlaceBrick(UVec3, Vec<(UVec3, [u8; 4])>),
+        SetVoxels(Vec<(IVec3, [u8; 4])>),
+        Clear(IVec3, IVec3),
+    }
+
+    fn colour() -> impl Strategy<Value = [u8; 4]> {
+        prop_oneof![
+            1 => Just([0; 4]),
+            3 => (any::<[u8; 3]>(), 1..=255u8).prop_map(|(rgb, a)| [rgb[0], rgb[1], rgb[2], a]),
+        ]
+    }
+
+    /// voxels are kept near the middle of the world so edits overlap
+    fn voxel() -> impl Strategy<Value = IVec3> {
+        (-40..40, -40..40, -40..40).prop_map(|(x, y, z)| IVec3::new(x, y, z))
+    }
+
+    /// a brick position and the voxels of the brick placed there
+    fn placed_brick() -> impl Strategy<Value = (UVec3, Vec<(UVec3, [u8; 4])>)> {
+        let local =
+            (0..BRICK_SIZE, 0..BRICK_SIZE, 0..BRICK_SIZE).prop_map(|(x, y, z)| UVec3::new(x, y, z));
+        (
+            (0..8u32, 0..8u32, 0..8u32).prop_map(|(x, y, z)| UVec3::new(x, y, z)),
+            vec((local, colour()), 0..64),
+        )
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            placed_brick().prop_map(|(pos, voxels)| Op::PlaceBrick(pos, voxels)),
+            vec((voxel(), colour()), 1..64).prop_map(Op::SetVoxels),
+            (voxel(), (0..12, 0..12, 0..12))
+                .prop_map(|(min, (x, y, z))| Op::Clear(min, min + IVec3::new(x, y, z))),
+        ]
+    }
+
+    fn brick(voxels: &[(UVec3, [u8; 4])]) -> Brick {
+        let mut brick = Brick::empty();
+        for (local, colour) in voxels {
+            brick.write(*local, *colour);
+        }
+        brick
+    }
+
+    /// applies `op` to the brickmap and to a map of the visible voxels it
+    /// should hold
+    fn apply(brickmap: &mut CpuBrickmap, model: &mut HashMap<IVec3, [u8; 4]>, op: Op) {
+        match op {
+            Op::PlaceBrick(pos, voxels) => {
+                let min = (pos * BRICK_SIZE).as_ivec3() - HALF;
+                model.retain(|voxel, _| {
+                    voxel.cmplt(min).any() || voxel.cmpge(min + BRICK_SIZE as i32).any()
+                });
+                for (local, colour) in &voxels {
+                    model.insert(min + local.as_ivec3(), *colour);
+                }
+                brickmap.place_brick(brick(&voxels), pos).unwrap();
+                brickmap.recreate_mipmaps().unwrap();
+            }
+            Op::SetVoxels(voxels) => {
+                model.extend(voxels.iter().copied());
+                brickmap.set_voxels(voxels).unwrap();
+            }
+            Op::Clear(min, max) => {
+                model.retain(|voxel, _| voxel.cmplt(min).any() || voxel.cmpgt(max).any());
+                brickmap.fill_box(min, max, [0; 4]).unwrap();
+            }
+        }
+        model.retain(|_, colour| colour[3] != 0);
+    }
+
+    fn check(brickmap: &CpuBrickmap, model: &HashMap<IVec3, [u8; 4]>) -> Result<(), TestCaseError> {
+        if let Err(e) = brickmap.validate(true) {
+            return Err(TestCaseError::fail(e));
+        }
+
+        // the leaves hold exactly the model's voxels
+        let voxels = brickmap.voxels().unwrap().into_iter().collect::<HashMap<_, _>>();
+        prop_assert_eq!(&voxels, model);
+        for (pos, colour) in model {
+            prop_assert_eq!(brickmap.get_voxel(*pos).unwrap(), *colour);
+        }
+
+        // every leaf is found by get_node, and its ancestors have mips. leaves
+        // cleared to fully transparent don't keep a brick
+        for (node_index, pos) in brickmap.leaves() {
+            prop_assert_eq!(brickmap.get_node(pos, None), (node_index, pos, DEPTH));
+            let brick = brickmap.brickmap[node_index].brick as usize;
+            prop_assert!(!brickmap.read_brick(brick).unwrap().is_empty());
+            for depth in 1..DEPTH {
+                let (index, node_pos, node_depth) = brickmap.get_node(pos, Some(depth));
+                prop_assert_eq!(node_depth, depth);
+                prop_assert_eq!(node_pos, pos >> (DEPTH - depth) << (DEPTH - depth));
+                prop_assert!(brickmap.brickmap[index].children != 0);
+                prop_assert!(brickmap.brickmap[index].brick != 0);
+            }
+        }
+
+        // mips of cells without a visible voxel below them are fully clear
+        for depth in 1..DEPTH {
+            let shift = DEPTH - depth;
+            let occupied = model
+                .keys()
+                .map(|pos| (*pos + HALF) >> shift as i32)
+                .collect::<HashSet<_>>();
+            let cells = (BRICK_SIZE << depth) as i32;
+            for x in 0..cells {
+                for y in 0..cells {
+                    for z in 0..cells {
+                        let cell = IVec3::new(x, y, z);
+                        if !occupied.contains(&cell) {
+                            let pos = (cell << shift as i32) - HALF;
+                            prop_assert_eq!(brickmap.get_voxel_at_lod(pos, depth).unwrap(), [0; 4]);
+                        }
+                    }
+                }
+            }
+        }
+
+        // to_gpu packs the nodes and lays bricks out along x, then y, then z
+        let dim = (1..)
+            .find(|d| d * d * d >= brickmap.bricks.len() as u32)
+            .unwrap();
+        let texture_size = UVec3::splat(dim * BRICK_SIZE);
+        let (nodes, texture) = brickmap.to_gpu(texture_size).unwrap();
+        prop_assert_eq!(nodes.len(), brickmap.brickmap.len());
+        for (gpu_node, node) in nodes.iter().zip(brickmap.brickmap.iter()) {
+            prop_assert_eq!(*gpu_node, node.children | node.brick << 16);
+        }
+        for brick_index in 0..brickmap.bricks.len() as u32 {
+            let brick = brickmap.bricks.read(brick_index as usize).unwrap();
+            let brick_min = UVec3::new(
+                brick_index / (dim * dim),
+                brick_index / dim % dim,
+                brick_index % dim,
+            ) * BRICK_SIZE;
+            for i in 0..BRICK_VOXELS as u32 {
+                let local = UVec3::new(
+                    i % BRICK_SIZE,
+                    i / BRICK_SIZE % BRICK_SIZE,
+                    i / (BRICK_SIZE * BRICK_SIZE),
+                );
+                let p = brick_min + local;
+                let index = 4
+                    * (p.z * texture_size.x * texture_size.y + p.y * texture_size.x + p.x) as usize;
+                prop_assert_eq!(&texture[index..index + 4], &brick.get(local)[..]);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn clearing_a_brick_frees_it() {
+        let mut brickmap = CpuBrickmap::new(DEPTH);
+        brickmap.enable_dedup();
+        let voxels = [
+            (UVec3::ZERO, [40, 50, 60, 255]),
+            (UVec3::ONE, [1, 2, 3, 255]),
+        ];
+        for pos in [UVec3::splat(4), UVec3::new(5, 4, 4)] {
+            brickmap.place_brick(brick(&voxels), pos).unwrap();
+        }
+        brickmap.recreate_mipmaps().unwrap();
+        let leaf = |pos: UVec3| brickmap.get_node(pos, None).0;
+        let (a, b) = (leaf(UVec3::splat(4)), leaf(UVec3::new(5, 4, 4)));
+        let shared = brickmap.brickmap[a].brick;
+        assert_eq!(brickmap.brickmap[b].brick, shared);
+
+        // clearing one copy of a shared brick leaves the other alone
+        brickmap
+            .fill_box(IVec3::ZERO, IVec3::splat(15), [0; 4])
+            .unwrap();
+        assert_eq!(brickmap.brickmap[a].brick, 0);
+        assert_eq!(brickmap.brickmap[b].brick, shared);
+        brickmap.validate(true).unwrap();
+
+        // clearing voxel by voxel frees it once the last one goes
+        let base = IVec3::new(16, 0, 0);
+        brickmap.set_voxels([(base, [0; 4])]).unwrap();
+        assert_ne!(brickmap.brickmap[b].brick, 0);
+        let unshared = brickmap.brickmap[b].brick as usize;
+        brickmap.set_voxels([(base + 1, [0; 4])]).unwrap();
+        assert_eq!(brickmap.brickmap[b].brick, 0);
+        assert!(brickmap.read_brick(unshared).unwrap().is_empty());
+        brickmap.validate(true).unwrap();
+        assert_eq!(brickmap.leaves().count(), 0);
+        assert_eq!(brickmap.get_voxel_at_lod(IVec3::ZERO, 1).unwrap(), [0; 4]);
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(32))]
+
+        #[test]
+        fn random_edits_keep_the_brickmap_consistent(
+            ops in vec(op(), 1..12),
+            dedup in any::<bool>(),
+        ) {
+            let mut brickmap = CpuBrickmap::new(DEPTH);
+            if dedup {
+                brickmap.enable_dedup();
+            }
+            let mut model = HashMap::new();
+            for op in ops {
+                apply(&mut brickmap, &mut model, op);
+            }
+            check(&brickmap, &model)?;
+        }
+
+        #[test]
+        fn update_mips_matches_recreate_mipmaps(
+            world in vec(placed_brick(), 1..24),
+            edits in vec(placed_brick(), 1..8),
+        ) {
+            let mut incremental = CpuBrickmap::new(DEPTH);
+            let mut full = CpuBrickmap::new(DEPTH);
+            for brickmap in [&mut incremental, &mut full] {
+                for (pos, voxels) in &world {
+                    brickmap.place_brick(brick(voxels), *pos).unwrap();
+                }
+                brickmap.recreate_mipmaps().unwrap();
+                for (pos, voxels) in &edits {
+                    brickmap.place_brick(brick(voxels), *pos).unwrap();
+                }
+            }
+            let dirty = edits.iter().map(|(pos, _)| *pos).collect::<Vec<_>>();
+            incremental.update_mips(&dirty).unwrap();
+            full.recreate_mipmaps().unwrap();
+
+            prop_assert_eq!(&incremental.brickmap, &full.brickmap);
+            prop_assert_eq!(incremental.bricks.len(), full.bricks.len());
+            for brick_index in 0..full.bricks.len() {
+                prop_assert_eq!(
+                    incremental.bricks.read(brick_index).unwrap(),
+                    full.bricks.read(brick_index).unwrap(),
+                    "brick {}",
+                    brick_index
+                );
+            }
+        }
+    }
+}
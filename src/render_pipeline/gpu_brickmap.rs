@@ -1,17 +1,87 @@
 use anyhow::Result;
 use bevy::{prelude::*, render::renderer::RenderQueue};
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
 
 use super::{
-    cpu_brickmap::Brick,
-    voxel_world::{CpuVoxelWorld, VoxelData},
+    cpu_brickmap::{Brick, CpuBrickmap},
+    voxel_world::VoxelData,
     BRICK_OFFSET, BRICK_SIZE,
 };
 
+/// Somewhere bricks are copied to once they have a slot on the gpu.
+pub trait BrickUpload {
+    fn write_brick(&self, brick_index: usize, brick: &Brick, color_texture_size: UVec3);
+}
+
+/// Writes bricks into the voxel buffers and textures through the render queue.
+pub struct RenderQueueUpload<'a> {
+    pub voxel_data: &'a VoxelData,
+    pub render_queue: &'a RenderQueue,
+}
+
+impl BrickUpload for RenderQueueUpload<'_> {
+    fn write_brick(&self, brick_index: usize, brick: &Brick, color_texture_size: UVec3) {
+        self.render_queue.write_buffer(
+            &self.voxel_data.bricks,
+            (brick_index * 4 * Brick::brick_ints()) as u64,
+            &brick.get_bitmask(),
+        );
+
+        let dim = color_texture_size / BRICK_SIZE;
+        let brick_pos = UVec3::new(
+            brick_index as u32 / (dim.x * dim.y),
+            brick_index as u32 / dim.x % dim.y,
+            brick_index as u32 % dim.x,
+        ) * BRICK_SIZE;
+        let gpu_brick = brick.to_gpu();
+        let origin = wgpu::Origin3d {
+            x: brick_pos.x,
+            y: brick_pos.y,
+            z: brick_pos.z,
+        };
+        let size = wgpu::Extent3d {
+            width: BRICK_SIZE,
+            height: BRICK_SIZE,
+            depth_or_array_layers: BRICK_SIZE,
+        };
+        self.render_queue.write_texture(
+            wgpu::ImageCopyTextureBase {
+                texture: &self.voxel_data.color,
+                origin,
+                mip_level: 0,
+                aspect: wgpu::TextureAspect::All,
+            },
+            &gpu_brick.colours,
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(BRICK_SIZE * 4),
+                rows_per_image: Some(BRICK_SIZE),
+            },
+            size,
+        );
+        self.render_queue.write_texture(
+            wgpu::ImageCopyTextureBase {
+                texture: &self.voxel_data.material,
+                origin,
+                mip_level: 0,
+                aspect: wgpu::TextureAspect::All,
+            },
+            &gpu_brick.materials,
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(BRICK_SIZE),
+                rows_per_image: Some(BRICK_SIZE),
+            },
+            size,
+        );
+    }
+}
+
 #[derive(Resource)]
 pub struct GpuVoxelWorld {
     pub brickmap: Vec<u32>,
     pub gpu_to_cpu: Vec<u32>,
+    pub cpu_to_gpu: HashMap<usize, usize>,
     pub brickmap_holes: VecDeque<usize>,
     pub brick_holes: VecDeque<usize>,
     pub color_texture_size: UVec3,
@@ -20,6 +90,26 @@ pub struct GpuVoxelWorld {
 
 #[allow(dead_code)]
 impl GpuVoxelWorld {
+    /// an empty gpu brickmap, the top level nodes map straight to the cpu top
+    /// level whether or not they hold a brick yet
+    pub fn new(brickmap_depth: u32, color_texture_size: UVec3, brickmap_max_nodes: usize) -> Self {
+        let dim = color_texture_size / BRICK_SIZE;
+        let brick_count = (dim.x * dim.y * dim.z) as usize;
+        let mut gpu_to_cpu = vec![0; 8 * brickmap_max_nodes];
+        for (i, cpu_index) in gpu_to_cpu.iter_mut().take(8).enumerate() {
+            *cpu_index = i as u32;
+        }
+        Self {
+            brickmap: vec![BRICK_OFFSET; 8 * brickmap_max_nodes],
+            gpu_to_cpu,
+            cpu_to_gpu: (0..8).map(|i| (i, i)).collect::<HashMap<usize, usize>>(),
+            brickmap_holes: (1..brickmap_max_nodes).collect::<VecDeque<usize>>(),
+            brick_holes: (1..brick_count).collect::<VecDeque<usize>>(),
+            color_texture_size,
+            brickmap_depth,
+        }
+    }
+
     /// recurse the brickmap and call f on each *node* (not just leaf nodes)
     pub fn recursive_search(&self, f: &mut dyn FnMut(usize, UVec3, u32)) {
         for i in 0..8 {
@@ -49,61 +139,64 @@ impl GpuVoxelWorld {
     }
 
     // allocate a brick and copy it to the gpu
-    pub fn allocate_brick(
-        &mut self,
-        brick: &Brick,
-        voxel_data: &VoxelData,
-        render_queue: &RenderQueue,
-    ) -> Result<usize> {
+    pub fn allocate_brick(&mut self, brick: &Brick, upload: &impl BrickUpload) -> Result<usize> {
         let Some(brick_index) = self.brick_holes.pop_front() else {
             return Err(anyhow::anyhow!("ran out of space in brickmap"));
         };
 
-        render_queue.write_buffer(
-            &voxel_data.bricks,
-            (brick_index * 4 * Brick::brick_ints()) as u64,
-            &brick.get_bitmask(),
-        );
-
-        let dim = self.color_texture_size / BRICK_SIZE;
-        let brick_pos = UVec3::new(
-            brick_index as u32 / (dim.x * dim.y),
-            brick_index as u32 / dim.x % dim.y,
-            brick_index as u32 % dim.x,
-        ) * BRICK_SIZE;
-        render_queue.write_texture(
-            wgpu::ImageCopyTextureBase {
-                texture: &voxel_data.color,
-                origin: wgpu::Origin3d {
-                    x: brick_pos.x,
-                    y: brick_pos.y,
-                    z: brick_pos.z,
-                },
-                mip_level: 0,
-                aspect: wgpu::TextureAspect::All,
-            },
-            unsafe { brick.to_gpu() },
-            wgpu::ImageDataLayout {
-                offset: 0,
-                bytes_per_row: Some(BRICK_SIZE * 4),
-                rows_per_image: Some(BRICK_SIZE),
-            },
-            wgpu::Extent3d {
-                width: BRICK_SIZE,
-                height: BRICK_SIZE,
-                depth_or_array_layers: BRICK_SIZE,
-            },
-        );
+        self.write_brick(brick_index, brick, upload);
 
         Ok(brick_index)
     }
 
+    // copy a brick to an already allocated slot on the gpu
+    pub fn write_brick(&self, brick_index: usize, brick: &Brick, upload: &impl BrickUpload) {
+        upload.write_brick(brick_index, brick, self.color_texture_size);
+    }
+
+    /// re-upload the bricks of resident gpu nodes mapped to the given cpu nodes
+    pub fn update_nodes(
+        &mut self,
+        cpu_nodes: impl IntoIterator<Item = usize>,
+        cpu_brickmap: &CpuBrickmap,
+        upload: &impl BrickUpload,
+    ) -> Result<()> {
+        for cpu_node_index in cpu_nodes {
+            let Some(&index) = self.cpu_to_gpu.get(&cpu_node_index) else {
+                continue;
+            };
+            let node = self.brickmap[index];
+            if node < BRICK_OFFSET {
+                // divided on the gpu, the children hold the visible bricks
+                continue;
+            }
+
+            let cpu_brick_index = cpu_brickmap.brickmap[cpu_node_index].brick as usize;
+            if cpu_brick_index == 0 {
+                // cleared on the cpu, free the brick it had
+                if node > BRICK_OFFSET {
+                    self.brick_holes.push_back((node - BRICK_OFFSET) as usize);
+                    self.brickmap[index] = BRICK_OFFSET;
+                }
+                continue;
+            }
+            let brick = cpu_brickmap.bricks.read(cpu_brick_index)?;
+            if node == BRICK_OFFSET {
+                let brick_index = self.allocate_brick(&brick, upload)?;
+                self.brickmap[index] = BRICK_OFFSET + brick_index as u32;
+            } else {
+                self.write_brick((node - BRICK_OFFSET) as usize, &brick, upload);
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn divide_node(
         &mut self,
         index: usize,
-        voxel_data: &VoxelData,
-        cpu_voxel_world: &CpuVoxelWorld,
-        render_queue: &RenderQueue,
+        cpu_brickmap: &CpuBrickmap,
+        upload: &impl BrickUpload,
     ) -> Result<()> {
         let node = self.brickmap[index];
         if node < BRICK_OFFSET {
@@ -114,7 +207,7 @@ impl GpuVoxelWorld {
         }
 
         let cpu_node_index = self.gpu_to_cpu[index] as usize;
-        let cpu_node = cpu_voxel_world.brickmap[cpu_node_index];
+        let cpu_node = cpu_brickmap.brickmap[cpu_node_index];
         if cpu_node.children == 0 {
             return Err(anyhow::anyhow!(
                 "tried to divide node with no children on cpu"
@@ -132,16 +225,14 @@ impl GpuVoxelWorld {
             self.brickmap[hole * 8 + i] = BRICK_OFFSET;
 
             let cpu_child_node_index = cpu_node.children as usize * 8 + i;
-            let cpu_child_node = cpu_voxel_world.brickmap[cpu_child_node_index];
+            let cpu_child_node = cpu_brickmap.brickmap[cpu_child_node_index];
             if cpu_child_node.brick != 0 {
-                let brick_index = self.allocate_brick(
-                    &cpu_voxel_world.bricks[cpu_child_node.brick as usize],
-                    voxel_data,
-                    render_queue,
-                )?;
+                let brick = cpu_brickmap.bricks.read(cpu_child_node.brick as usize)?;
+                let brick_index = self.allocate_brick(&brick, upload)?;
                 self.brickmap[hole * 8 + i] = BRICK_OFFSET + brick_index as u32;
             }
             self.gpu_to_cpu[hole * 8 + i] = cpu_child_node_index as u32;
+            self.cpu_to_gpu.insert(cpu_child_node_index, hole * 8 + i);
         }
 
         // update node and free old brick
@@ -154,9 +245,8 @@ impl GpuVoxelWorld {
     pub fn cull_node(
         &mut self,
         index: usize,
-        voxel_data: &VoxelData,
-        cpu_voxel_world: &CpuVoxelWorld,
-        render_queue: &RenderQueue,
+        cpu_brickmap: &CpuBrickmap,
+        upload: &impl BrickUpload,
     ) -> Result<()> {
         let node = self.brickmap[index];
         if node >= BRICK_OFFSET {
@@ -171,16 +261,15 @@ impl GpuVoxelWorld {
                 self.brick_holes
                     .push_back((child_node - BRICK_OFFSET) as usize);
             }
+            self.cpu_to_gpu
+                .remove(&(self.gpu_to_cpu[children_index + i] as usize));
         }
 
         // allocate a new brick
         let cpu_node_index = self.gpu_to_cpu[index] as usize;
-        let cpu_node = cpu_voxel_world.brickmap[cpu_node_index];
-        let brick_index = self.allocate_brick(
-            &cpu_voxel_world.bricks[cpu_node.brick as usize],
-            voxel_data,
-            render_queue,
-        )?;
+        let cpu_node = cpu_brickmap.brickmap[cpu_node_index];
+        let brick = cpu_brickmap.bricks.read(cpu_node.brick as usize)?;
+        let brick_index = self.allocate_brick(&brick, upload)?;
 
         // update node and free child nodes
         self.brickmap[index] = BRICK_OFFSET + brick_index as u32;
@@ -189,3 +278,165 @@ impl GpuVoxelWorld {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render_pipeline::voxel_world::CpuVoxelWorld;
+    use std::cell::RefCell;
+
+    /// keeps the last brick written to each gpu slot
+    #[derive(Default)]
+    struct RecordingUpload(RefCell<HashMap<usize, Brick>>);
+
+    impl BrickUpload for RecordingUpload {
+        fn write_brick(&self, brick_index: usize, brick: &Brick, _: UVec3) {
+            self.0.borrow_mut().insert(brick_index, brick.clone());
+        }
+    }
+
+    #[test]
+    fn editing_an_empty_octant_streams_its_own_children() {
+        let colour = [200, 100, 50, 255];
+        let mut cpu_brickmap = CpuBrickmap::new(2);
+        cpu_brickmap.set_voxel(IVec3::splat(-20), colour).unwrap();
+        assert_eq!(cpu_brickmap.brickmap[7].brick, 0);
+
+        let upload = RecordingUpload::default();
+        let mut gpu_voxel_world = GpuVoxelWorld::new(2, UVec3::splat(64), 16);
+        gpu_voxel_world
+            .update_nodes(0..8, &cpu_brickmap, &upload)
+            .unwrap();
+        assert!(gpu_voxel_world.brickmap[0] > BRICK_OFFSET);
+        assert_eq!(gpu_voxel_world.brickmap[7], BRICK_OFFSET);
+
+        // octant 7 is empty until now
+        let mut cpu_voxel_world = CpuVoxelWorld::new(cpu_brickmap);
+        cpu_voxel_world.take_dirty_nodes();
+        cpu_voxel_world.set_voxel(IVec3::splat(20), colour).unwrap();
+        let dirty_nodes = cpu_voxel_world.take_dirty_nodes();
+        gpu_voxel_world
+            .update_nodes(dirty_nodes, &cpu_voxel_world, &upload)
+            .unwrap();
+        assert!(gpu_voxel_world.brickmap[7] > BRICK_OFFSET);
+        assert_eq!(gpu_voxel_world.gpu_to_cpu[7], 7);
+        assert_eq!(gpu_voxel_world.cpu_to_gpu[&7], 7);
+
+        gpu_voxel_world
+            .divide_node(7, &cpu_voxel_world, &upload)
+            .unwrap();
+        let gpu_children = 8 * gpu_voxel_world.brickmap[7] as usize;
+        let cpu_children = 8 * cpu_voxel_world.brickmap[7].children as usize;
+        assert_ne!(
+            cpu_children,
+            8 * cpu_voxel_world.brickmap[0].children as usize
+        );
+        for i in 0..8 {
+            assert_eq!(
+                gpu_voxel_world.gpu_to_cpu[gpu_children + i] as usize,
+                cpu_children + i
+            );
+            assert_eq!(
+                gpu_voxel_world.cpu_to_gpu[&(cpu_children + i)],
+                gpu_children + i
+            );
+        }
+
+        // the child holding the voxel got its own brick, not one of octant 0's
+        let (leaf, _, _) = cpu_voxel_world.get_node(
+            cpu_voxel_world.voxel_pos(IVec3::splat(20)).unwrap() / BRICK_SIZE,
+            None,
+        );
+        let gpu_leaf = gpu_voxel_world.cpu_to_gpu[&leaf];
+        assert_eq!(gpu_leaf / 8, gpu_children / 8);
+        let gpu_brick = (gpu_voxel_world.brickmap[gpu_leaf] - BRICK_OFFSET) as usize;
+        let bricks = upload.0.borrow();
+        let brick = &bricks[&gpu_brick];
+        assert_eq!(
+            brick,
+            &*cpu_voxel_world
+                .bricks
+                .read(cpu_voxel_world.brickmap[leaf].brick as usize)
+                .unwrap()
+        );
+        assert_eq!(brick.get(UVec3::splat(52) % BRICK_SIZE), colour);
+    }
+
+    #[test]
+    fn clearing_a_leaf_frees_its_gpu_brick() {
+        let mut cpu_voxel_world = CpuVoxelWorld::new(CpuBrickmap::new(2));
+        cpu_voxel_world
+            .set_voxel(IVec3::splat(20), [1, 2, 3, 255])
+            .unwrap();
+        cpu_voxel_world.take_dirty_nodes();
+
+        let upload = RecordingUpload::default();
+        let mut gpu_voxel_world = GpuVoxelWorld::new(2, UVec3::splat(64), 16);
+        gpu_voxel_world
+            .update_nodes(0..8, &cpu_voxel_world, &upload)
+            .unwrap();
+        gpu_voxel_world
+            .divide_node(7, &cpu_voxel_world, &upload)
+            .unwrap();
+        let free_bricks = gpu_voxel_world.brick_holes.len();
+
+        cpu_voxel_world.clear_voxel(IVec3::splat(20)).unwrap();
+        let dirty_nodes = cpu_voxel_world.take_dirty_nodes();
+        gpu_voxel_world
+            .update_nodes(dirty_nodes, &cpu_voxel_world, &upload)
+            .unwrap();
+        let (leaf, _, _) = cpu_voxel_world.get_node(
+            cpu_voxel_world.voxel_pos(IVec3::splat(20)).unwrap() / BRICK_SIZE,
+            None,
+        );
+        assert_eq!(cpu_voxel_world.brickmap[leaf].brick, 0);
+        assert_eq!(
+            gpu_voxel_world.brickmap[gpu_voxel_world.cpu_to_gpu[&leaf]],
+            BRICK_OFFSET
+        );
+        assert_eq!(gpu_voxel_world.brick_holes.len(), free_bricks + 1);
+    }
+
+    #[test]
+    fn streaming_reads_dont_page_bricks_in() {
+        let mut cpu_brickmap = CpuBrickmap::new(2);
+        for pos in [IVec3::splat(-20), IVec3::splat(20), IVec3::new(-20, 5, 20)] {
+            cpu_brickmap.set_voxel(pos, [10, 20, 30, 255]).unwrap();
+        }
+        cpu_brickmap.recreate_mipmaps().unwrap();
+        let path =
+            std::env::temp_dir().join(format!("alex-streaming-{}.brickmap", std::process::id()));
+        cpu_brickmap.save(&path).unwrap();
+        let paged = CpuBrickmap::open_paged(&path, 0, false).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(paged.bricks.cached(), 0);
+
+        let upload = RecordingUpload::default();
+        let mut gpu_voxel_world = GpuVoxelWorld::new(2, UVec3::splat(64), 16);
+        gpu_voxel_world.update_nodes(0..8, &paged, &upload).unwrap();
+        for node in 0..8 {
+            if paged.brickmap[node].children != 0 {
+                gpu_voxel_world.divide_node(node, &paged, &upload).unwrap();
+            }
+        }
+
+        // every brick came from the file, none of them stayed behind
+        assert_eq!(paged.bricks.cached(), 0);
+        assert_eq!(paged.bricks.memory_usage(), (0, 0));
+        let mut streamed = 0;
+        for (gpu_node, cpu_node) in gpu_voxel_world.gpu_to_cpu.iter().enumerate() {
+            let brick = paged.brickmap[*cpu_node as usize].brick as usize;
+            if brick == 0 || gpu_voxel_world.brickmap[gpu_node] <= BRICK_OFFSET {
+                continue;
+            }
+            let gpu_brick = (gpu_voxel_world.brickmap[gpu_node] - BRICK_OFFSET) as usize;
+            assert_eq!(
+                upload.0.borrow()[&gpu_brick],
+                *cpu_brickmap.bricks.read(brick).unwrap()
+            );
+            streamed += 1;
+        }
+        // one leaf per voxel, the octants' own bricks were freed when divided
+        assert_eq!(streamed, 3);
+    }
+}
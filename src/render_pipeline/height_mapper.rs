@@ -1,4 +1,5 @@
-use image::{GenericImageView, Pixel};
+use image::DynamicImage;
+use noise::{Fbm, MultiFractal, NoiseFn, Perlin};
 use std::path::Path;
 
 use bevy::{asset::LoadState, prelude::*};
@@ -6,46 +7,153 @@ use bevy::{asset::LoadState, prelude::*};
 #[derive(Default, Resource)]
 pub struct WorldSetup;
 
-// Define a resource to store the heightmap data
+/// A grid of height samples, in the units of the file they came from: 0 to
+/// 255 for 8 bit images, 0 to 65535 for 16 bit ones and as stored for raw
+/// 32 bit floats.
 #[derive(Default, Resource)]
-pub struct Heightmap(pub Vec<Vec<u32>>);
-pub fn load_and_process_heightmap() -> Option<Heightmap> {
-    let image_path = Path::new("assets/heightmapdata/Take1.png");
+pub struct Heightmap {
+    pub width: usize,
+    pub height: usize,
+    /// row major, `x + z * width`
+    pub heights: Vec<f32>,
+}
 
-    match image::open(&image_path) {
-        Ok(img) => {
-            let (width, height) = img.dimensions();
-            let sample_step = 1; // Adjust as needed for sampling resolution
+impl Heightmap {
+    /// the height at `x, z` in samples, interpolated bilinearly between the
+    /// four nearest. positions off the edge take the nearest edge sample
+    pub fn sample(&self, x: f32, z: f32) -> f32 {
+        let x = x.clamp(0.0, (self.width - 1) as f32);
+        let z = z.clamp(0.0, (self.height - 1) as f32);
+        let (x0, z0) = (x.floor() as usize, z.floor() as usize);
+        let (x1, z1) = ((x0 + 1).min(self.width - 1), (z0 + 1).min(self.height - 1));
+        let (tx, tz) = (x - x0 as f32, z - z0 as f32);
 
-            let sampled_width = (width as usize + sample_step - 1) / sample_step;
-            let sampled_height = (height as usize + sample_step - 1) / sample_step;
+        let at = |x: usize, z: usize| self.heights[x + z * self.width];
+        let top = at(x0, z0) * (1.0 - tx) + at(x1, z0) * tx;
+        let bottom = at(x0, z1) * (1.0 - tx) + at(x1, z1) * tx;
+        top * (1.0 - tz) + bottom * tz
+    }
 
-            let mut heightmap = vec![vec![0u32; sampled_width]; sampled_height];
+    /// the lowest and highest samples, `0.0, 0.0` for an empty heightmap
+    pub fn height_range(&self) -> (f32, f32) {
+        let (min, max) = self
+            .heights
+            .iter()
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), &height| {
+                (min.min(height), max.max(height))
+            });
+        if min > max {
+            (0.0, 0.0)
+        } else {
+            (min, max)
+        }
+    }
+}
 
-            for (sampled_y, y) in (0..height).step_by(sample_step).enumerate() {
-                for (sampled_x, x) in (0..width).step_by(sample_step).enumerate() {
-                    let pixel = img.get_pixel(x, y);
+/// loads a greyscale image, 8 or 16 bit, or a raw square grid of little
+/// endian u16 (`.r16`) or f32 (`.r32`) samples
+pub fn load_heightmap(path: &Path) -> Result<Heightmap, String> {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_ascii_lowercase);
+    match extension.as_deref() {
+        Some("r16") => load_raw(path, 2, |bytes| {
+            u16::from_le_bytes([bytes[0], bytes[1]]) as f32
+        }),
+        Some("r32") => load_raw(path, 4, |bytes| {
+            f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+        }),
+        _ => load_image(path),
+    }
+}
 
-                    // Convert pixel to luminance (grayscale value)
-                    let luminance = pixel.to_luma()[0] as u32;
+fn load_image(path: &Path) -> Result<Heightmap, String> {
+    let image = image::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let heights = match image {
+        DynamicImage::ImageLuma16(_)
+        | DynamicImage::ImageLumaA16(_)
+        | DynamicImage::ImageRgb16(_)
+        | DynamicImage::ImageRgba16(_) => image
+            .into_luma16()
+            .into_raw()
+            .into_iter()
+            .map(|height| height as f32)
+            .collect(),
+        DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_) => {
+            image.into_rgb32f().pixels().map(|pixel| pixel[0]).collect()
+        }
+        _ => image
+            .into_luma8()
+            .into_raw()
+            .into_iter()
+            .map(|height| height as f32)
+            .collect(),
+    };
+    Ok(Heightmap {
+        width,
+        height,
+        heights,
+    })
+}
 
-                    // Store the luminance directly as the height value
-                    heightmap[sampled_y][sampled_x] = luminance;
-                }
+/// raw heightmaps have no header, so they have to be square
+fn load_raw(
+    path: &Path,
+    sample_size: usize,
+    read: impl Fn(&[u8]) -> f32,
+) -> Result<Heightmap, String> {
+    let bytes = std::fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let samples = bytes.len() / sample_size;
+    let side = (samples as f64).sqrt() as usize;
+    if bytes.len() % sample_size != 0 || side * side != samples || side == 0 {
+        return Err(format!(
+            "{}: {} bytes isn't a square grid of {} byte samples",
+            path.display(),
+            bytes.len(),
+            sample_size
+        ));
+    }
+    let heights = bytes
+        .chunks_exact(sample_size)
+        .map(|bytes| {
+            let height = read(bytes);
+            if height.is_finite() {
+                height
+            } else {
+                0.0
             }
+        })
+        .collect();
+    Ok(Heightmap {
+        width: side,
+        height: side,
+        heights,
+    })
+}
 
-            println!("Heightmap loaded and processed successfully.");
-            Some(Heightmap(heightmap))
-        }
-        Err(e) => {
-            error!(
-                "Failed to load heightmap image from {:?}: {}",
-                image_path, e
-            );
-            None
-        }
+/// fractal noise terrain `size` voxels across, heights are in the same 0..255
+/// range as image heightmaps
+pub fn procedural_heightmap(seed: u32, size: usize) -> Heightmap {
+    let noise = Fbm::<Perlin>::new(seed).set_octaves(5);
+    let scale = 1.0 / 128.0;
+
+    let heights = (0..size * size)
+        .map(|i| {
+            let (x, z) = (i % size, i / size);
+            let value = noise.get([x as f64 * scale, z as f64 * scale]);
+            ((value * 0.5 + 0.5).clamp(0.0, 1.0) * 255.0) as f32
+        })
+        .collect();
+
+    Heightmap {
+        width: size,
+        height: size,
+        heights,
     }
 }
+
 pub fn run_setup(height_map: Option<Res<Heightmap>>, world_setup: Option<Res<WorldSetup>>) -> bool {
     if let Some(_) = world_setup {
         if let Some(_) = height_map {
@@ -54,3 +162,79 @@ pub fn run_setup(height_map: Option<Res<Heightmap>>, world_setup: Option<Res<Wor
     }
     false
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("alex-heightmap-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn sample_interpolates_between_texels() {
+        let heightmap = Heightmap {
+            width: 2,
+            height: 2,
+            heights: vec![0.0, 10.0, 20.0, 40.0],
+        };
+        assert_eq!(heightmap.sample(0.0, 0.0), 0.0);
+        assert_eq!(heightmap.sample(1.0, 0.0), 10.0);
+        assert_eq!(heightmap.sample(0.0, 1.0), 20.0);
+        assert_eq!(heightmap.sample(1.0, 1.0), 40.0);
+        assert_eq!(heightmap.sample(0.5, 0.0), 5.0);
+        assert_eq!(heightmap.sample(0.0, 0.25), 5.0);
+        assert_eq!(heightmap.sample(0.5, 0.5), 17.5);
+
+        // off the edge clamps to it
+        assert_eq!(heightmap.sample(-3.0, 0.0), 0.0);
+        assert_eq!(heightmap.sample(5.0, 9.0), 40.0);
+    }
+
+    #[test]
+    fn raw_heightmaps_round_trip() {
+        let r16 = [0u16, 1, 1000, u16::MAX];
+        let path = temp_path("a.r16");
+        let bytes = r16.iter().flat_map(|h| h.to_le_bytes()).collect::<Vec<_>>();
+        std::fs::write(&path, bytes).unwrap();
+        let heightmap = load_heightmap(&path);
+        std::fs::remove_file(&path).unwrap();
+        let heightmap = heightmap.unwrap();
+        assert_eq!((heightmap.width, heightmap.height), (2, 2));
+        assert_eq!(heightmap.heights, r16.map(|h| h as f32));
+
+        let r32 = [-2.5f32, 0.0, 0.125, 300.0, 7.0, 8.0, 9.0, 1e6, f32::NAN];
+        let path = temp_path("a.R32");
+        let bytes = r32.iter().flat_map(|h| h.to_le_bytes()).collect::<Vec<_>>();
+        std::fs::write(&path, bytes).unwrap();
+        let heightmap = load_heightmap(&path);
+        std::fs::remove_file(&path).unwrap();
+        let heightmap = heightmap.unwrap();
+        assert_eq!((heightmap.width, heightmap.height), (3, 3));
+        // samples that aren't finite read as 0
+        assert_eq!(heightmap.heights[..8], r32[..8]);
+        assert_eq!(heightmap.heights[8], 0.0);
+    }
+
+    #[test]
+    fn raw_heightmaps_have_to_be_square() {
+        for (name, len) in [("a.r16", 6), ("b.r16", 7), ("c.r32", 8), ("d.r16", 0)] {
+            let path = temp_path(name);
+            std::fs::write(&path, vec![0; len]).unwrap();
+            let heightmap = load_heightmap(&path);
+            std::fs::remove_file(&path).unwrap();
+            assert!(heightmap.is_err(), "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn height_range_spans_the_samples() {
+        let heightmap = Heightmap {
+            width: 3,
+            height: 1,
+            heights: vec![4.0, -1.5, 12.0],
+        };
+        assert_eq!(heightmap.height_range(), (-1.5, 12.0));
+        assert_eq!(Heightmap::default().height_range(), (0.0, 0.0));
+    }
+}
@@ -1,164 +1,814 @@
 use super::{
+    block_colours::{AnvilBlock, BlockColour, BlockColours},
     cpu_brickmap::{Brick, CpuBrickmap},
+    voxel_world::VoxelWorldConfig,
+    world_builder::empty_brickmap,
     BRICK_SIZE,
 };
-use bevy::{prelude::*, utils::HashMap};
-use std::path::PathBuf;
-
-fn load_palette() -> HashMap<String, [u8; 4]> {
-    let file = std::fs::File::open("assets/palette/blockstates.json");
-    let mut json: HashMap<String, [u8; 4]> = serde_json::from_reader(file.unwrap()).unwrap();
-    json.insert("".to_string(), [200, 200, 200, 127]);
-    json.insert("minecraft:grass".to_string(), [0, 0, 0, 0]);
-    json.insert("minecraft:tall_grass".to_string(), [0, 0, 0, 0]);
-    json.insert("minecraft:grass_block".to_string(), [62, 204, 18, 255]);
-    json.insert("minecraft:water".to_string(), [20, 105, 201, 30]);
-    json.insert("minecraft:cave_air".to_string(), [0, 0, 0, 0]);
-    json.insert("minecraft:lava".to_string(), [255, 123, 0, 255]);
-    json.insert("minecraft:seagrass".to_string(), [62, 204, 18, 255]);
-    json.insert("minecraft:deepslate".to_string(), [77, 77, 77, 255]);
-    json.insert("minecraft:oak_log".to_string(), [112, 62, 8, 255]);
-    json.insert("minecraft:oak_stairs".to_string(), [112, 62, 8, 255]);
-
-    json
-}
-
-pub fn load_anvil(region_path: PathBuf, world_depth: u32) -> CpuBrickmap {
-    let side_length = 1 << world_depth;
-    let mut brickmap = CpuBrickmap::new(world_depth - BRICK_SIZE.trailing_zeros());
-
-    // load mc palette
-    let palette = load_palette();
-
-    // load chunks into the texture
-    use fastanvil::{CurrentJavaChunk, Region};
-    use fastnbt::from_bytes;
+use bevy::prelude::*;
+use fastanvil::{biome::Biome, BiomeData, BlockData};
+use rayon::prelude::*;
+use serde::{de::IgnoredAny, Deserialize};
+use std::path::{Path, PathBuf};
 
-    let side_length_chunks = side_length / 16;
-    let side_length_regions = (side_length_chunks / 32).max(1);
-    let half_side_length_regions: i32 = side_length_regions / 2;
-    'outer: for region_x in -half_side_length_regions..half_side_length_regions.max(4) {
-        for region_z in -half_side_length_regions..half_side_length_regions.max(4) {
-            let path = region_path.join(format!("r.{}.{}.mca", region_x, region_z));
-            if let Ok(file) = std::fs::File::open(path.clone()) {
-                info!("loading region {}", path.display());
-                let mut region = match Region::from_stream(file) {
-                    Ok(region) => region,
-                    Err(e) => {
-                        error!("failed to load region {}: {}", path.display(), e);
-                        continue;
-                    }
-                };
-
-                for chunk_x in 0..side_length_chunks.min(32) {
-                    for chunk_z in 0..side_length_chunks.min(32) {
-                        if let Some(data) = region
-                            .read_chunk(chunk_x as usize, chunk_z as usize)
-                            .unwrap()
-                        {
-                            let chunk: CurrentJavaChunk = from_bytes(data.as_slice()).unwrap();
-                            let section_tower = chunk.sections.unwrap();
-
-                            for section in section_tower.sections() {
-                                if section.block_states.palette().len() <= 1 {
+/// the first data version with block palettes, from the 1.13 snapshots
+const FIRST_1_13_DATA_VERSION: i32 = 1451;
+
+/// Which blocks of an anvil world are imported and where they go.
+#[derive(Clone, Debug)]
+pub struct AnvilWindow {
+    /// the block placed at the centre of the world, a multiple of 16 on
+    /// every axis
+    pub origin: IVec3,
+    /// the lowest block y imported
+    pub min_y: i32,
+    /// the highest block y imported
+    pub max_y: i32,
+}
+
+impl Default for AnvilWindow {
+    /// the whole height of a 1.18 world, centred on y 128
+    fn default() -> Self {
+        Self {
+            origin: IVec3::new(0, 128, 0),
+            min_y: -64,
+            max_y: 319,
+        }
+    }
+}
+
+impl AnvilWindow {
+    fn validate(&self) -> Result<(), String> {
+        if self.origin % 16 != IVec3::ZERO {
+            return Err(format!(
+                "origin {} must be a multiple of 16 on every axis",
+                self.origin
+            ));
+        }
+        if self.min_y > self.max_y {
+            return Err(format!(
+                "min y {} is above max y {}",
+                self.min_y, self.max_y
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The fields of a chunk needed to place it and tell which layout it uses.
+#[derive(Deserialize)]
+struct ChunkHeader {
+    #[serde(rename = "DataVersion")]
+    data_version: Option<i32>,
+    #[serde(rename = "xPos")]
+    x_pos: Option<i32>,
+    #[serde(rename = "zPos")]
+    z_pos: Option<i32>,
+    #[serde(rename = "Level")]
+    level: Option<IgnoredAny>,
+}
+
+/// The sections of a 1.18 or newer chunk, with every property of each block.
+#[derive(Deserialize)]
+struct AnvilChunk {
+    #[serde(default)]
+    sections: Vec<AnvilSection>,
+}
+
+#[derive(Deserialize)]
+struct AnvilSection {
+    #[serde(rename = "Y")]
+    y: i8,
+    #[serde(default)]
+    block_states: BlockData<AnvilBlock>,
+    #[serde(default)]
+    biomes: BiomeData<Biome>,
+}
+
+/// A section of a chunk in either layout, with its palette coloured.
+struct ChunkSection {
+    /// block y of the bottom of the section
+    min_y: i32,
+    blocks: Vec<Option<BlockColour>>,
+    /// index into `blocks` of every block, by x then z then y
+    indices: Vec<usize>,
+    /// biome of every 4x4x4 cell, by x then z then y
+    biomes: Vec<Option<Biome>>,
+}
+
+impl ChunkSection {
+    /// `None` if none of the section's blocks are drawn
+    fn new(
+        y: i8,
+        blocks: Vec<Option<BlockColour>>,
+        indices: Option<Vec<usize>>,
+        biome: impl Fn(usize, usize, usize) -> Option<Biome>,
+    ) -> Option<Self> {
+        if blocks.iter().all(Option::is_none) {
+            return None;
+        }
+        let biomes = (0..4 * 4 * 4)
+            .map(|cell| biome(cell % 4 * 4, cell / 16 * 4, cell / 4 % 4 * 4))
+            .collect();
+        Some(Self {
+            min_y: y as i32 * 16,
+            blocks,
+            // sections of a single block don't store indices
+            indices: indices.unwrap_or_else(|| vec![0; 16 * 16 * 16]),
+            biomes,
+        })
+    }
+
+    /// the section as bricks with their position in the section, leaving out
+    /// blocks below `min_y` or above `max_y`
+    fn bricks(&self, colours: &BlockColours, min_y: i32, max_y: i32) -> Vec<(Brick, UVec3)> {
+        let chunk_side_length_bricks = 16 / BRICK_SIZE;
+        let mut bricks = Vec::new();
+        for brick_x in 0..chunk_side_length_bricks {
+            for brick_y in 0..chunk_side_length_bricks {
+                for brick_z in 0..chunk_side_length_bricks {
+                    let mut brick = Brick::empty();
+                    for x in 0..BRICK_SIZE {
+                        for y in 0..BRICK_SIZE {
+                            for z in 0..BRICK_SIZE {
+                                let (local_x, local_y, local_z) = (
+                                    (brick_x * BRICK_SIZE + x) as usize,
+                                    (brick_y * BRICK_SIZE + y) as usize,
+                                    (brick_z * BRICK_SIZE + z) as usize,
+                                );
+                                let block_y = self.min_y + local_y as i32;
+                                if block_y < min_y || block_y > max_y {
                                     continue;
                                 }
+                                let index = local_y * 16 * 16 + local_z * 16 + local_x;
+                                let Some(Some(block)) =
+                                    self.indices.get(index).and_then(|i| self.blocks.get(*i))
+                                else {
+                                    continue;
+                                };
 
-                                let block_data = &section.block_states;
-                                let pos = UVec3::new(
-                                    32 * (region_x + half_side_length_regions) as u32
-                                        + chunk_x as u32,
-                                    (section.y as i32 + side_length_chunks / 2) as u32,
-                                    32 * (region_z + half_side_length_regions) as u32
-                                        + chunk_z as u32,
+                                let cell = local_y / 4 * 16 + local_z / 4 * 4 + local_x / 4;
+                                brick.write_material(
+                                    UVec3::new(x, y, z),
+                                    colours.colour(block, self.biomes[cell]),
+                                    block.material(),
                                 );
-
-                                let chunk_side_length_bricks = 16 / BRICK_SIZE;
-                                for brick_x in 0..chunk_side_length_bricks {
-                                    for brick_y in 0..chunk_side_length_bricks {
-                                        for brick_z in 0..chunk_side_length_bricks {
-                                            let mut brick = Brick::empty();
-                                            for x in 0..BRICK_SIZE {
-                                                for y in 0..BRICK_SIZE {
-                                                    for z in 0..BRICK_SIZE {
-                                                        let block = block_data.at(
-                                                            (brick_x * BRICK_SIZE + x) as usize,
-                                                            (brick_y * BRICK_SIZE + y) as usize,
-                                                            (brick_z * BRICK_SIZE + z) as usize,
-                                                        );
-                                                        if block.unwrap().name() == "minecraft:air"
-                                                        {
-                                                            continue;
-                                                        }
-
-                                                        let block_name = block.unwrap().name();
-                                                        let defualt_col = palette.get("").unwrap();
-                                                        let colour = palette
-                                                            .get(block_name)
-                                                            .unwrap_or(defualt_col);
-
-                                                        let pos = UVec3::new(x, y, z);
-                                                        brick.write(pos, *colour);
-                                                    }
-                                                }
-                                            }
-
-                                            match brickmap.place_brick(
-                                                brick,
-                                                chunk_side_length_bricks * pos
-                                                    + UVec3::new(brick_x, brick_y, brick_z),
-                                            ) {
-                                                Ok(_) => {}
-                                                Err(e) => {
-                                                    println!("{}", e);
-                                                    break 'outer;
-                                                }
-                                            };
-                                        }
-                                    }
-                                }
                             }
                         }
                     }
+                    bricks.push((brick, UVec3::new(brick_x, brick_y, brick_z)));
+                }
+            }
+        }
+        bricks
+    }
+}
+
+/// Reads a chunk in the 1.18 layout, or the 1.13 to 1.17 one, into its
+/// position and the sections with something to draw.
+fn read_chunk(data: &[u8], colours: &BlockColours) -> Result<(IVec2, Vec<ChunkSection>), String> {
+    use fastanvil::{pre18, Chunk};
+    use fastnbt::from_bytes;
+
+    let header: ChunkHeader = from_bytes(data).map_err(|e| e.to_string())?;
+    let data_version = header.data_version.unwrap_or(0);
+    if data_version < FIRST_1_13_DATA_VERSION {
+        return Err(format!(
+            "data version {} is from before 1.13, open the world in 1.13 or newer to upgrade it",
+            data_version
+        ));
+    }
+
+    if header.level.is_none() {
+        let (Some(x_pos), Some(z_pos)) = (header.x_pos, header.z_pos) else {
+            return Err("chunk has no xPos or zPos".into());
+        };
+        let chunk: AnvilChunk = from_bytes(data).map_err(|e| e.to_string())?;
+        let sections = chunk
+            .sections
+            .iter()
+            .filter_map(|section| {
+                // resolve each block of the section's palette once
+                let blocks = section
+                    .block_states
+                    .palette()
+                    .iter()
+                    .map(|block| colours.resolve(block))
+                    .collect();
+                ChunkSection::new(
+                    section.y,
+                    blocks,
+                    section
+                        .block_states
+                        .try_iter_indices()
+                        .map(|indices| indices.collect()),
+                    |x, y, z| section.biomes.at(x, y, z).copied(),
+                )
+            })
+            .collect();
+        return Ok((IVec2::new(x_pos, z_pos), sections));
+    }
+
+    // before 1.18 everything is under Level, and sections start at y 0 unless
+    // a data pack moved them
+    let chunk: pre18::JavaChunk = from_bytes(data).map_err(|e| e.to_string())?;
+    let sections = chunk
+        .level
+        .sections
+        .iter()
+        .flat_map(|tower| tower.sections())
+        .filter_map(|section| {
+            let blocks = section
+                .palette
+                .iter()
+                .map(|block| colours.resolve(&AnvilBlock::from(block)))
+                .collect();
+            let min_y = section.y as isize * 16;
+            ChunkSection::new(
+                section.y,
+                blocks,
+                section
+                    .block_states
+                    .as_ref()
+                    .map(|states| states.iter_indices(section.palette.len()).collect()),
+                |x, y, z| chunk.biome(x, min_y + y as isize, z),
+            )
+        })
+        .collect();
+    Ok((IVec2::new(chunk.level.x_pos, chunk.level.z_pos), sections))
+}
+
+/// Every `r.x.z.mca` file in `region_path` with its region coordinates.
+fn anvil_regions(region_path: &Path) -> Result<Vec<(IVec2, PathBuf)>, String> {
+    let entries = std::fs::read_dir(region_path)
+        .map_err(|e| format!("failed to read {}: {}", region_path.display(), e))?;
+
+    let mut regions = Vec::new();
+    for entry in entries {
+        let path = entry.map_err(|e| e.to_string())?.path();
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        let parts = name.split('.').collect::<Vec<_>>();
+        if let ["r", x, z, "mca"] = parts[..] {
+            if let (Ok(x), Ok(z)) = (x.parse(), z.parse()) {
+                regions.push((IVec2::new(x, z), path));
+            }
+        }
+    }
+    regions.sort_by_key(|(region, _)| (region.x, region.y));
+
+    Ok(regions)
+}
+
+/// Reads one region file into bricks, placing each chunk by its stored
+/// position relative to the window's origin. Chunks and sections that fall
+/// outside the world are left out and counted, chunks that can't be read are
+/// recorded in the summary.
+fn load_region(
+    path: &Path,
+    config: &VoxelWorldConfig,
+    window: &AnvilWindow,
+    colours: &BlockColours,
+) -> Result<(Vec<(Brick, UVec3)>, RegionSummary), String> {
+    use fastanvil::Region;
+
+    let side_length_chunks = (1 << config.world_depth) / 16;
+    let chunk_side_length_bricks = 16 / BRICK_SIZE;
+    let origin_chunk = window.origin / 16;
+
+    let file = std::fs::File::open(path).map_err(|e| e.to_string())?;
+    let mut region_file = Region::from_stream(file).map_err(|e| e.to_string())?;
+
+    let mut bricks = Vec::new();
+    let mut summary = RegionSummary::default();
+    for chunk_x in 0..32 {
+        for chunk_z in 0..32 {
+            let slot = UVec2::new(chunk_x, chunk_z);
+            let mut chunk_error = |e: String| summary.chunk_errors.push((slot, e));
+
+            let data = match region_file.read_chunk(chunk_x as usize, chunk_z as usize) {
+                Ok(Some(data)) => data,
+                Ok(None) => continue,
+                Err(e) => {
+                    chunk_error(e.to_string());
+                    continue;
                 }
+            };
+            let (chunk_pos, sections) = match read_chunk(&data, colours) {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    chunk_error(e);
+                    continue;
+                }
+            };
+            summary.chunks += 1;
+
+            let chunk_pos = chunk_pos - origin_chunk.xz() + side_length_chunks / 2;
+            if chunk_pos.cmplt(IVec2::ZERO).any()
+                || chunk_pos.cmpge(IVec2::splat(side_length_chunks)).any()
+            {
+                summary.clipped_chunks += 1;
+                continue;
+            }
+            for section in sections {
+                if section.min_y > window.max_y || section.min_y + 15 < window.min_y {
+                    continue;
+                }
+                let section_y = section.min_y / 16 - origin_chunk.y + side_length_chunks / 2;
+                if section_y < 0 || section_y >= side_length_chunks {
+                    summary.clipped_sections += 1;
+                    continue;
+                }
+
+                let pos = IVec3::new(chunk_pos.x, section_y, chunk_pos.y).as_uvec3();
+                bricks.extend(
+                    section
+                        .bricks(colours, window.min_y, window.max_y)
+                        .into_iter()
+                        .map(|(brick, brick_pos)| {
+                            (brick, chunk_side_length_bricks * pos + brick_pos)
+                        }),
+                );
+            }
+        }
+    }
+    summary.bricks = bricks.len();
+
+    Ok((bricks, summary))
+}
+
+/// What was read from a region file.
+#[derive(Clone, Debug, Default)]
+pub struct RegionSummary {
+    pub bricks: usize,
+    /// chunks read, including ones outside the world
+    pub chunks: usize,
+    /// chunks left out for being outside the world
+    pub clipped_chunks: usize,
+    /// sections inside the window left out for being above or below the
+    /// world
+    pub clipped_sections: usize,
+    /// chunks that couldn't be read, by their slot in the region
+    pub chunk_errors: Vec<(UVec2, String)>,
+}
+
+/// What happened to one region file during `load_anvil`.
+#[derive(Clone, Debug)]
+pub enum RegionStatus {
+    Loaded(RegionSummary),
+    /// every chunk the region could hold is outside of the world
+    OutsideWorld,
+    Failed(String),
+}
+
+/// Every region `load_anvil` looked at and what happened to it.
+#[derive(Default, Debug)]
+pub struct AnvilReport {
+    pub regions: Vec<(PathBuf, RegionStatus)>,
+    /// blocks missing from the palette, drawn with its fallback colour
+    pub unknown_blocks: Vec<String>,
+}
+
+impl AnvilReport {
+    pub fn loaded(&self) -> impl Iterator<Item = (&PathBuf, &RegionSummary)> {
+        self.regions
+            .iter()
+            .filter_map(|(path, status)| match status {
+                RegionStatus::Loaded(summary) => Some((path, summary)),
+                _ => None,
+            })
+    }
+
+    pub fn outside_world(&self) -> usize {
+        self.regions
+            .iter()
+            .filter(|(_, status)| matches!(status, RegionStatus::OutsideWorld))
+            .count()
+    }
+
+    pub fn failed(&self) -> impl Iterator<Item = (&PathBuf, &String)> {
+        self.regions
+            .iter()
+            .filter_map(|(path, status)| match status {
+                RegionStatus::Failed(e) => Some((path, e)),
+                _ => None,
+            })
+    }
+
+    /// every chunk that couldn't be read, with the region it's in
+    pub fn chunk_errors(&self) -> impl Iterator<Item = (&PathBuf, UVec2, &String)> {
+        self.loaded().flat_map(|(path, summary)| {
+            summary
+                .chunk_errors
+                .iter()
+                .map(move |(slot, e)| (path, *slot, e))
+        })
+    }
+}
+
+/// Loads every region in `region_path` in parallel, calling `progress` from
+/// the worker threads as each region finishes. Regions and chunks that fail to
+/// load are left out and recorded in the report. Mips aren't built.
+pub fn load_anvil(
+    region_path: &Path,
+    config: &VoxelWorldConfig,
+    window: &AnvilWindow,
+    progress: impl Fn(&Path, &RegionStatus) + Sync,
+) -> Result<(CpuBrickmap, AnvilReport), String> {
+    window.validate()?;
+    let mut brickmap = empty_brickmap(config);
+
+    let colours = BlockColours::load(config.palette.as_deref())?;
+
+    let side_length_chunks = (1 << config.world_depth) / 16;
+    let (regions, bricks): (Vec<_>, Vec<_>) = anvil_regions(region_path)?
+        .into_par_iter()
+        .map(|(region, path)| {
+            let min_chunk = region * 32 - window.origin.xz() / 16 + side_length_chunks / 2;
+            let outside = (min_chunk + 32).cmple(IVec2::ZERO).any()
+                || min_chunk.cmpge(IVec2::splat(side_length_chunks)).any();
+            let (status, bricks) = if outside {
+                (RegionStatus::OutsideWorld, Vec::new())
             } else {
-                info!("skipping region {}", path.display());
+                match load_region(&path, config, window, &colours) {
+                    Ok((bricks, summary)) => (RegionStatus::Loaded(summary), bricks),
+                    Err(e) => (RegionStatus::Failed(e), Vec::new()),
+                }
+            };
+            progress(&path, &status);
+            ((path, status), bricks)
+        })
+        .unzip();
+    brickmap.place_bricks(bricks.into_iter().flatten().collect())?;
+
+    Ok((
+        brickmap,
+        AnvilReport {
+            regions,
+            unknown_blocks: colours.palette().unknown_blocks(),
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fastnbt::{IntArray, LongArray, Value};
+
+    fn compound(entries: Vec<(&str, Value)>) -> Value {
+        Value::Compound(
+            entries
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+        )
+    }
+
+    fn block(name: &str) -> Value {
+        compound(vec![("Name", Value::String(name.into()))])
+    }
+
+    /// 4 bit palette indices, 1 at `blocks` and 0 everywhere else
+    fn indices(blocks: &[UVec3]) -> Value {
+        let mut longs = vec![0i64; 16 * 16 * 16 / 16];
+        for pos in blocks {
+            let index = (pos.y * 16 * 16 + pos.z * 16 + pos.x) as usize;
+            longs[index / 16] |= 1 << (index % 16 * 4);
+        }
+        Value::LongArray(LongArray::new(longs))
+    }
+
+    /// a 1.18 section of stone at `stone` in air, or filled with stone
+    fn section(y: i8, stone: Option<&[UVec3]>) -> Value {
+        let block_states = match stone {
+            Some(stone) => compound(vec![
+                (
+                    "palette",
+                    Value::List(vec![block("minecraft:air"), block("minecraft:stone")]),
+                ),
+                ("data", indices(stone)),
+            ]),
+            None => compound(vec![(
+                "palette",
+                Value::List(vec![block("minecraft:stone")]),
+            )]),
+        };
+        compound(vec![
+            ("Y", Value::Byte(y)),
+            ("block_states", block_states),
+            (
+                "biomes",
+                compound(vec![(
+                    "palette",
+                    Value::List(vec![Value::String("minecraft:plains".into())]),
+                )]),
+            ),
+        ])
+    }
+
+    fn chunk(x: i32, z: i32, sections: Vec<Value>) -> Vec<u8> {
+        fastnbt::to_bytes(&compound(vec![
+            ("DataVersion", Value::Int(2975)),
+            ("xPos", Value::Int(x)),
+            ("zPos", Value::Int(z)),
+            ("Status", Value::String("minecraft:full".into())),
+            ("sections", Value::List(sections)),
+        ]))
+        .unwrap()
+    }
+
+    /// a 1.16 chunk, with stone at `stone` in the sections at `section_ys`
+    /// and the empty section older worlds end with below them
+    fn pre_1_18_chunk(x: i32, z: i32, section_ys: &[i8], stone: &[UVec3]) -> Vec<u8> {
+        let mut sections = vec![compound(vec![("Y", Value::Byte(-1))])];
+        sections.extend(section_ys.iter().map(|y| {
+            compound(vec![
+                ("Y", Value::Byte(*y)),
+                (
+                    "Palette",
+                    Value::List(vec![block("minecraft:air"), block("minecraft:stone")]),
+                ),
+                ("BlockStates", indices(stone)),
+            ])
+        }));
+        fastnbt::to_bytes(&compound(vec![
+            ("DataVersion", Value::Int(2586)),
+            (
+                "Level",
+                compound(vec![
+                    ("xPos", Value::Int(x)),
+                    ("zPos", Value::Int(z)),
+                    ("Status", Value::String("full".into())),
+                    ("Sections", Value::List(sections)),
+                    ("Biomes", Value::IntArray(IntArray::new(vec![1; 1024]))),
+                ]),
+            ),
+        ]))
+        .unwrap()
+    }
+
+    fn stone(colours: &BlockColours) -> [u8; 4] {
+        let stone = colours
+            .resolve(&AnvilBlock::named("minecraft:stone"))
+            .unwrap();
+        colours.colour(&stone, None)
+    }
+
+    /// the drawn blocks of a section by their block position
+    fn section_blocks(section: &ChunkSection, colours: &BlockColours) -> Vec<(IVec3, [u8; 4])> {
+        let mut blocks = Vec::new();
+        for (brick, brick_pos) in section.bricks(colours, i32::MIN, i32::MAX) {
+            for i in 0..BRICK_SIZE.pow(3) {
+                let pos = UVec3::new(i % 16, i / 16 % 16, i / 256);
+                let colour = brick.get(pos);
+                if colour[3] != 0 {
+                    let pos = (brick_pos * BRICK_SIZE + pos).as_ivec3();
+                    blocks.push((pos + IVec3::Y * section.min_y, colour));
+                }
             }
         }
+        blocks
+    }
+
+    #[test]
+    fn reads_1_18_chunks() {
+        let colours = BlockColours::load(None).unwrap();
+        let data = chunk(
+            3,
+            -2,
+            vec![
+                section(-4, Some(&[UVec3::new(1, 2, 3), UVec3::new(15, 15, 15)])),
+                compound(vec![
+                    ("Y", Value::Byte(2)),
+                    (
+                        "block_states",
+                        compound(vec![("palette", Value::List(vec![block("minecraft:air")]))]),
+                    ),
+                ]),
+                section(5, None),
+            ],
+        );
+        let (pos, sections) = read_chunk(&data, &colours).unwrap();
+
+        assert_eq!(pos, IVec2::new(3, -2));
+        // the all air section is left out
+        assert_eq!(
+            sections.iter().map(|s| s.min_y).collect::<Vec<_>>(),
+            [-64, 80]
+        );
+        assert_eq!(
+            section_blocks(&sections[0], &colours),
+            [
+                (IVec3::new(1, -62, 3), stone(&colours)),
+                (IVec3::new(15, -49, 15), stone(&colours)),
+            ]
+        );
+        assert_eq!(section_blocks(&sections[1], &colours).len(), 16 * 16 * 16);
+    }
+
+    #[test]
+    fn reads_pre_1_18_chunks() {
+        let colours = BlockColours::load(None).unwrap();
+        let data = pre_1_18_chunk(-7, 12, &[0, 15], &[UVec3::new(4, 0, 9)]);
+        let (pos, sections) = read_chunk(&data, &colours).unwrap();
+
+        assert_eq!(pos, IVec2::new(-7, 12));
+        assert_eq!(
+            sections.iter().map(|s| s.min_y).collect::<Vec<_>>(),
+            [0, 240]
+        );
+        assert_eq!(
+            section_blocks(&sections[1], &colours),
+            [(IVec3::new(4, 240, 9), stone(&colours))]
+        );
+    }
+
+    #[test]
+    fn rejects_old_and_unplaced_chunks() {
+        let colours = BlockColours::load(None).unwrap();
+        let old = fastnbt::to_bytes(&compound(vec![
+            ("DataVersion", Value::Int(1343)),
+            ("Level", compound(vec![])),
+        ]))
+        .unwrap();
+        assert!(matches!(read_chunk(&old, &colours), Err(e) if e.contains("before 1.13")));
+
+        let unplaced = fastnbt::to_bytes(&compound(vec![
+            ("DataVersion", Value::Int(2975)),
+            ("sections", Value::List(vec![])),
+        ]))
+        .unwrap();
+        assert!(read_chunk(&unplaced, &colours).is_err());
+    }
+
+    /// loads a world of one region holding `chunks`
+    fn load_chunks(
+        name: &str,
+        chunks: &[Vec<u8>],
+        world_depth: u32,
+        window: &AnvilWindow,
+    ) -> Result<(CpuBrickmap, RegionSummary), String> {
+        let dir = std::env::temp_dir().join(format!("alex-anvil-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = std::fs::File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(dir.join("r.0.0.mca"))
+            .unwrap();
+        let mut region = fastanvil::Region::new(file).unwrap();
+        for (slot, chunk) in chunks.iter().enumerate() {
+            region.write_chunk(slot, 0, chunk).unwrap();
+        }
+        drop(region);
+
+        let config = VoxelWorldConfig {
+            world_depth,
+            ..default()
+        };
+        let result = load_anvil(&dir, &config, window, |_, _| {});
+        std::fs::remove_dir_all(&dir).unwrap();
+        let (brickmap, report) = result?;
+        let (_, summary) = report.loaded().next().unwrap();
+        Ok((brickmap, summary.clone()))
+    }
+
+    /// a chunk at 0, 0 with a block of stone at its corner in sections -4, 0
+    /// and 10
+    fn tall_chunk() -> Vec<u8> {
+        chunk(
+            0,
+            0,
+            [-4, 0, 10]
+                .into_iter()
+                .map(|y| section(y, Some(&[UVec3::ZERO])))
+                .collect(),
+        )
     }
 
-    // let file = std::fs::File::open("assets/region/r.0.0.mca").unwrap();
-    // let mut region = Region::from_stream(file).unwrap();
-    // let data = region.read_chunk(0, 0).unwrap().unwrap();
-    // let chunk: CurrentJavaChunk = from_bytes(data.as_slice()).unwrap();
-    // let section_tower = chunk.sections.unwrap();
-    // place_section(
-    //     &section_tower.get_section_for_y(64).unwrap().block_states,
-    //     IVec3::new(2, 2, 2),
-    // )
-    // .unwrap();
-
-    brickmap
-}
-
-// pub fn from_block_data(block_data: &BlockData<Block>, palette: &HashMap<String, [u8; 4]>) -> Brick {
-//     let mut brick = Brick::empty();
-//     for x in 0..BRICK_SIZE {
-//         for y in 0..BRICK_SIZE {
-//             for z in 0..BRICK_SIZE {
-//                 let block = block_data.at(x as usize, y as usize, z as usize);
-//                 if block.unwrap().name() == "minecraft:air" {
-//                     continue;
-//                 }
-
-//                 let block_name = block.unwrap().name();
-//                 let defualt_col = palette.get("").unwrap();
-//                 let colour = palette.get(block_name).unwrap_or(&defualt_col);
-
-//                 let pos = UVec3::new(x, y, z);
-//                 brick.write(pos, *colour);
-//             }
-//         }
-//     }
-//     brick
-// }
+    #[test]
+    fn sections_outside_the_world_are_clipped() {
+        // 64 blocks tall, from y -32 to 31
+        let window = AnvilWindow {
+            origin: IVec3::ZERO,
+            ..default()
+        };
+        let (brickmap, summary) = load_chunks("clipped", &[tall_chunk()], 6, &window).unwrap();
+
+        assert_eq!(summary.chunks, 1);
+        assert_eq!(summary.clipped_chunks, 0);
+        assert_eq!(summary.clipped_sections, 2);
+        assert_eq!(
+            brickmap.voxels().unwrap().into_iter().map(|(pos, _)| pos).collect::<Vec<_>>(),
+            [IVec3::ZERO]
+        );
+    }
+
+    #[test]
+    fn negative_sections_land_below_the_origin() {
+        // from y -80 to -17
+        let window = AnvilWindow {
+            origin: IVec3::new(0, -48, 0),
+            ..default()
+        };
+        let (brickmap, summary) = load_chunks("negative", &[tall_chunk()], 6, &window).unwrap();
+
+        assert_eq!(summary.clipped_sections, 2);
+        let colours = BlockColours::load(None).unwrap();
+        let block = IVec3::new(0, -64, 0);
+        assert_eq!(
+            brickmap.voxels().unwrap(),
+            [(block - window.origin, stone(&colours))]
+        );
+    }
+
+    #[test]
+    fn window_limits_the_height_imported() {
+        let window = AnvilWindow {
+            origin: IVec3::ZERO,
+            min_y: -63,
+            max_y: 0,
+        };
+        let data = chunk(
+            0,
+            0,
+            vec![
+                section(-4, Some(&[UVec3::ZERO, UVec3::Y])),
+                section(0, Some(&[UVec3::ZERO, UVec3::Y])),
+                section(10, None),
+            ],
+        );
+        let (brickmap, summary) = load_chunks("window", &[data], 7, &window).unwrap();
+
+        // the section above the window isn't clipped, it's left out
+        assert_eq!(summary.clipped_sections, 0);
+        let mut voxels = brickmap.voxels().unwrap().into_iter().map(|(pos, _)| pos).collect::<Vec<_>>();
+        voxels.sort_by_key(|pos| pos.y);
+        assert_eq!(voxels, [IVec3::new(0, -63, 0), IVec3::ZERO]);
+    }
+
+    #[test]
+    fn inverted_window_is_an_error() {
+        let window = AnvilWindow {
+            min_y: 100,
+            max_y: 50,
+            ..default()
+        };
+        let error = load_chunks("inverted", &[tall_chunk()], 6, &window).unwrap_err();
+        assert!(error.contains("above"), "{}", error);
+    }
+
+    #[test]
+    fn loads_imperial_city() {
+        let config = VoxelWorldConfig {
+            world_depth: 12,
+            ..default()
+        };
+        let window = AnvilWindow {
+            origin: IVec3::new(-256, 64, 768),
+            ..default()
+        };
+        let (brickmap, report) = load_anvil(
+            Path::new("assets/worlds/imperial_city"),
+            &config,
+            &window,
+            |_, _| {},
+        )
+        .unwrap();
+
+        assert_eq!(report.regions.len(), 34);
+        assert_eq!(report.loaded().count(), 34);
+        assert_eq!(report.chunk_errors().count(), 0);
+        let chunks = report
+            .loaded()
+            .map(|(_, summary)| summary.chunks)
+            .sum::<usize>();
+        assert_eq!(chunks, 1709);
+        assert!(report
+            .loaded()
+            .all(|(_, summary)| summary.clipped_chunks == 0 && summary.clipped_sections == 0));
+
+        // a column of the beach in r.1.-2, looked up with fastanvil
+        let colours = BlockColours::load(None).unwrap();
+        let colour =
+            |name| colours.colour(&colours.resolve(&AnvilBlock::named(name)).unwrap(), None);
+        for (block, name) in [
+            (IVec3::new(519, 0, -780), "minecraft:bedrock"),
+            (IVec3::new(519, 30, -780), "minecraft:stone"),
+            (IVec3::new(519, 63, -780), "minecraft:sand"),
+            (IVec3::new(519, 63, -700), "minecraft:sand"),
+        ] {
+            assert_eq!(
+                brickmap.get_voxel(block - window.origin).unwrap(),
+                colour(name),
+                "{}",
+                block
+            );
+        }
+        for air in [IVec3::new(519, -1, -780), IVec3::new(519, 64, -780)] {
+            assert_eq!(brickmap.get_voxel(air - window.origin).unwrap(), [0; 4], "{}", air);
+        }
+    }
+}
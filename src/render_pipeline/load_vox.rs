@@ -0,0 +1,186 @@
+use super::cpu_brickmap::CpuBrickmap;
+use bevy::prelude::*;
+use dot_vox::{DotVoxData, SceneNode};
+use std::path::Path;
+
+/// A rigid transform made of quarter turns, so voxels land exactly on other
+/// voxels.
+#[derive(Clone, Copy, Debug)]
+struct VoxTransform {
+    rotation: Mat3,
+    translation: Vec3,
+}
+
+impl VoxTransform {
+    const IDENTITY: Self = Self {
+        rotation: Mat3::IDENTITY,
+        translation: Vec3::ZERO,
+    };
+
+    fn then(self, child: Self) -> Self {
+        Self {
+            rotation: self.rotation * child.rotation,
+            translation: self.rotation * child.translation + self.translation,
+        }
+    }
+
+    /// the voxel whose centre `centre` is moved to
+    fn voxel(&self, centre: Vec3) -> IVec3 {
+        (self.rotation * centre + self.translation)
+            .floor()
+            .as_ivec3()
+    }
+}
+
+/// snaps `rotation` to the nearest quarter turns
+fn snap_rotation(rotation: Quat) -> Result<Mat3, String> {
+    let matrix = Mat3::from_quat(rotation);
+    let snap = |axis: Vec3| {
+        let i = axis.abs().max_element();
+        Vec3::select(axis.abs().cmpeq(Vec3::splat(i)), axis.signum(), Vec3::ZERO)
+    };
+    let snapped = Mat3::from_cols(
+        snap(matrix.x_axis),
+        snap(matrix.y_axis),
+        snap(matrix.z_axis),
+    );
+    if snapped.determinant().abs() != 1.0 {
+        return Err(format!("can't snap {} to quarter turns", rotation));
+    }
+    Ok(snapped)
+}
+
+pub fn load_vox(path: &Path) -> Result<DotVoxData, String> {
+    let path_str = path
+        .to_str()
+        .ok_or_else(|| format!("{} isn't valid utf8", path.display()))?;
+    dot_vox::load(path_str).map_err(|e| format!("failed to load {}: {}", path.display(), e))
+}
+
+/// Every voxel of a `.vox` scene in world space. Magicavoxel is z up, the
+/// scene is turned to be y up, rotated by `rotation` (snapped to quarter
+/// turns) around its origin, then moved to `offset`. Hidden nodes and layers
+/// are left out.
+pub fn vox_voxels(
+    vox: &DotVoxData,
+    offset: IVec3,
+    rotation: Quat,
+) -> Result<Vec<(IVec3, [u8; 4])>, String> {
+    let palette = vox
+        .palette
+        .iter()
+        .map(|colour| [colour.r, colour.g, colour.b, colour.a])
+        .collect::<Vec<_>>();
+
+    // z up to y up
+    let z_up = Mat3::from_cols(Vec3::X, -Vec3::Z, Vec3::Y);
+    let root = VoxTransform {
+        rotation: snap_rotation(rotation)? * z_up,
+        translation: offset.as_vec3(),
+    };
+
+    let mut shapes = Vec::new();
+    if vox.scenes.is_empty() {
+        // files from before the scene graph only have models
+        shapes.extend((0..vox.models.len()).map(|model| (model as u32, root)));
+    } else {
+        collect_shapes(vox, 0, root, 0, &mut shapes)?;
+    }
+
+    let mut voxels = Vec::new();
+    for (model_id, transform) in shapes {
+        let model = vox
+            .models
+            .get(model_id as usize)
+            .ok_or_else(|| format!("shape references missing model {}", model_id))?;
+        let pivot = (UVec3::new(model.size.x, model.size.y, model.size.z) / 2).as_vec3();
+        for voxel in model.voxels.iter() {
+            let centre = Vec3::new(voxel.x as f32, voxel.y as f32, voxel.z as f32) + 0.5 - pivot;
+            let colour = palette.get(voxel.i as usize).copied().unwrap_or([255; 4]);
+            voxels.push((transform.voxel(centre), colour));
+        }
+    }
+
+    Ok(voxels)
+}
+
+/// walks the scene graph from `node`, collecting every model with the
+/// transform it's drawn with
+fn collect_shapes(
+    vox: &DotVoxData,
+    node: u32,
+    transform: VoxTransform,
+    depth: usize,
+    shapes: &mut Vec<(u32, VoxTransform)>,
+) -> Result<(), String> {
+    if depth > vox.scenes.len() {
+        return Err("scene graph has a cycle".into());
+    }
+    let scene_node = vox
+        .scenes
+        .get(node as usize)
+        .ok_or_else(|| format!("scene references missing node {}", node))?;
+
+    match scene_node {
+        SceneNode::Transform {
+            attributes,
+            frames,
+            child,
+            layer_id,
+        } => {
+            let layer_hidden = vox
+                .layers
+                .get(*layer_id as usize)
+                .is_some_and(|layer| layer.hidden());
+            if layer_hidden || attributes.get("_hidden").is_some_and(|v| v == "1") {
+                return Ok(());
+            }
+
+            let local = match frames.first() {
+                Some(frame) => VoxTransform {
+                    rotation: frame.orientation().map_or(Mat3::IDENTITY, |r| {
+                        Mat3::from_cols_array_2d(&r.to_cols_array_2d())
+                    }),
+                    translation: frame.position().map_or(Vec3::ZERO, |p| {
+                        Vec3::new(p.x as f32, p.y as f32, p.z as f32)
+                    }),
+                },
+                None => VoxTransform::IDENTITY,
+            };
+            collect_shapes(vox, *child, transform.then(local), depth + 1, shapes)
+        }
+        SceneNode::Group {
+            attributes,
+            children,
+        } => {
+            if attributes.get("_hidden").is_some_and(|v| v == "1") {
+                return Ok(());
+            }
+            for child in children {
+                collect_shapes(vox, *child, transform, depth + 1, shapes)?;
+            }
+            Ok(())
+        }
+        SceneNode::Shape { models, .. } => {
+            shapes.extend(models.iter().map(|model| (model.model_id, transform)));
+            Ok(())
+        }
+    }
+}
+
+/// Places a `.vox` scene into `brickmap`, see `vox_voxels`. Voxels outside of
+/// the brickmap are clipped. Returns the nodes whose bricks changed and how
+/// many voxels were clipped.
+pub fn place_vox(
+    brickmap: &mut CpuBrickmap,
+    vox: &DotVoxData,
+    offset: IVec3,
+    rotation: Quat,
+) -> Result<(Vec<usize>, usize), String> {
+    let mut voxels = vox_voxels(vox, offset, rotation)?;
+    let count = voxels.len();
+    voxels.retain(|(pos, _)| brickmap.voxel_pos(*pos).is_some());
+    let clipped = count - voxels.len();
+    let changed = brickmap.set_voxels(voxels)?;
+    Ok((changed, clipped))
+}
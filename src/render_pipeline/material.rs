@@ -0,0 +1,123 @@
+use bytemuck::{Pod, Zeroable};
+
+pub const DEFAULT_MATERIAL: u8 = 0;
+pub const WATER: u8 = 1;
+pub const LAVA: u8 = 2;
+pub const GLASS: u8 = 3;
+pub const ICE: u8 = 4;
+pub const FOLIAGE: u8 = 5;
+pub const PLANT: u8 = 6;
+pub const LIGHT: u8 = 7;
+
+/// Every material a voxel can have, indexed by the material stored in its
+/// brick.
+pub const MATERIALS: [Material; 8] = [
+    Material::SOLID,
+    Material {
+        roughness: 0.05,
+        transparency: Transparency::Blend,
+        liquid: true,
+        collision: false,
+        ..Material::SOLID
+    },
+    Material {
+        emissive: 2.0,
+        roughness: 0.6,
+        liquid: true,
+        collision: false,
+        ..Material::SOLID
+    },
+    Material {
+        roughness: 0.05,
+        transparency: Transparency::Blend,
+        ..Material::SOLID
+    },
+    Material {
+        roughness: 0.1,
+        transparency: Transparency::Blend,
+        ..Material::SOLID
+    },
+    Material {
+        transparency: Transparency::Cutout,
+        ..Material::SOLID
+    },
+    Material {
+        transparency: Transparency::Cutout,
+        collision: false,
+        ..Material::SOLID
+    },
+    Material {
+        emissive: 1.5,
+        ..Material::SOLID
+    },
+];
+
+/// How light passes through a voxel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transparency {
+    /// blocks light whatever its alpha
+    Opaque,
+    /// hit or missed by its alpha, like leaves
+    Cutout,
+    /// blended with what's behind it by its alpha, like water and glass
+    Blend,
+}
+
+/// What a voxel is made of beyond its colour, for lighting and gameplay.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Material {
+    /// light given off, as a multiple of the voxel's colour
+    pub emissive: f32,
+    /// 0 is a mirror, 1 is fully diffuse
+    pub roughness: f32,
+    pub transparency: Transparency,
+    /// can be swum through and flows
+    pub liquid: bool,
+    /// stops the player and physics objects
+    pub collision: bool,
+}
+
+impl Material {
+    /// plain opaque blocks, what voxels without a material are made of
+    pub const SOLID: Self = Self {
+        emissive: 0.0,
+        roughness: 1.0,
+        transparency: Transparency::Opaque,
+        liquid: false,
+        collision: true,
+    };
+
+    /// the material with index `material`, unknown indices are solid
+    pub fn get(material: u8) -> &'static Self {
+        MATERIALS
+            .get(material as usize)
+            .unwrap_or(&MATERIALS[DEFAULT_MATERIAL as usize])
+    }
+}
+
+/// A material as the shaders read it from the material table.
+#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+#[repr(C)]
+pub struct GpuMaterial {
+    pub emissive: f32,
+    pub roughness: f32,
+    /// 0 opaque, 1 cutout, 2 blend
+    pub transparency: u32,
+    /// bit 0 liquid, bit 1 collision
+    pub flags: u32,
+}
+
+impl From<&Material> for GpuMaterial {
+    fn from(material: &Material) -> Self {
+        Self {
+            emissive: material.emissive,
+            roughness: material.roughness,
+            transparency: match material.transparency {
+                Transparency::Opaque => 0,
+                Transparency::Cutout => 1,
+                Transparency::Blend => 2,
+            },
+            flags: material.liquid as u32 | (material.collision as u32) << 1,
+        }
+    }
+}
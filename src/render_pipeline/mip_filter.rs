@@ -0,0 +1,165 @@
+use bevy::prelude::*;
+use std::{fmt, str::FromStr, sync::Arc};
+
+/// a filter combining 8 child voxels into their mip voxel
+pub type MipFilterFn = dyn Fn(&[[u8; 4]; 8]) -> [u8; 4] + Send + Sync;
+
+/// How 8 child voxels are combined into one voxel of the mip above them.
+/// Set `VoxelWorldConfig::mip_filter` before the world is built to pick the
+/// filter used for that world.
+#[derive(Clone, Default)]
+pub enum MipFilter {
+    /// alpha weighted colour average, alpha is the mean of the children
+    #[default]
+    Average,
+    /// the most common child colour. ties go to the more opaque colour
+    Majority,
+    /// alpha weighted colour average, alpha is the most opaque child so
+    /// thin features like fences and torches stay solid in the distance
+    MaxOpacity,
+    /// a filter from code. it's only reachable through the api, it prints as
+    /// `Custom(..)` and can't be parsed back, so `alex-convert --mip-filter`
+    /// can't pick it
+    Custom(Arc<MipFilterFn>),
+}
+
+impl MipFilter {
+    pub fn custom(f: impl Fn(&[[u8; 4]; 8]) -> [u8; 4] + Send + Sync + 'static) -> Self {
+        Self::Custom(Arc::new(f))
+    }
+
+    pub fn apply(&self, children: &[[u8; 4]; 8]) -> [u8; 4] {
+        match self {
+            Self::Average => {
+                let (colour, total_alpha) = weighted_colour(children);
+                [
+                    colour.x as u8,
+                    colour.y as u8,
+                    colour.z as u8,
+                    (total_alpha / 8.0) as u8,
+                ]
+            }
+            Self::Majority => {
+                let mut best = children[0];
+                let mut best_count = 0;
+                for (i, colour) in children.iter().enumerate() {
+                    if children[..i].contains(colour) {
+                        continue;
+                    }
+                    let count = children[i..].iter().filter(|c| *c == colour).count();
+                    if count > best_count || count == best_count && colour[3] > best[3] {
+                        best = *colour;
+                        best_count = count;
+                    }
+                }
+                best
+            }
+            Self::MaxOpacity => {
+                let (colour, _) = weighted_colour(children);
+                let alpha = children.iter().map(|c| c[3]).max().unwrap_or(0);
+                [colour.x as u8, colour.y as u8, colour.z as u8, alpha]
+            }
+            Self::Custom(f) => f(children),
+        }
+    }
+}
+
+/// sums the children's colour weighted by alpha. returns the weighted
+/// average colour, black if every child is transparent, and the total alpha
+fn weighted_colour(children: &[[u8; 4]; 8]) -> (Vec3, f32) {
+    let mut colour = Vec3::ZERO;
+    let mut total_alpha = 0.0;
+    for child_colour in children {
+        let alpha = child_colour[3] as f32;
+        let child_colour = Vec3::new(
+            child_colour[0] as f32,
+            child_colour[1] as f32,
+            child_colour[2] as f32,
+        );
+
+        colour += child_colour * alpha;
+        total_alpha += alpha;
+    }
+    // fully transparent cells have no colour to average
+    if total_alpha > 0.0 {
+        colour /= total_alpha;
+    }
+
+    (colour, total_alpha)
+}
+
+impl fmt::Debug for MipFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Average => write!(f, "Average"),
+            Self::Majority => write!(f, "Majority"),
+            Self::MaxOpacity => write!(f, "MaxOpacity"),
+            Self::Custom(_) => write!(f, "Custom(..)"),
+        }
+    }
+}
+
+impl FromStr for MipFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "average" => Ok(Self::Average),
+            "majority" => Ok(Self::Majority),
+            "max-opacity" => Ok(Self::MaxOpacity),
+            _ => Err(format!(
+                "unknown mip filter {:?}, expected average, majority or max-opacity",
+                s
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILTERS: [MipFilter; 3] = [
+        MipFilter::Average,
+        MipFilter::Majority,
+        MipFilter::MaxOpacity,
+    ];
+
+    #[test]
+    fn transparent_children_give_a_transparent_mip() {
+        for children in [[[0; 4]; 8], [[255, 40, 0, 0]; 8]] {
+            for filter in FILTERS {
+                assert_eq!(filter.apply(&children)[3], 0, "{:?}", filter);
+            }
+            // there's no colour to average
+            assert_eq!(MipFilter::Average.apply(&children), [0; 4]);
+            assert_eq!(MipFilter::MaxOpacity.apply(&children), [0; 4]);
+        }
+    }
+
+    #[test]
+    fn majority_ties_go_to_the_more_opaque_colour() {
+        let (glass, stone) = ([10, 20, 30, 100], [90, 90, 90, 255]);
+        let mut children = [glass, glass, glass, glass, stone, stone, stone, stone];
+        assert_eq!(MipFilter::Majority.apply(&children), stone);
+        children.reverse();
+        assert_eq!(MipFilter::Majority.apply(&children), stone);
+
+        // a clear majority still wins over opacity
+        children[0] = glass;
+        assert_eq!(MipFilter::Majority.apply(&children), glass);
+    }
+
+    #[test]
+    fn max_opacity_keeps_thin_features_opaque() {
+        let torch = [250, 180, 60, 255];
+        let mut children = [[0; 4]; 8];
+        children[5] = torch;
+        assert_eq!(MipFilter::MaxOpacity.apply(&children), torch);
+        assert_eq!(MipFilter::Average.apply(&children), [250, 180, 60, 31]);
+
+        // the colour is still averaged over the visible children
+        children[2] = [50, 80, 60, 255];
+        assert_eq!(MipFilter::MaxOpacity.apply(&children), [150, 130, 60, 255]);
+    }
+}
@@ -1,4 +1,18 @@
-pub use self::{voxel_streaming::StreamingSettings, voxel_world::VoxelWorldStatsResource};
+pub use self::{
+    cpu_brickmap::{Brick, CpuBrickmap},
+    load_anvil::{load_anvil, AnvilReport, AnvilWindow, RegionStatus},
+    load_vox::{load_vox, place_vox, vox_voxels},
+    material::{Material, Transparency, MATERIALS},
+    mip_filter::{MipFilter, MipFilterFn},
+    save_vox::{export_vox, save_vox},
+    solid_voxels::SolidVoxels,
+    voxel_streaming::StreamingSettings,
+    voxel_world::{
+        CpuVoxelWorld, CpuVoxelWorldResource, HeightmapSettings, VoxelWorldConfig, VoxelWorldStatsResource,
+        WorldSource,
+    },
+    world_builder::{empty_brickmap, load_world, procedural_bricks},
+};
 
 use self::{
     voxel_render::VoxelRenderPlugin, voxel_streaming::VoxelStreamingPlugin,
@@ -12,10 +26,20 @@ use bevy::{
     },
 };
 
+mod block_colours;
+mod brick_store;
+mod brickmap_file;
 mod cpu_brickmap;
 mod gpu_brickmap;
 mod height_mapper;
 mod load_anvil;
+mod load_vox;
+mod material;
+mod mip_filter;
+mod palette;
+mod raycast;
+mod save_vox;
+mod solid_voxels;
 mod voxel_render;
 mod voxel_streaming;
 mod voxel_world;
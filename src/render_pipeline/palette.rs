@@ -0,0 +1,162 @@
+use bevy::utils::HashMap;
+use serde::Deserialize;
+use std::{collections::BTreeSet, path::Path, sync::Mutex};
+
+const BLOCKSTATES_PATH: &str = "assets/palette/blockstates.json";
+
+/// colour of blocks missing from the palette, unless the overrides set one
+const DEFAULT_FALLBACK: [u8; 4] = [200, 200, 200, 127];
+
+/// What an override file holds, as TOML or JSON:
+///
+/// ```toml
+/// fallback = [200, 200, 200, 127]
+///
+/// [colours]
+/// "minecraft:grass_block" = [62, 204, 18, 255]
+/// "minecraft:oak_log|axis=y" = [150, 116, 65, 255]
+/// "minecraft:*_leaves" = [40, 110, 30, 230]
+/// ```
+///
+/// Keys are a block name, a block state as blockstates.json writes it, or
+/// either with `*` standing for any run of characters. An alpha of 0 hides
+/// the block.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct PaletteOverrides {
+    fallback: Option<[u8; 4]>,
+    colours: HashMap<String, [u8; 4]>,
+}
+
+/// Colours of Minecraft blocks, from blockstates.json with a user's overrides
+/// on top. Blocks that aren't found are remembered so the import can report
+/// them.
+pub struct Palette {
+    /// colours by block state, `name|key=value,...`, or by name for blocks
+    /// without properties
+    blockstates: HashMap<String, [u8; 4]>,
+    /// a colour for each block name, for states missing from blockstates.json
+    names: HashMap<String, [u8; 4]>,
+    /// overrides by block name or state
+    overrides: HashMap<String, [u8; 4]>,
+    /// overrides with a `*`, most specific first
+    patterns: Vec<(String, [u8; 4])>,
+    fallback: [u8; 4],
+    unknown: Mutex<BTreeSet<String>>,
+}
+
+impl Palette {
+    /// loads blockstates.json, then the overrides in `overrides` if given.
+    /// the file is read as TOML or JSON by its extension
+    pub fn load(overrides: Option<&Path>) -> Result<Self, String> {
+        let file = std::fs::File::open(BLOCKSTATES_PATH)
+            .map_err(|e| format!("{}: {}", BLOCKSTATES_PATH, e))?;
+        let blockstates: HashMap<String, [u8; 4]> = serde_json::from_reader(file)
+            .map_err(|e| format!("failed to parse {}: {}", BLOCKSTATES_PATH, e))?;
+
+        // sorted so every load picks the same state for a name
+        let mut keys = blockstates.keys().collect::<Vec<_>>();
+        keys.sort_unstable();
+        let mut names = HashMap::new();
+        for key in keys {
+            let name = key.split('|').next().unwrap_or(key);
+            names.entry(name.to_string()).or_insert(blockstates[key]);
+        }
+
+        let PaletteOverrides { fallback, colours } = match overrides {
+            Some(path) => load_overrides(path)?,
+            None => PaletteOverrides::default(),
+        };
+        let (mut patterns, overrides): (Vec<_>, Vec<_>) =
+            colours.into_iter().partition(|(key, _)| key.contains('*'));
+        // the pattern with the most literal characters wins, ties go by name
+        // so the order doesn't depend on the file
+        patterns.sort_unstable_by(|(a, _), (b, _)| {
+            let literal = |pattern: &str| pattern.chars().filter(|c| *c != '*').count();
+            literal(b).cmp(&literal(a)).then_with(|| a.cmp(b))
+        });
+
+        Ok(Self {
+            blockstates,
+            names,
+            overrides: overrides.into_iter().collect(),
+            patterns,
+            fallback: fallback.unwrap_or(DEFAULT_FALLBACK),
+            unknown: Mutex::new(BTreeSet::new()),
+        })
+    }
+
+    /// the user's colour for a block, which replaces whatever the block would
+    /// be drawn with. `state` is the block's `name|key=value,...` key
+    pub fn override_colour(&self, name: &str, state: &str) -> Option<[u8; 4]> {
+        self.overrides
+            .get(state)
+            .or_else(|| self.overrides.get(name))
+            .or_else(|| {
+                self.patterns
+                    .iter()
+                    .find(|(pattern, _)| matches(pattern, state) || matches(pattern, name))
+                    .map(|(_, colour)| colour)
+            })
+            .copied()
+    }
+
+    /// the colour of a block's state in blockstates.json, or of another state
+    /// of the same block
+    pub fn base_colour(&self, name: &str, state: &str) -> Option<[u8; 4]> {
+        self.blockstates
+            .get(state)
+            .or_else(|| self.names.get(name))
+            .copied()
+    }
+
+    /// the fallback colour, recording `name` as a block the palette doesn't
+    /// know
+    pub fn unknown(&self, name: &str) -> [u8; 4] {
+        let mut unknown = self.unknown.lock().unwrap();
+        if !unknown.contains(name) {
+            unknown.insert(name.to_string());
+        }
+        self.fallback
+    }
+
+    /// every block drawn with the fallback colour so far, by name
+    pub fn unknown_blocks(&self) -> Vec<String> {
+        self.unknown.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+fn load_overrides(path: &Path) -> Result<PaletteOverrides, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_ascii_lowercase);
+    match extension.as_deref() {
+        Some("toml") => toml::from_str(&text).map_err(|e| e.to_string()),
+        Some("json") => serde_json::from_str(&text).map_err(|e| e.to_string()),
+        _ => Err("expected a .toml or .json file".into()),
+    }
+    .map_err(|e| format!("failed to load palette overrides {}: {}", path.display(), e))
+}
+
+/// whether `text` matches `pattern`, where `*` matches any run of characters
+fn matches(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+    let parts = parts.collect::<Vec<_>>();
+    let Some((last, middle)) = parts.split_last() else {
+        // no `*` at all
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
@@ -0,0 +1,273 @@
+use super::{cpu_brickmap::CpuBrickmap, BRICK_SIZE};
+use bevy::prelude::*;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RaycastHit {
+    /// centred position of the voxel that was hit. at a lower lod this is
+    /// the minimum corner of the mip cell
+    pub voxel: IVec3,
+    /// side of the voxel the ray entered through, zero if the ray started
+    /// inside it
+    pub normal: IVec3,
+    /// distance along the ray in voxels
+    pub distance: f32,
+    pub colour: [u8; 4],
+}
+
+impl CpuBrickmap {
+    /// Marches a ray in centred voxel coordinates through the brickmap,
+    /// stepping over empty nodes whole and running a DDA through the bricks
+    /// in between. `lod` caps the depth the tree is descended to, so a lower
+    /// lod hits the mips instead of the leaves.
+    pub fn raycast(
+        &self,
+        origin: Vec3,
+        direction: Vec3,
+        max_distance: f32,
+        lod: Option<u32>,
+    ) -> Result<Option<RaycastHit>, String> {
+        let Some(direction) = direction.try_normalize() else {
+            return Ok(None);
+        };
+        let size = self.size() as i32;
+        let half = (size / 2) as f32;
+        let origin = origin + half;
+
+        let step = direction.signum().as_ivec3();
+        let inv_dir = direction.recip();
+        // distance along the ray to the far side of a box on each axis
+        let exit = |min: IVec3, max: IVec3| {
+            let bound = IVec3::select(direction.cmpgt(Vec3::ZERO), max + 1, min).as_vec3();
+            let t = (bound - origin) * inv_dir;
+            Vec3::select(direction.cmpeq(Vec3::ZERO), Vec3::INFINITY, t)
+        };
+        // the voxel the ray is in at `t`, clamped so rounding can't put it
+        // outside of the box it's known to be in
+        let voxel_at = |t: f32, min: IVec3, max: IVec3| {
+            (origin + direction * t).floor().as_ivec3().clamp(min, max)
+        };
+
+        // clip the ray to the world
+        let world_max = IVec3::splat(size - 1);
+        let t_enter = (IVec3::ZERO.as_vec3() - origin) * inv_dir;
+        let t_enter = Vec3::select(
+            direction.cmpgt(Vec3::ZERO),
+            t_enter,
+            (Vec3::splat(size as f32) - origin) * inv_dir,
+        );
+        let t_enter = Vec3::select(direction.cmpeq(Vec3::ZERO), Vec3::NEG_INFINITY, t_enter);
+        let t_exit = exit(IVec3::ZERO, world_max);
+        if direction.cmpeq(Vec3::ZERO).any() {
+            let outside = origin.cmplt(Vec3::ZERO) | origin.cmpge(Vec3::splat(size as f32));
+            if (outside & direction.cmpeq(Vec3::ZERO)).any() {
+                return Ok(None);
+            }
+        }
+
+        let mut t = t_enter.max_element().max(0.0);
+        if t > t_exit.min_element() || t > max_distance {
+            return Ok(None);
+        }
+        let mut normal = IVec3::ZERO;
+        if t > 0.0 {
+            let axis = max_axis(t_enter);
+            normal[axis] = -step[axis];
+        }
+        let mut voxel = voxel_at(t, IVec3::ZERO, world_max);
+
+        while voxel.cmpge(IVec3::ZERO).all() && voxel.cmple(world_max).all() {
+            if t > max_distance {
+                return Ok(None);
+            }
+
+            let (index, node_pos, depth) = self.get_node(voxel.as_uvec3() / BRICK_SIZE, lod);
+            let scale = 1 << (self.brickmap_depth - depth);
+            let node_min = (node_pos * BRICK_SIZE).as_ivec3();
+            let node_max = node_min + (BRICK_SIZE * scale) as i32 - 1;
+
+            let brick_index = self.brickmap[index].brick as usize;
+            if brick_index == 0 {
+                // skip the whole node
+                let t_node = exit(node_min, node_max);
+                let axis = min_axis(t_node);
+                t = t_node[axis];
+                normal = IVec3::ZERO;
+                normal[axis] = -step[axis];
+
+                let mut min = node_min;
+                let mut max = node_max;
+                let next = if step[axis] > 0 {
+                    node_max[axis] + 1
+                } else {
+                    node_min[axis] - 1
+                };
+                min[axis] = next;
+                max[axis] = next;
+                voxel = voxel_at(t, min, max);
+                continue;
+            }
+
+            let brick = self.read_brick(brick_index)?;
+            let scale = scale as i32;
+            let mut cell = (voxel - node_min) / scale;
+            let mut t_max = exit(node_min + cell * scale, node_min + cell * scale + scale - 1);
+            let t_delta = (Vec3::splat(scale as f32) * inv_dir).abs();
+            loop {
+                let colour = brick.get(cell.as_uvec3());
+                if colour[3] != 0 {
+                    if t > max_distance {
+                        return Ok(None);
+                    }
+                    return Ok(Some(RaycastHit {
+                        voxel: node_min + cell * scale - size / 2,
+                        normal,
+                        distance: t,
+                        colour,
+                    }));
+                }
+
+                let axis = min_axis(t_max);
+                t = t_max[axis];
+                t_max[axis] += t_delta[axis];
+                cell[axis] += step[axis];
+                normal = IVec3::ZERO;
+                normal[axis] = -step[axis];
+
+                let cell_min = node_min + cell * scale;
+                voxel = voxel_at(t, cell_min, cell_min + scale - 1);
+                if !(0..BRICK_SIZE as i32).contains(&cell[axis]) || t > max_distance {
+                    break;
+                }
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+fn min_axis(v: Vec3) -> usize {
+    if v.x <= v.y && v.x <= v.z {
+        0
+    } else if v.y <= v.z {
+        1
+    } else {
+        2
+    }
+}
+
+fn max_axis(v: Vec3) -> usize {
+    min_axis(-v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::{collection::vec, prelude::*};
+    use std::collections::HashMap;
+
+    const DEPTH: u32 = 3;
+    const HALF: i32 = (BRICK_SIZE << DEPTH) as i32 / 2;
+
+    /// distances along the ray where it enters and leaves the voxel
+    fn slab(origin: Vec3, direction: Vec3, voxel: IVec3) -> Option<(f32, f32)> {
+        let (mut near, mut far) = (0.0f32, f32::INFINITY);
+        for axis in 0..3 {
+            let min = voxel[axis] as f32;
+            if direction[axis] == 0.0 {
+                if origin[axis] < min || origin[axis] >= min + 1.0 {
+                    return None;
+                }
+                continue;
+            }
+            let t0 = (min - origin[axis]) / direction[axis];
+            let t1 = (min + 1.0 - origin[axis]) / direction[axis];
+            near = near.max(t0.min(t1));
+            far = far.min(t0.max(t1));
+        }
+        (near <= far).then_some((near, far))
+    }
+
+    fn component() -> impl Strategy<Value = f32> {
+        prop_oneof![1 => Just(0.0), 3 => -1.0f32..1.0]
+    }
+
+    /// checks the raycast against testing the ray against every voxel
+    fn check_ray(
+        brickmap: &CpuBrickmap,
+        voxels: &HashMap<IVec3, [u8; 4]>,
+        origin: Vec3,
+        direction: Vec3,
+        max_distance: f32,
+    ) -> Result<(), TestCaseError> {
+        let hit = brickmap
+            .raycast(origin, direction, max_distance, None)
+            .unwrap();
+
+        // the nearest voxel the ray passes properly through, rays grazing an
+        // edge may or may not hit it
+        let nearest = voxels
+            .keys()
+            .filter_map(|voxel| slab(origin, direction, *voxel))
+            .filter(|(near, far)| far - near > 1e-3)
+            .map(|(near, _)| near)
+            .fold(f32::INFINITY, f32::min);
+
+        let Some(hit) = hit else {
+            prop_assert!(nearest >= max_distance - 1e-3);
+            return Ok(());
+        };
+        prop_assert_eq!(voxels.get(&hit.voxel), Some(&hit.colour));
+        let (near, _) = slab(origin, direction, hit.voxel).unwrap();
+        prop_assert!((near - hit.distance).abs() < 1e-3);
+        prop_assert!(hit.distance <= nearest + 1e-3);
+        prop_assert!(hit.distance <= max_distance);
+
+        // the normal is the face the ray entered through
+        if hit.distance == 0.0 {
+            prop_assert_eq!(hit.normal, IVec3::ZERO);
+            return Ok(());
+        }
+        prop_assert_eq!(hit.normal.abs().element_sum(), 1);
+        let axis = (0..3).find(|axis| hit.normal[*axis] != 0).unwrap();
+        prop_assert_eq!(hit.normal[axis], -direction[axis].signum() as i32);
+        let face = hit.voxel[axis] + (hit.normal[axis] > 0) as i32;
+        let point = origin + direction * hit.distance;
+        prop_assert!((point[axis] - face as f32).abs() < 1e-3);
+        Ok(())
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn matches_brute_force(
+            scattered in vec(((-HALF..HALF, -HALF..HALF, -HALF..HALF), 1..=255u8), 0..300),
+            boxes in vec(((-HALF..HALF - 8, -HALF..HALF - 8, -HALF..HALF - 8), 0..8), 0..4),
+            rays in vec((
+                (-80.0f32..80.0, -80.0f32..80.0, -80.0f32..80.0),
+                (component(), component(), component()),
+                prop_oneof![Just(f32::INFINITY), 0.0f32..300.0],
+            ), 16),
+        ) {
+            let mut brickmap = CpuBrickmap::new(DEPTH);
+            for ((x, y, z), size) in boxes {
+                let min = IVec3::new(x, y, z);
+                brickmap.fill_box(min, min + size, [200, 100, 50, 255]).unwrap();
+            }
+            brickmap
+                .set_voxels(scattered.into_iter().map(|((x, y, z), alpha)| {
+                    (IVec3::new(x, y, z), [x as u8, y as u8, z as u8, alpha])
+                }))
+                .unwrap();
+            let voxels = brickmap.voxels().unwrap().into_iter().collect::<HashMap<_, _>>();
+
+            for ((ox, oy, oz), (dx, dy, dz), max_distance) in rays {
+                let origin = Vec3::new(ox, oy, oz);
+                let Some(direction) = Vec3::new(dx, dy, dz).try_normalize() else {
+                    continue;
+                };
+                check_ray(&brickmap, &voxels, origin, direction, max_distance)?;
+            }
+        }
+    }
+}
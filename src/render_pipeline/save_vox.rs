@@ -0,0 +1,247 @@
+use super::cpu_brickmap::CpuBrickmap;
+use bevy::prelude::*;
+use dot_vox::{Color, Dict, DotVoxData, Frame, Layer, Model, SceneNode, ShapeModel, Size, Voxel};
+use std::{collections::HashMap, fs::File, io::BufWriter, path::Path};
+
+/// largest model magicavoxel can open, along each axis
+const MAX_MODEL_SIZE: i32 = 256;
+/// palette index 0 is empty, leaving 255 colours
+const PALETTE_SIZE: usize = 255;
+
+/// Copies the inclusive box `min..=max` of `brickmap` into a `.vox` scene.
+/// Colours are quantised to 255 and boxes bigger than 256 voxels along an
+/// axis are split into several models. Importing the scene with `vox_voxels`
+/// at `offset = min` puts every voxel back where it came from.
+pub fn export_vox(brickmap: &CpuBrickmap, min: IVec3, max: IVec3) -> Result<DotVoxData, String> {
+    let (min, max) = (min.min(max), min.max(max));
+
+    // read the box one model at a time
+    let mut models = Vec::new();
+    let mut histogram = HashMap::new();
+    let size = max - min + 1;
+    for x in (0..size.x).step_by(MAX_MODEL_SIZE as usize) {
+        for y in (0..size.y).step_by(MAX_MODEL_SIZE as usize) {
+            for z in (0..size.z).step_by(MAX_MODEL_SIZE as usize) {
+                let model_min = IVec3::new(x, y, z);
+                let model_size = (size - model_min).min(IVec3::splat(MAX_MODEL_SIZE));
+                let voxels =
+                    brickmap.read_box(min + model_min, min + model_min + model_size - 1)?;
+
+                let mut opaque = Vec::new();
+                for (i, colour) in voxels.into_iter().enumerate() {
+                    if colour[3] == 0 {
+                        continue;
+                    }
+                    let i = i as i32;
+                    let pos = IVec3::new(
+                        i % model_size.x,
+                        i / model_size.x % model_size.y,
+                        i / (model_size.x * model_size.y),
+                    );
+                    *histogram.entry(colour).or_insert(0usize) += 1;
+                    opaque.push((pos, colour));
+                }
+                if !opaque.is_empty() {
+                    models.push((model_min, model_size, opaque));
+                }
+            }
+        }
+    }
+    if models.is_empty() {
+        return Err(format!("box {}..={} is empty", min, max));
+    }
+
+    let palette = quantise(&histogram, PALETTE_SIZE);
+    let lookup = histogram
+        .keys()
+        .map(|colour| (*colour, nearest(&palette, *colour)))
+        .collect::<HashMap<_, _>>();
+
+    // y up to z up, the inverse of the turn in `vox_voxels`. a box of y up
+    // voxels `min..min + size` covers `(x, -z - size.z, y)` in z up voxels
+    let mut vox_models = Vec::new();
+    let mut children = Vec::new();
+    let mut scenes = vec![
+        SceneNode::Transform {
+            attributes: Dict::new(),
+            frames: vec![Frame::new(Dict::new())],
+            child: 1,
+            layer_id: u32::MAX,
+        },
+        SceneNode::Group {
+            attributes: Dict::new(),
+            children: Vec::new(),
+        },
+    ];
+    for (model_min, model_size, voxels) in models {
+        let vox_size = UVec3::new(
+            model_size.x as u32,
+            model_size.z as u32,
+            model_size.y as u32,
+        );
+        let vox_min = IVec3::new(model_min.x, -model_min.z - model_size.z, model_min.y);
+        // magicavoxel positions models by their centre, rounded down
+        let translation = vox_min + (vox_size / 2).as_ivec3();
+
+        let mut frame = Dict::new();
+        frame.insert(
+            "_t".to_string(),
+            format!("{} {} {}", translation.x, translation.y, translation.z),
+        );
+        children.push(scenes.len() as u32);
+        scenes.push(SceneNode::Transform {
+            attributes: Dict::new(),
+            frames: vec![Frame::new(frame)],
+            child: scenes.len() as u32 + 1,
+            layer_id: 0,
+        });
+        scenes.push(SceneNode::Shape {
+            attributes: Dict::new(),
+            models: vec![ShapeModel {
+                model_id: vox_models.len() as u32,
+                attributes: Dict::new(),
+            }],
+        });
+
+        vox_models.push(Model {
+            size: Size {
+                x: vox_size.x,
+                y: vox_size.y,
+                z: vox_size.z,
+            },
+            voxels: voxels
+                .into_iter()
+                .map(|(pos, colour)| Voxel {
+                    x: pos.x as u8,
+                    y: (model_size.z - 1 - pos.z) as u8,
+                    z: pos.y as u8,
+                    i: lookup[&colour],
+                })
+                .collect(),
+        });
+    }
+    if let SceneNode::Group {
+        children: group, ..
+    } = &mut scenes[1]
+    {
+        *group = children;
+    }
+
+    let mut palette = palette
+        .into_iter()
+        .map(|[r, g, b, a]| Color { r, g, b, a })
+        .collect::<Vec<_>>();
+    palette.resize(
+        256,
+        Color {
+            r: 0,
+            g: 0,
+            b: 0,
+            a: 255,
+        },
+    );
+
+    Ok(DotVoxData {
+        version: 150,
+        models: vox_models,
+        palette,
+        materials: Vec::new(),
+        scenes,
+        layers: vec![Layer {
+            attributes: Dict::new(),
+        }],
+    })
+}
+
+pub fn save_vox(brickmap: &CpuBrickmap, min: IVec3, max: IVec3, path: &Path) -> Result<(), String> {
+    let vox = export_vox(brickmap, min, max)?;
+    let file = File::create(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    vox.write_vox(&mut BufWriter::new(file))
+        .map_err(|e| format!("failed to write {}: {}", path.display(), e))
+}
+
+/// Picks at most `size` colours for the colours in `histogram` by median cut,
+/// weighting each colour by how many voxels use it.
+fn quantise(histogram: &HashMap<[u8; 4], usize>, size: usize) -> Vec<[u8; 4]> {
+    let mut colours = histogram
+        .iter()
+        .map(|(colour, count)| (*colour, *count))
+        .collect::<Vec<_>>();
+    colours.sort_unstable();
+    if colours.len() <= size {
+        return colours.into_iter().map(|(colour, _)| colour).collect();
+    }
+
+    // the channel with the widest range and how wide it is
+    fn widest(colours: &[([u8; 4], usize)]) -> (usize, u8) {
+        (0..4)
+            .map(|channel| {
+                let (min, max) = colours.iter().fold((255, 0), |(min, max), (colour, _)| {
+                    (colour[channel].min(min), colour[channel].max(max))
+                });
+                (channel, max - min)
+            })
+            .max_by_key(|(_, range)| *range)
+            .unwrap()
+    }
+
+    let mut boxes = vec![colours];
+    while boxes.len() < size {
+        let Some((index, channel)) = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, colours)| colours.len() > 1)
+            .map(|(index, colours)| (index, widest(colours)))
+            .max_by_key(|(_, (_, range))| *range)
+            .map(|(index, (channel, _))| (index, channel))
+        else {
+            break;
+        };
+
+        // split at the weighted median
+        let mut colours = boxes.swap_remove(index);
+        colours.sort_unstable_by_key(|(colour, _)| colour[channel]);
+        let total = colours.iter().map(|(_, count)| count).sum::<usize>();
+        let mut seen = 0;
+        let split = colours
+            .iter()
+            .position(|(_, count)| {
+                seen += count;
+                seen * 2 >= total
+            })
+            .unwrap()
+            .clamp(0, colours.len() - 2)
+            + 1;
+        let upper = colours.split_off(split);
+        boxes.push(colours);
+        boxes.push(upper);
+    }
+
+    boxes
+        .iter()
+        .map(|colours| {
+            let total = colours.iter().map(|(_, count)| count).sum::<usize>();
+            let mut sum = [0usize; 4];
+            for (colour, count) in colours {
+                for channel in 0..4 {
+                    sum[channel] += colour[channel] as usize * count;
+                }
+            }
+            sum.map(|channel| ((channel + total / 2) / total) as u8)
+        })
+        .collect()
+}
+
+fn nearest(palette: &[[u8; 4]], colour: [u8; 4]) -> u8 {
+    let distance = |other: &[u8; 4]| {
+        (0..4)
+            .map(|channel| (other[channel] as i32 - colour[channel] as i32).pow(2))
+            .sum::<i32>()
+    };
+    palette
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, other)| distance(other))
+        .map(|(index, _)| index as u8)
+        .unwrap()
+}
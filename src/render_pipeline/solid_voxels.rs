@@ -0,0 +1,120 @@
+use super::{cpu_brickmap::CpuBrickmap, material::Material, BRICK_SIZE};
+use bevy::prelude::*;
+use std::{collections::HashMap, sync::RwLock};
+
+const BRICK_VOXELS: usize = (BRICK_SIZE * BRICK_SIZE * BRICK_SIZE) as usize;
+const BRICK_WORDS: usize = BRICK_VOXELS / 64;
+
+type Mask = Box<[u64; BRICK_WORDS]>;
+
+/// A bitmask of the solid leaf voxels of the world for physics, the voxels
+/// whose material has collision. A brick's mask is built at 1 bit a voxel the
+/// first time it's queried and forgotten when the brick is edited, bricks
+/// without a solid voxel are remembered as `None`.
+#[derive(Default)]
+pub struct SolidVoxels {
+    bricks: RwLock<HashMap<IVec3, Option<Mask>>>,
+}
+
+impl SolidVoxels {
+    /// whether the voxel at a centred world position is solid
+    pub fn is_solid(&self, cpu_brickmap: &CpuBrickmap, pos: IVec3) -> Result<bool, String> {
+        let (brick_pos, bit) = Self::split(pos);
+        let solid = |mask: &Option<Mask>| {
+            mask.as_ref()
+                .is_some_and(|mask| mask[bit / 64] & 1 << (bit % 64) != 0)
+        };
+
+        if let Some(mask) = self.bricks.read().unwrap().get(&brick_pos) {
+            return Ok(solid(mask));
+        }
+        let mask = Self::build_mask(cpu_brickmap, brick_pos)?;
+        let is_solid = solid(&mask);
+        self.bricks.write().unwrap().insert(brick_pos, mask);
+        Ok(is_solid)
+    }
+
+    /// whether any voxel overlapping the box is solid
+    pub fn overlaps(
+        &self,
+        cpu_brickmap: &CpuBrickmap,
+        min: Vec3,
+        max: Vec3,
+    ) -> Result<bool, String> {
+        // boxes touching a voxel, give or take rounding, don't overlap it
+        let min = (min + 1e-4).floor().as_ivec3();
+        let max = (max - 1e-4).floor().as_ivec3();
+        for x in min.x..=max.x {
+            for y in min.y..=max.y {
+                for z in min.z..=max.z {
+                    if self.is_solid(cpu_brickmap, IVec3::new(x, y, z))? {
+                        return Ok(true);
+                    }
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// forgets the masks of the bricks overlapping the inclusive box after
+    /// it's edited
+    pub fn invalidate(&mut self, min: IVec3, max: IVec3) {
+        let (min_brick, _) = Self::split(min.min(max));
+        let (max_brick, _) = Self::split(min.max(max));
+        let bricks = self.bricks.get_mut().unwrap();
+        let count = (max_brick - min_brick + 1).as_i64vec3();
+        if count.x * count.y * count.z > bricks.len() as i64 {
+            bricks.retain(|pos, _| pos.cmplt(min_brick).any() || pos.cmpgt(max_brick).any());
+            return;
+        }
+        for x in min_brick.x..=max_brick.x {
+            for y in min_brick.y..=max_brick.y {
+                for z in min_brick.z..=max_brick.z {
+                    bricks.remove(&IVec3::new(x, y, z));
+                }
+            }
+        }
+    }
+
+    /// forgets every mask, after the brickmap has been edited directly
+    pub fn clear(&mut self) {
+        self.bricks.get_mut().unwrap().clear();
+    }
+
+    /// the solid voxels of the leaf brick at a centred brick position, `None`
+    /// if it has none
+    fn build_mask(cpu_brickmap: &CpuBrickmap, brick_pos: IVec3) -> Result<Option<Mask>, String> {
+        let half_size = 1 << (cpu_brickmap.brickmap_depth - 1);
+        let brick_pos = brick_pos + half_size;
+        if brick_pos.cmplt(IVec3::ZERO).any() || brick_pos.cmpge(IVec3::splat(2 * half_size)).any()
+        {
+            return Ok(None);
+        }
+
+        let (node_index, _, depth) = cpu_brickmap.get_node(brick_pos.as_uvec3(), None);
+        let brick_index = cpu_brickmap.brickmap[node_index].brick as usize;
+        if depth != cpu_brickmap.brickmap_depth || brick_index == 0 {
+            return Ok(None);
+        }
+
+        let brick = cpu_brickmap.read_brick(brick_index)?;
+        let mut mask = Box::new([0; BRICK_WORDS]);
+        let mut solid = false;
+        for i in 0..BRICK_VOXELS {
+            if brick.get_index(i)[3] != 0 && Material::get(brick.material_index(i)).collision {
+                mask[i / 64] |= 1 << (i % 64);
+                solid = true;
+            }
+        }
+        Ok(solid.then_some(mask))
+    }
+
+    /// brick position and bit index of a voxel, matching the brick voxel order
+    fn split(pos: IVec3) -> (IVec3, usize) {
+        let size = BRICK_SIZE as i32;
+        let brick_pos = pos.div_euclid(IVec3::splat(size));
+        let local = pos.rem_euclid(IVec3::splat(size));
+        let bit = (local.z * size * size + local.y * size + local.x) as usize;
+        (brick_pos, bit)
+    }
+}
@@ -44,7 +44,12 @@ impl Plugin for VoxelRenderPlugin {
     }
 
     fn finish(&self, app: &mut App) {
-        app.sub_app_mut(RenderApp).init_resource::<VoxelPipeline>();
+        let render_app = app.sub_app_mut(RenderApp);
+        // the world failed to load and the app is exiting
+        if !render_app.world().contains_resource::<VoxelData>() {
+            return;
+        }
+        render_app.init_resource::<VoxelPipeline>();
     }
 }
 
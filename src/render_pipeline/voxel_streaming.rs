@@ -1,9 +1,10 @@
 use super::{
-    gpu_brickmap::GpuVoxelWorld,
-    voxel_world::{CpuVoxelWorld, VoxelData},
+    gpu_brickmap::{GpuVoxelWorld, RenderQueueUpload},
+    voxel_world::{CpuVoxelWorldResource, ExtractedVoxelEdits, VoxelData},
     VoxelVolume, VoxelWorldStatsResource, BRICK_OFFSET, BRICK_SIZE,
 };
 use bevy::{
+    ecs::system::SystemParam,
     prelude::*,
     render::{
         extract_resource::{ExtractResource, ExtractResourcePlugin},
@@ -39,16 +40,58 @@ impl Plugin for VoxelStreamingPlugin {
     }
 }
 
+/// The gpu side of the world that streaming writes to.
+#[derive(SystemParam)]
+struct GpuStreaming<'w> {
+    voxel_data: Res<'w, VoxelData>,
+    render_queue: Res<'w, RenderQueue>,
+    gpu_voxel_world: ResMut<'w, GpuVoxelWorld>,
+    voxel_stats: Res<'w, VoxelWorldStatsResource>,
+}
+
 fn voxel_streaming_system(
-    voxel_data: Res<VoxelData>,
-    render_queue: Res<RenderQueue>,
-    cpu_voxel_world: Res<CpuVoxelWorld>,
-    mut gpu_voxel_world: ResMut<GpuVoxelWorld>,
+    gpu_streaming: GpuStreaming,
+    cpu_voxel_world: Res<CpuVoxelWorldResource>,
+    mut voxel_edits: ResMut<ExtractedVoxelEdits>,
     streaming_settings: Res<StreamingSettings>,
-    voxel_stats: Res<VoxelWorldStatsResource>,
     voxel_volume: Query<&VoxelVolume>,
 ) {
+    let GpuStreaming {
+        voxel_data,
+        render_queue,
+        mut gpu_voxel_world,
+        voxel_stats,
+    } = gpu_streaming;
+
+    // only read, bricks are copied straight out of paged stores rather than
+    // cached, so gameplay systems can keep using the world while we stream
+    let cpu_voxel_world = cpu_voxel_world.read().unwrap();
+    let upload = RenderQueueUpload {
+        voxel_data: &voxel_data,
+        render_queue: &render_queue,
+    };
+
+    // refresh resident bricks that were edited since the last frame
+    let dirty_nodes = std::mem::take(&mut voxel_edits.0);
+    let edited = !dirty_nodes.is_empty();
+    if edited {
+        let my_span = info_span!("streaming edits").entered();
+        if let Err(e) = gpu_voxel_world.update_nodes(dirty_nodes, &cpu_voxel_world, &upload) {
+            warn!("failed to update edited nodes: {}", e);
+        }
+        voxel_stats
+            .lock()
+            .unwrap()
+            .update_cpu_memory(&cpu_voxel_world);
+        drop(my_span);
+    }
+
     if streaming_settings.pause_streaming {
+        if !edited {
+            return;
+        }
+        let (_, data, _) = unsafe { gpu_voxel_world.brickmap.align_to::<u8>() };
+        render_queue.write_buffer(&voxel_data.brickmap, 0, data);
         return;
     }
 
@@ -115,9 +158,7 @@ fn voxel_streaming_system(
 
     let my_span = info_span!("streaming division").entered();
     for (index, _, _) in nodes_to_divide {
-        if let Err(e) =
-            gpu_voxel_world.divide_node(index, &voxel_data, &cpu_voxel_world, &render_queue)
-        {
+        if let Err(e) = gpu_voxel_world.divide_node(index, &cpu_voxel_world, &upload) {
             warn!("failed to divide node: {}", e);
             break;
         }
@@ -126,9 +167,7 @@ fn voxel_streaming_system(
 
     let my_span = info_span!("streaming culling").entered();
     for (index, _, _) in nodes_to_cull {
-        if let Err(e) =
-            gpu_voxel_world.cull_node(index, &voxel_data, &cpu_voxel_world, &render_queue)
-        {
+        if let Err(e) = gpu_voxel_world.cull_node(index, &cpu_voxel_world, &upload) {
             warn!("failed to cull node: {}", e);
             break;
         }
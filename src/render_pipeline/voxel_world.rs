@@ -1,9 +1,11 @@
 use super::{
     cpu_brickmap::{Brick, CpuBrickmap},
-    gpu_brickmap::GpuVoxelWorld,
-    height_mapper::load_and_process_heightmap,
-    world_builder::setup_voxels,
-    BRICK_OFFSET, BRICK_SIZE, COUNTER_BITS,
+    gpu_brickmap::{GpuVoxelWorld, RenderQueueUpload},
+    material::{GpuMaterial, MATERIALS},
+    mip_filter::MipFilter,
+    solid_voxels::SolidVoxels,
+    world_builder::load_world,
+    BRICK_SIZE, COUNTER_BITS,
 };
 use bevy::{
     ecs::system::{lifetimeless::SRes, SystemParamItem},
@@ -13,23 +15,212 @@ use bevy::{
         render_phase::{PhaseItem, RenderCommand, RenderCommandResult, TrackedRenderPass},
         render_resource::*,
         renderer::{RenderDevice, RenderQueue},
-        Render, RenderApp, RenderSet,
+        Extract, ExtractSchedule, Render, RenderApp, RenderSet,
     },
 };
 use std::{
-    collections::VecDeque,
+    collections::HashSet,
     path::PathBuf,
-    sync::{Arc, Mutex},
+    sync::{Arc, Mutex, RwLock},
 };
 
+/// Where the world is loaded from.
+#[derive(Clone, Debug)]
+pub enum WorldSource {
+    /// a greyscale height image, 8 or 16 bit, or a raw `.r16`/`.r32` grid
+    Heightmap(PathBuf),
+    /// a minecraft region directory full of `r.x.z.mca` files
+    Anvil(PathBuf),
+    /// a magicavoxel `.vox` file
+    Vox(PathBuf),
+    /// generated terrain
+    Procedural { seed: u32 },
+    /// a brickmap saved with `CpuBrickmap::save`
+    Brickmap(PathBuf),
+}
+
+impl WorldSource {
+    /// guesses the kind of world at `path` from its extension, directories
+    /// are taken to be anvil regions
+    pub fn from_path(path: PathBuf) -> Option<Self> {
+        if path.is_dir() {
+            return Some(Self::Anvil(path));
+        }
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "png" | "jpg" | "jpeg" | "tif" | "tiff" | "r16" | "r32" => Some(Self::Heightmap(path)),
+            "vox" => Some(Self::Vox(path)),
+            "brickmap" => Some(Self::Brickmap(path)),
+            _ => None,
+        }
+    }
+}
+
+/// How a heightmap is scaled into the world. Heights are in the units of the
+/// heightmap, see `Heightmap`.
+#[derive(Clone, Debug, Default)]
+pub struct HeightmapSettings {
+    /// voxels per heightmap sample along x and z, with the heightmap centred
+    /// in the world. `None` stretches it over the whole world
+    pub horizontal_scale: Option<f32>,
+    /// voxels per unit of height above the lowest sample, which sits at the
+    /// bottom of the world. `None` puts the highest sample at the top
+    pub vertical_scale: Option<f32>,
+    /// height water fills up to, `None` for no water
+    pub sea_level: Option<f32>,
+}
+
+/// Picks the world to load and the budgets it's loaded with. Insert it before
+/// the render app finishes building, the defaults load the example heightmap.
+#[derive(Resource, Clone, Debug)]
+pub struct VoxelWorldConfig {
+    pub source: WorldSource,
+    /// log2 of the world side length in voxels. saved brickmaps keep the
+    /// depth they were saved with
+    pub world_depth: u32,
+    /// side length of the gpu brick texture in voxels, this sets how many
+    /// bricks can be resident on the gpu at once
+    pub color_texture_size: u32,
+    /// how many groups of 8 nodes the gpu brickmap can hold
+    pub brickmap_max_nodes: usize,
+    pub mip_filter: MipFilter,
+    /// share storage between identical bricks
+    pub dedup: bool,
+    /// page saved brickmaps in from disk, keeping at most this many bytes of
+    /// bricks in memory
+    pub cpu_brick_budget: Option<usize>,
+    /// check the checksum of the whole file before paging a saved brickmap
+    /// in. fully loaded brickmaps are always checked
+    pub verify_checksum: bool,
+    /// colour overrides for minecraft blocks, a .toml or .json file applied
+    /// on top of blockstates.json
+    pub palette: Option<PathBuf>,
+    /// scaling for heightmap and procedural worlds
+    pub heightmap: HeightmapSettings,
+}
+
+impl Default for VoxelWorldConfig {
+    fn default() -> Self {
+        Self {
+            source: WorldSource::Heightmap("assets/heightmapdata/Take1.png".into()),
+            world_depth: 8,
+            color_texture_size: 640,
+            brickmap_max_nodes: 1 << 16,
+            mip_filter: MipFilter::default(),
+            dedup: true,
+            cpu_brick_budget: None,
+            verify_checksum: false,
+            palette: None,
+            heightmap: HeightmapSettings::default(),
+        }
+    }
+}
+
 const REGION_SIZE: usize = 512; // Blocks per region along one axis
 
 fn calculate_num_regions(image_size: usize) -> usize {
     ((image_size as f32) / (REGION_SIZE as f32)).ceil() as usize
 }
 
-#[derive(Resource, Deref, DerefMut)]
-pub struct CpuVoxelWorld(CpuBrickmap);
+/// The cpu side copy of the world. Edits made through it are tracked so the
+/// streaming system can re-upload any bricks already resident on the gpu.
+#[derive(Deref, DerefMut)]
+pub struct CpuVoxelWorld {
+    #[deref]
+    cpu_brickmap: CpuBrickmap,
+    solid_voxels: SolidVoxels,
+    dirty_nodes: HashSet<usize>,
+}
+
+#[allow(dead_code)]
+impl CpuVoxelWorld {
+    pub fn new(cpu_brickmap: CpuBrickmap) -> Self {
+        Self {
+            cpu_brickmap,
+            solid_voxels: SolidVoxels::default(),
+            dirty_nodes: HashSet::new(),
+        }
+    }
+
+    /// whether the voxel at a centred world position is solid for physics
+    pub fn is_solid(&self, pos: IVec3) -> Result<bool, String> {
+        self.solid_voxels.is_solid(&self.cpu_brickmap, pos)
+    }
+
+    /// whether any voxel overlapping the box is solid for physics
+    pub fn overlaps_solid(&self, min: Vec3, max: Vec3) -> Result<bool, String> {
+        self.solid_voxels.overlaps(&self.cpu_brickmap, min, max)
+    }
+
+    pub fn set_voxel(&mut self, pos: IVec3, colour: [u8; 4]) -> Result<(), String> {
+        let changed = self.cpu_brickmap.set_voxel(pos, colour)?;
+        self.solid_voxels.invalidate(pos, pos);
+        self.dirty_nodes.extend(changed);
+        Ok(())
+    }
+
+    pub fn clear_voxel(&mut self, pos: IVec3) -> Result<(), String> {
+        let changed = self.cpu_brickmap.clear_voxel(pos)?;
+        self.solid_voxels.invalidate(pos, pos);
+        self.dirty_nodes.extend(changed);
+        Ok(())
+    }
+
+    pub fn fill_box(&mut self, min: IVec3, max: IVec3, colour: [u8; 4]) -> Result<(), String> {
+        let changed = self.cpu_brickmap.fill_box(min, max, colour)?;
+        self.solid_voxels.invalidate(min, max);
+        self.dirty_nodes.extend(changed);
+        Ok(())
+    }
+
+    pub fn set_voxels(&mut self, voxels: Vec<(IVec3, [u8; 4])>) -> Result<(), String> {
+        let changed = self.cpu_brickmap.set_voxels(voxels.iter().copied())?;
+        for (pos, _) in voxels {
+            self.solid_voxels.invalidate(pos, pos);
+        }
+        self.dirty_nodes.extend(changed);
+        Ok(())
+    }
+
+    /// forgets the solid voxels after editing the brickmap directly, they're
+    /// rebuilt as they're queried
+    pub fn rebuild_solid_voxels(&mut self) {
+        self.solid_voxels.clear();
+    }
+
+    /// mark nodes as changed after editing the brickmap directly
+    pub fn mark_dirty(&mut self, nodes: impl IntoIterator<Item = usize>) {
+        self.dirty_nodes.extend(nodes);
+    }
+
+    pub fn take_dirty_nodes(&mut self) -> HashSet<usize> {
+        std::mem::take(&mut self.dirty_nodes)
+    }
+}
+
+/// A handle to the cpu world shared by the main and render apps. Gameplay
+/// systems lock it to read or edit the world, the dirty nodes of their edits
+/// are extracted into `ExtractedVoxelEdits` once a frame.
+#[derive(Resource, Clone, Deref, DerefMut)]
+pub struct CpuVoxelWorldResource(Arc<RwLock<CpuVoxelWorld>>);
+
+impl CpuVoxelWorldResource {
+    pub fn new(cpu_voxel_world: CpuVoxelWorld) -> Self {
+        Self(Arc::new(RwLock::new(cpu_voxel_world)))
+    }
+}
+
+/// nodes edited in the main world since the render world last streamed
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct ExtractedVoxelEdits(pub HashSet<usize>);
+
+fn extract_voxel_edits(
+    cpu_voxel_world: Extract<Res<CpuVoxelWorldResource>>,
+    mut edits: ResMut<ExtractedVoxelEdits>,
+) {
+    let dirty_nodes = cpu_voxel_world.write().unwrap().take_dirty_nodes();
+    edits.extend(dirty_nodes);
+}
 
 pub struct VoxelWorldPlugin;
 
@@ -41,34 +232,40 @@ impl Plugin for VoxelWorldPlugin {
     }
 
     fn finish(&self, app: &mut App) {
+        let config = app
+            .world()
+            .get_resource::<VoxelWorldConfig>()
+            .cloned()
+            .unwrap_or_default();
+        let voxel_stats = app.world().resource::<VoxelWorldStatsResource>().clone();
         let render_device = app.world().resource::<RenderDevice>();
         let render_queue = app.world().resource::<RenderQueue>();
-        let heightmap = load_and_process_heightmap();
-        if heightmap.is_none() {
-            error!("Failed to load heightmap");
-            return;
-        }
-        let heightmap = heightmap.unwrap();
+        let cpu_brickmap = match load_world(&config) {
+            Ok(cpu_brickmap) => cpu_brickmap,
+            Err(e) => {
+                // the render systems can't run without a world, ask the app to exit
+                // before the first frame
+                error!("failed to load voxel world from {:?}: {}", config.source, e);
+                app.world_mut().send_event(AppExit::error());
+                return;
+            }
+        };
 
         // brickmap settings
-        let world_depth = 8;
-        let color_texture_size = UVec3::splat(640);
-        let brickmap_max_nodes = 1 << 16;
-        let mut cpu_brickmap = setup_voxels(heightmap, world_depth);
-        cpu_brickmap.recreate_mipmaps();
+        let color_texture_size = UVec3::splat(config.color_texture_size);
+        let brickmap_max_nodes = config.brickmap_max_nodes;
+        #[cfg(debug_assertions)]
+        if let Err(e) = cpu_brickmap.validate(false) {
+            error!("invalid brickmap: {}", e);
+        }
+        voxel_stats.lock().unwrap().update_cpu_memory(&cpu_brickmap);
 
         // setup gpu brickmap
-        let brickmap_depth = world_depth - BRICK_SIZE.trailing_zeros();
+        let brickmap_depth = cpu_brickmap.brickmap_depth;
         let dim = color_texture_size / BRICK_SIZE;
         let brick_count = (dim.x * dim.y * dim.z) as usize;
-        let mut gpu_voxel_world = GpuVoxelWorld {
-            brickmap: vec![BRICK_OFFSET; 8 * brickmap_max_nodes],
-            gpu_to_cpu: vec![0; 8 * brickmap_max_nodes],
-            brickmap_holes: (1..brickmap_max_nodes).collect::<VecDeque<usize>>(),
-            brick_holes: (1..brick_count).collect::<VecDeque<usize>>(),
-            color_texture_size,
-            brickmap_depth,
-        };
+        let mut gpu_voxel_world =
+            GpuVoxelWorld::new(brickmap_depth, color_texture_size, brickmap_max_nodes);
 
         // uniforms
         let voxel_uniforms = VoxelUniforms {
@@ -126,6 +323,36 @@ impl Plugin for VoxelWorldPlugin {
             &color,
         );
 
+        // material, one index into the material table per voxel
+        let material = vec![0; texture_length as usize];
+        let material = render_device.create_texture_with_data(
+            render_queue,
+            &TextureDescriptor {
+                label: None,
+                view_formats: &[TextureFormat::R8Uint],
+                size: Extent3d {
+                    width: color_texture_size.x,
+                    height: color_texture_size.y,
+                    depth_or_array_layers: color_texture_size.z,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: TextureDimension::D3,
+                format: TextureFormat::R8Uint,
+                usage: TextureUsages::TEXTURE_BINDING,
+            },
+            TextureDataOrder::LayerMajor,
+            &material,
+        );
+
+        // material table
+        let materials = MATERIALS.iter().map(GpuMaterial::from).collect::<Vec<_>>();
+        let materials = render_device.create_buffer_with_data(&BufferInitDescriptor {
+            contents: bytemuck::cast_slice(&materials),
+            label: None,
+            usage: BufferUsages::STORAGE,
+        });
+
         let bind_group_layout = render_device.create_bind_group_layout(
             Some("voxelization bind group layout"),
             &[
@@ -179,6 +406,26 @@ impl Plugin for VoxelWorldPlugin {
                     },
                     count: None,
                 },
+                BindGroupLayoutEntry {
+                    binding: 5,
+                    visibility: ShaderStages::FRAGMENT | ShaderStages::COMPUTE,
+                    ty: BindingType::Texture {
+                        sample_type: TextureSampleType::Uint,
+                        view_dimension: TextureViewDimension::D3,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 6,
+                    visibility: ShaderStages::FRAGMENT | ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: BufferSize::new(size_of::<GpuMaterial>() as u64),
+                    },
+                    count: None,
+                },
             ],
         );
 
@@ -188,32 +435,31 @@ impl Plugin for VoxelWorldPlugin {
             counters,
             bricks,
             color,
+            material,
+            materials,
             bind_group_layout,
             bind_group: None,
         };
 
         // initialize brickmap with lowest mip level
-        for i in 0..8 {
-            let brick_index = cpu_brickmap.brickmap[i].brick;
-            if brick_index > 0 {
-                let brick = &cpu_brickmap.bricks[brick_index as usize];
-                match gpu_voxel_world.allocate_brick(brick, &voxel_data, render_queue) {
-                    Ok(gpu_brick_index) => {
-                        gpu_voxel_world.brickmap[i] = BRICK_OFFSET + gpu_brick_index as u32;
-                        gpu_voxel_world.gpu_to_cpu[i] = i as u32;
-                    }
-                    Err(e) => {
-                        error!("failed to allocate brick: {}", e);
-                    }
-                }
-            }
+        let upload = RenderQueueUpload {
+            voxel_data: &voxel_data,
+            render_queue,
+        };
+        if let Err(e) = gpu_voxel_world.update_nodes(0..8, &cpu_brickmap, &upload) {
+            error!("failed to allocate brick: {}", e);
         }
 
+        let cpu_voxel_world = CpuVoxelWorldResource::new(CpuVoxelWorld::new(cpu_brickmap));
+        app.insert_resource(config);
+        app.insert_resource(cpu_voxel_world.clone());
         app.sub_app_mut(RenderApp)
             .insert_resource(voxel_uniforms)
             .insert_resource(voxel_data)
-            .insert_resource(CpuVoxelWorld(cpu_brickmap))
+            .insert_resource(cpu_voxel_world)
+            .insert_resource(ExtractedVoxelEdits::default())
             .insert_resource(gpu_voxel_world)
+            .add_systems(ExtractSchedule, extract_voxel_edits)
             .add_systems(
                 Render,
                 (
@@ -231,6 +477,8 @@ pub struct VoxelData {
     pub counters: Buffer,
     pub bricks: Buffer,
     pub color: Texture,
+    pub material: Texture,
+    pub materials: Buffer,
     pub bind_group_layout: BindGroupLayout,
     pub bind_group: Option<BindGroup>,
 }
@@ -283,6 +531,18 @@ fn prepare_bind_group(render_device: Res<RenderDevice>, mut voxel_data: ResMut<V
                         .create_view(&wgpu::TextureViewDescriptor::default()),
                 ),
             },
+            BindGroupEntry {
+                binding: 5,
+                resource: BindingResource::TextureView(
+                    &voxel_data
+                        .material
+                        .create_view(&wgpu::TextureViewDescriptor::default()),
+                ),
+            },
+            BindGroupEntry {
+                binding: 6,
+                resource: voxel_data.materials.as_entire_binding(),
+            },
         ],
     );
     voxel_data.bind_group = Some(bind_group);
@@ -325,6 +585,8 @@ impl Default for VoxelWorldStatsResource {
         Self(Arc::new(Mutex::new(VoxelWorldStats {
             nodes: 0,
             bricks: 0,
+            cpu_bricks: 0,
+            cpu_brick_memory: 0,
         })))
     }
 }
@@ -332,4 +594,17 @@ impl Default for VoxelWorldStatsResource {
 pub struct VoxelWorldStats {
     pub nodes: usize,
     pub bricks: usize,
+    pub cpu_bricks: usize,
+    pub cpu_brick_memory: usize,
+}
+
+impl VoxelWorldStats {
+    pub fn update_cpu_memory(&mut self, cpu_brickmap: &CpuBrickmap) {
+        (self.cpu_bricks, self.cpu_brick_memory) = cpu_brickmap.bricks.memory_usage();
+    }
+
+    /// memory saved by compressing the resident cpu bricks
+    pub fn cpu_memory_saved(&self) -> usize {
+        (self.cpu_bricks * Brick::RAW_SIZE).saturating_sub(self.cpu_brick_memory)
+    }
 }
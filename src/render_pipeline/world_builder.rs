@@ -1,109 +1,290 @@
 use bevy::prelude::*;
+use fastanvil::biome::Biome;
 use rayon::prelude::*;
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-use super::height_mapper::Heightmap;
+use super::height_mapper::{load_heightmap, procedural_heightmap, Heightmap};
 use super::{
+    block_colours::{AnvilBlock, BlockColours},
     cpu_brickmap::{Brick, CpuBrickmap},
+    load_vox::{load_vox, place_vox},
+    voxel_world::{HeightmapSettings, VoxelWorldConfig, WorldSource},
     BRICK_SIZE,
 };
-use crate::ultilities::timeit::{timeit, timeitmut};
 
 const REGION_SIZE: usize = 512; // Blocks per region along one axis
 const WORLD_ORIGIN_OFFSET: u32 = 4096; // Arbitrary offset to handle negative regions
 
-/// Loads the palette from a JSON file.
-fn load_palette() -> HashMap<String, [u8; 4]> {
-    let file = std::fs::File::open("assets/palette/blockstates.json")
-        .expect("Failed to open palette file");
-    let mut json: HashMap<String, [u8; 4]> =
-        serde_json::from_reader(file).expect("Failed to parse palette JSON");
-    // Insert default or fallback colors
-    json.insert("".to_string(), [200, 200, 200, 127]);
-    json.insert("minecraft:grass".to_string(), [0, 0, 0, 0]);
-    json.insert("minecraft:tall_grass".to_string(), [0, 0, 0, 0]);
-    json.insert("minecraft:grass_block".to_string(), [62, 204, 18, 255]);
-    json.insert("minecraft:water".to_string(), [20, 105, 201, 30]);
-    json.insert("minecraft:cave_air".to_string(), [0, 0, 0, 0]);
-    json.insert("minecraft:lava".to_string(), [255, 123, 0, 255]);
-    json.insert("minecraft:seagrass".to_string(), [62, 204, 18, 255]);
-    json.insert("minecraft:deepslate".to_string(), [77, 77, 77, 255]);
-    json.insert("minecraft:oak_log".to_string(), [112, 62, 8, 255]);
-    json.insert("minecraft:oak_stairs".to_string(), [112, 62, 8, 255]);
-
-    json
+/// builds or loads the world described by `config`, with its mips
+pub fn load_world(config: &VoxelWorldConfig) -> Result<CpuBrickmap, String> {
+    match &config.source {
+        WorldSource::Heightmap(path) => {
+            let heightmap =
+                load_heightmap(path).map_err(|e| format!("failed to load heightmap {}", e))?;
+            setup_voxels(heightmap, config)
+        }
+        WorldSource::Procedural { seed } => {
+            let heightmap = procedural_heightmap(*seed, 1 << config.world_depth);
+            setup_voxels(heightmap, config)
+        }
+        WorldSource::Anvil(path) => Err(format!(
+            "can't load {} directly, convert it with alex-convert first",
+            path.display()
+        )),
+        WorldSource::Vox(path) => {
+            let vox = load_vox(path)?;
+            let mut brickmap = empty_brickmap(config);
+            let (_, clipped) = place_vox(&mut brickmap, &vox, IVec3::ZERO, Quat::IDENTITY)?;
+            if clipped > 0 {
+                warn!(
+                    "{} voxels of {} didn't fit in the world",
+                    clipped,
+                    path.display()
+                );
+            }
+            Ok(brickmap)
+        }
+        WorldSource::Brickmap(path) => {
+            // saved brickmaps already have their mips
+            let mut brickmap = match config.cpu_brick_budget {
+                Some(budget) => CpuBrickmap::open_paged(path, budget, config.verify_checksum),
+                None => CpuBrickmap::load(path),
+            }
+            .map_err(|e| format!("failed to load brickmap {}: {}", path.display(), e))?;
+            if brickmap.brickmap_depth + BRICK_SIZE.trailing_zeros() != config.world_depth {
+                warn!(
+                    "{} has a world depth of {}, not {}",
+                    path.display(),
+                    brickmap.brickmap_depth + BRICK_SIZE.trailing_zeros(),
+                    config.world_depth
+                );
+            }
+            brickmap.mip_filter = config.mip_filter.clone();
+            if config.dedup {
+                brickmap.enable_dedup();
+            }
+            Ok(brickmap)
+        }
+    }
+}
+
+/// an empty brickmap set up as `config` asks
+pub fn empty_brickmap(config: &VoxelWorldConfig) -> CpuBrickmap {
+    let mut brickmap = CpuBrickmap::new(config.world_depth - BRICK_SIZE.trailing_zeros())
+        .with_mip_filter(config.mip_filter.clone());
+    if config.dedup {
+        brickmap.enable_dedup();
+    }
+    brickmap
 }
-pub fn setup_voxels(heightmap: Heightmap, world_depth: u32) -> CpuBrickmap {
-    let heightmap_data = &heightmap.0;
-    //println!("Heightmap data {:?}", heightmap_data);
-    let height_scale: f32 = 200.0;
-    let mut brickmap = CpuBrickmap::new(world_depth - BRICK_SIZE.trailing_zeros());
-
-    let palette = load_palette();
-    let grass_color = palette["minecraft:grass_block"];
-    let dirt_color = palette["minecraft:dirt"];
-
-    let chunk_side_length_bricks = 16 / BRICK_SIZE;
-    let side_length = 1 << world_depth;
-
-    for chunk_x in 0..side_length / 16 {
-        for chunk_z in 0..side_length / 16 {
-            for brick_y in 0..chunk_side_length_bricks {
-                for brick_x in 0..chunk_side_length_bricks {
-                    for brick_z in 0..chunk_side_length_bricks {
-                        let mut brick = Brick::empty();
-                        for x in 0..BRICK_SIZE {
-                            for y in 0..BRICK_SIZE {
-                                for z in 0..BRICK_SIZE {
-                                    let global_x = chunk_x * 16 + brick_x * BRICK_SIZE + x;
-                                    let global_y = brick_y * BRICK_SIZE + y;
-                                    let global_z = chunk_z * 16 + brick_z * BRICK_SIZE + z;
-
-                                    if global_x >= heightmap_data.len() as u32
-                                        || global_z >= heightmap_data[0].len() as u32
-                                    {
-                                        continue;
-                                    }
-                                    println!(
-                                        "Hieghtmap data x y {:?}, global x y {:?}",
-                                        heightmap_data[global_z as usize][global_x as usize],
-                                        (global_x, global_y)
-                                    );
-                                    let surface_y = (heightmap_data[global_z as usize]
-                                        [global_x as usize]
-                                        as f32
-                                        * height_scale)
-                                        as u32;
-
-                                    if global_y <= surface_y {
-                                        let color = if global_y == surface_y {
-                                            grass_color
-                                        } else {
-                                            dirt_color
-                                        };
-                                        let pos = UVec3::new(x as u32, y as u32, z as u32);
-                                        brick.write(pos, color);
-                                    }
-                                }
-                            }
-                        }
 
-                        let brick_pos = UVec3::new(
-                            chunk_x as u32 * chunk_side_length_bricks as u32 + brick_x as u32,
-                            brick_y as u32,
-                            chunk_z as u32 * chunk_side_length_bricks as u32 + brick_z as u32,
-                        );
+pub fn setup_voxels(
+    heightmap: Heightmap,
+    config: &VoxelWorldConfig,
+) -> Result<CpuBrickmap, String> {
+    if heightmap.heights.is_empty() {
+        return Err("heightmap is empty".into());
+    }
+    let mut brickmap = empty_brickmap(config);
 
-                        brickmap
-                            .place_brick(brick, brick_pos)
-                            .expect("Failed to place brick");
+    let colours = BlockColours::load(config.palette.as_deref())?;
+    info!(
+        "building a {}x{} heightmap, {:?}",
+        heightmap.width, heightmap.height, config.heightmap
+    );
+    let (bricks, clipped) =
+        heightmap_bricks(&heightmap, config.world_depth, &config.heightmap, &colours);
+    if clipped > 0 {
+        warn!(
+            "{} columns were taller than the world and were cut off, lower the vertical scale \
+             or raise the world depth",
+            clipped
+        );
+    }
+    let unknown = colours.palette().unknown_blocks();
+    if !unknown.is_empty() {
+        warn!("blocks missing from the palette: {}", unknown.join(", "));
+    }
+    brickmap.place_bricks(bricks)?;
+
+    brickmap.recreate_mipmaps()?;
+    Ok(brickmap)
+}
+
+/// generates the bricks of the heightmap world, in parallel per column of
+/// bricks. also returns how many voxel columns were cut off at the top of the
+/// world
+fn heightmap_bricks(
+    heightmap: &Heightmap,
+    world_depth: u32,
+    settings: &HeightmapSettings,
+    colours: &BlockColours,
+) -> (Vec<(Brick, UVec3)>, usize) {
+    let side_length = 1u32 << world_depth;
+    let top = side_length as i32 - 1;
+
+    // voxels per sample, the heightmap is centred in the world
+    let horizontal_scale = settings
+        .horizontal_scale
+        .unwrap_or(side_length as f32 / heightmap.width.max(heightmap.height) as f32);
+    let footprint = Vec2::new(heightmap.width as f32, heightmap.height as f32) * horizontal_scale;
+    let offset = (Vec2::splat(side_length as f32) - footprint) / 2.0;
+    // the lowest sample sits at the bottom of the world
+    let (min_height, max_height) = heightmap.height_range();
+    let vertical_scale = settings.vertical_scale.unwrap_or_else(|| {
+        if max_height > min_height {
+            top as f32 / (max_height - min_height)
+        } else {
+            1.0
+        }
+    });
+    let to_voxels = |height: f32| {
+        ((height - min_height) * vertical_scale)
+            .round()
+            .clamp(-1.0, top as f32) as i32
+    };
+    let sea_level = settings.sea_level.map_or(-1, to_voxels);
+
+    // heightmaps have no biomes, plains is minecraft's default
+    let voxel = |name| {
+        colours
+            .resolve(&AnvilBlock::named(name))
+            .map_or(([0; 4], 0), |block| {
+                (
+                    colours.colour(&block, Some(Biome::Plains)),
+                    block.material(),
+                )
+            })
+    };
+    let grass = voxel("minecraft:grass_block");
+    let dirt = voxel("minecraft:dirt");
+    let water = voxel("minecraft:water");
+
+    // the surface of the column at voxel `x, z`, or `None` outside of the
+    // heightmap. -1 is a column without ground
+    let clipped = AtomicUsize::new(0);
+    let surface = |x: u32, z: u32| {
+        let pos = Vec2::new(x as f32, z as f32) + 0.5 - offset;
+        if pos.cmplt(Vec2::ZERO).any() || pos.cmpge(footprint).any() {
+            return None;
+        }
+        let sample = pos / horizontal_scale - 0.5;
+        let height = (heightmap.sample(sample.x, sample.y) - min_height) * vertical_scale;
+        if height.round() > top as f32 {
+            clipped.fetch_add(1, Ordering::Relaxed);
+        }
+        Some(height.round().clamp(-1.0, top as f32) as i32)
+    };
+
+    let columns = (0..side_length / BRICK_SIZE)
+        .flat_map(|brick_x| (0..side_length / BRICK_SIZE).map(move |brick_z| (brick_x, brick_z)))
+        .collect::<Vec<(u32, u32)>>();
+    let bricks = columns
+        .into_par_iter()
+        .flat_map_iter(|(brick_x, brick_z)| {
+            let mut surfaces = [[None; BRICK_SIZE as usize]; BRICK_SIZE as usize];
+            let mut highest = -1;
+            for x in 0..BRICK_SIZE {
+                for z in 0..BRICK_SIZE {
+                    let column = surface(brick_x * BRICK_SIZE + x, brick_z * BRICK_SIZE + z);
+                    if let Some(surface_y) = column {
+                        highest = highest.max(surface_y).max(sea_level);
                     }
+                    surfaces[x as usize][z as usize] = column;
                 }
             }
-        }
+
+            let brick_count = if highest < 0 {
+                0
+            } else {
+                highest as u32 / BRICK_SIZE + 1
+            };
+            (0..brick_count).map(move |brick_y| {
+                let mut brick = Brick::empty();
+                for x in 0..BRICK_SIZE {
+                    for z in 0..BRICK_SIZE {
+                        let Some(surface_y) = surfaces[x as usize][z as usize] else {
+                            continue;
+                        };
+                        for y in 0..BRICK_SIZE {
+                            let global_y = (brick_y * BRICK_SIZE + y) as i32;
+                            let (colour, material) = if global_y < surface_y {
+                                dirt
+                            } else if global_y == surface_y {
+                                grass
+                            } else if global_y <= sea_level {
+                                water
+                            } else {
+                                break;
+                            };
+                            brick.write_material(UVec3::new(x, y, z), colour, material);
+                        }
+                    }
+                }
+                (brick, UVec3::new(brick_x, brick_y, brick_z))
+            })
+        })
+        .collect();
+
+    (bricks, clipped.into_inner())
+}
+
+/// the bricks of the procedural world with the default palette and settings,
+/// before they're placed. used to benchmark placement
+pub fn procedural_bricks(seed: u32, world_depth: u32) -> Result<Vec<(Brick, UVec3)>, String> {
+    let heightmap = procedural_heightmap(seed, 1 << world_depth);
+    let colours = BlockColours::load(None)?;
+    let (bricks, _) = heightmap_bricks(
+        &heightmap,
+        world_depth,
+        &HeightmapSettings::default(),
+        &colours,
+    );
+    Ok(bricks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// the leaves of a brickmap by position, node layouts can differ
+    fn leaf_bricks(brickmap: &CpuBrickmap) -> Vec<(UVec3, Brick)> {
+        let mut leaves = brickmap
+            .leaves()
+            .map(|(node_index, pos)| {
+                let brick = brickmap.bricks.read(brickmap.brickmap[node_index].brick as usize).unwrap();
+                (pos, brick.into_owned())
+            })
+            .collect::<Vec<_>>();
+        leaves.sort_by_key(|(pos, _)| (pos.x, pos.y, pos.z));
+        leaves
     }
 
-    brickmap.recreate_mipmaps();
-    brickmap
+    #[test]
+    fn parallel_placement_matches_serial() {
+        let world_depth = 7;
+        let brickmap_depth = world_depth - BRICK_SIZE.trailing_zeros();
+        let mut bricks = procedural_bricks(1, world_depth).unwrap();
+        assert!(!bricks.is_empty());
+
+        // a brick that's already there sends its octant down the serial path,
+        // and a repeated position has to keep the later brick
+        let mut first = Brick::empty();
+        first.write(UVec3::ZERO, [255, 0, 0, 255]);
+        let (repeated, repeated_pos) = bricks[bricks.len() / 2].clone();
+        let mut replacement = repeated.clone();
+        replacement.write(UVec3::ONE, [0, 0, 255, 255]);
+        bricks.push((replacement, repeated_pos));
+
+        let mut serial = CpuBrickmap::new(brickmap_depth);
+        serial.place_brick(first.clone(), UVec3::ZERO).unwrap();
+        serial.place_bricks_serial(bricks.clone()).unwrap();
+        let mut parallel = CpuBrickmap::new(brickmap_depth);
+        parallel.place_brick(first, UVec3::ZERO).unwrap();
+        parallel.place_bricks(bricks).unwrap();
+
+        serial.validate(false).unwrap();
+        parallel.validate(false).unwrap();
+        assert_eq!(leaf_bricks(&serial), leaf_bricks(&parallel));
+    }
 }
@@ -76,6 +76,12 @@ fn ui_system(
         let voxel_stats = voxel_stats.lock().unwrap();
         ui.label(format!("Nodes: {}", voxel_stats.nodes));
         ui.label(format!("Bricks: {}", voxel_stats.bricks));
+        ui.label(format!(
+            "CPU bricks: {} ({:.1} MiB, {:.1} MiB saved)",
+            voxel_stats.cpu_bricks,
+            voxel_stats.cpu_brick_memory as f32 / (1 << 20) as f32,
+            voxel_stats.cpu_memory_saved() as f32 / (1 << 20) as f32,
+        ));
 
         let voxel_volume = voxel_volume.single_mut();
         ui_for_value(voxel_volume.into_inner(), ui, &type_registry.read());
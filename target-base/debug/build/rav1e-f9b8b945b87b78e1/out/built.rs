@@ -0,0 +1,102 @@
+//
+// EVERYTHING BELOW THIS POINT WAS AUTO-GENERATED DURING COMPILATION. DO NOT MODIFY.
+//
+#[doc=r#"The Continuous Integration platform detected during compilation."#]
+#[allow(dead_code)]
+pub const CI_PLATFORM: Option<&str> = None;
+#[doc=r#"The full version."#]
+#[allow(dead_code)]
+pub const PKG_VERSION: &str = r"0.7.1";
+#[doc=r#"The major version."#]
+#[allow(dead_code)]
+pub const PKG_VERSION_MAJOR: &str = r"0";
+#[doc=r#"The minor version."#]
+#[allow(dead_code)]
+pub const PKG_VERSION_MINOR: &str = r"7";
+#[doc=r#"The patch version."#]
+#[allow(dead_code)]
+pub const PKG_VERSION_PATCH: &str = r"1";
+#[doc=r#"The pre-release version."#]
+#[allow(dead_code)]
+pub const PKG_VERSION_PRE: &str = r"";
+#[doc=r#"A colon-separated list of authors."#]
+#[allow(dead_code)]
+pub const PKG_AUTHORS: &str = r"Thomas Daede <tdaede@xiph.org>";
+#[doc=r#"The name of the package."#]
+#[allow(dead_code)]
+pub const PKG_NAME: &str = r"rav1e";
+#[doc=r#"The description."#]
+#[allow(dead_code)]
+pub const PKG_DESCRIPTION: &str = r"The fastest and safest AV1 encoder";
+#[doc=r#"The homepage."#]
+#[allow(dead_code)]
+pub const PKG_HOMEPAGE: &str = r"";
+#[doc=r#"The license."#]
+#[allow(dead_code)]
+pub const PKG_LICENSE: &str = r"BSD-2-Clause";
+#[doc=r#"The source repository as advertised in Cargo.toml."#]
+#[allow(dead_code)]
+pub const PKG_REPOSITORY: &str = r"https://github.com/xiph/rav1e/";
+#[doc=r#"The target triple that was being compiled for."#]
+#[allow(dead_code)]
+pub const TARGET: &str = r"x86_64-unknown-linux-gnu";
+#[doc=r#"The host triple of the rust compiler."#]
+#[allow(dead_code)]
+pub const HOST: &str = r"x86_64-unknown-linux-gnu";
+#[doc=r#"`release` for release builds, `debug` for other builds."#]
+#[allow(dead_code)]
+pub const PROFILE: &str = r"debug";
+#[doc=r#"The compiler that cargo resolved to use."#]
+#[allow(dead_code)]
+pub const RUSTC: &str = r"/root/.rustup/toolchains/stable-x86_64-unknown-linux-gnu/bin/rustc";
+#[doc=r#"The documentation generator that cargo resolved to use."#]
+#[allow(dead_code)]
+pub const RUSTDOC: &str = r"/root/.rustup/toolchains/stable-x86_64-unknown-linux-gnu/bin/rustdoc";
+#[doc=r#"Value of OPT_LEVEL for the profile used during compilation."#]
+#[allow(dead_code)]
+pub const OPT_LEVEL: &str = r"0";
+#[doc=r#"The parallelism that was specified during compilation."#]
+#[allow(dead_code)]
+pub const NUM_JOBS: u32 = 1;
+#[doc=r#"Value of DEBUG for the profile used during compilation."#]
+#[allow(dead_code)]
+pub const DEBUG: bool = true;
+#[doc=r#"The features that were enabled during compilation."#]
+#[allow(dead_code)]
+pub const FEATURES: [&str; 0] = [];
+#[doc=r#"The features as a comma-separated string."#]
+#[allow(dead_code)]
+pub const FEATURES_STR: &str = r"";
+#[doc=r#"The features as above, as lowercase strings."#]
+#[allow(dead_code)]
+pub const FEATURES_LOWERCASE: [&str; 0] = [];
+#[doc=r#"The feature-string as above, from lowercase strings."#]
+#[allow(dead_code)]
+pub const FEATURES_LOWERCASE_STR: &str = r"";
+#[doc=r#"The output of `/root/.rustup/toolchains/stable-x86_64-unknown-linux-gnu/bin/rustc -V`"#]
+#[allow(dead_code)]
+pub const RUSTC_VERSION: &str = r"rustc 1.95.0 (59807616e 2026-04-14)";
+#[doc=r#"The output of `/root/.rustup/toolchains/stable-x86_64-unknown-linux-gnu/bin/rustdoc -V`; empty string if `/root/.rustup/toolchains/stable-x86_64-unknown-linux-gnu/bin/rustdoc -V` failed to execute"#]
+#[allow(dead_code)]
+pub const RUSTDOC_VERSION: &str = r"rustdoc 1.95.0 (59807616e 2026-04-14)";
+#[doc=r#"The target architecture, given by `CARGO_CFG_TARGET_ARCH`."#]
+#[allow(dead_code)]
+pub const CFG_TARGET_ARCH: &str = r"x86_64";
+#[doc=r#"The endianness, given by `CARGO_CFG_TARGET_ENDIAN`."#]
+#[allow(dead_code)]
+pub const CFG_ENDIAN: &str = r"little";
+#[doc=r#"The toolchain-environment, given by `CARGO_CFG_TARGET_ENV`."#]
+#[allow(dead_code)]
+pub const CFG_ENV: &str = r"gnu";
+#[doc=r#"The OS-family, given by `CARGO_CFG_TARGET_FAMILY`."#]
+#[allow(dead_code)]
+pub const CFG_FAMILY: &str = r"unix";
+#[doc=r#"The operating system, given by `CARGO_CFG_TARGET_OS`."#]
+#[allow(dead_code)]
+pub const CFG_OS: &str = r"linux";
+#[doc=r#"The pointer width, given by `CARGO_CFG_TARGET_POINTER_WIDTH`."#]
+#[allow(dead_code)]
+pub const CFG_POINTER_WIDTH: &str = r"64";
+//
+// EVERYTHING ABOVE THIS POINT WAS AUTO-GENERATED DURING COMPILATION. DO NOT MODIFY.
+//